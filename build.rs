@@ -38,6 +38,7 @@ fn main() {
         .whitelist_function("rtnl_link_alloc_cache")
         .whitelist_function("rtnl_link_get_by_name")
         .whitelist_function("rtnl_link_get_ifindex")
+        .whitelist_function("rtnl_link_get_mtu")
         .whitelist_function("rtnl_qdisc_alloc_cache")
         .whitelist_function("rtnl_qdisc_alloc")
         .whitelist_function("rtnl_qdisc_get")
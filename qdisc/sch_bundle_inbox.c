@@ -22,9 +22,17 @@
 #include <net/sch_generic.h>
 #include <net/pkt_sched.h>
 #include <linux/ip.h>
+#include <linux/ipv6.h>
+#include <net/ipv6.h>
+#include <linux/if_ether.h>
 #include <linux/tcp.h>
+#include <linux/udp.h>
 #include <linux/netlink.h>
 #include <linux/version.h>
+#include <linux/siphash.h>
+#include <linux/rcupdate.h>
+#include <linux/slab.h>
+#include <asm/unaligned.h>
 
 #define FIFO 1
 #define FQ_CODEL 2
@@ -152,8 +160,25 @@
 #define NETLINK_USER 30
 // Mark 1 out of every 100 packets, on average
 #define PACKET_SAMPLE_RATE 100
+// Until the inbox tells us which bundle we belong to
+#define DEFAULT_BUNDLE_ID 42
+
+// Must match serialize::{MAGIC, PROTOCOL_VERSION, MsgType} in the userspace bundler
+#define BUNDLER_MAGIC 0xb417
+#define BUNDLER_PROTOCOL_VERSION 6
+#define BUNDLER_MSG_QDISC_FEEDBACK 3
+#define BUNDLER_MSG_QDISC_UPDATE 4
+
+struct __attribute__((packed, aligned(4))) BundlerMsgHdr {
+	u16 magic;
+	u8 version;
+	u8 msg_type;
+	u16 len;
+	u16 reserved;
+};
 
 struct __attribute__((packed, aligned(4))) FeedbackMsg {
+	struct BundlerMsgHdr hdr;
 	u32 bundle_id;
 	u32 marked_packet_hash;
     u32 curr_qlen;
@@ -178,13 +203,31 @@ struct tbf_sched_data {
   struct qdisc_watchdog watchdog;  /* Watchdog timer */
 
   /* bundler */
-  struct sock *nl_sock;
+  struct list_head bundles;
+  int ifindex;
+  u32 bundle_id;
   u32 epoch_sample_rate;
+  struct bundle_hash_cfg __rcu *hash_cfg;
   u64 epoch_bytes_sent; 
   u64 epoch_pkts_sent;
   char msg_buffer[24];
 };
 
+/*
+ * How a qdisc hashes packets for marks. Never changed once published: an update swaps in a new
+ * one under RCU, so dequeue never hashes with half of an old key or a key from another spec.
+ */
+struct bundle_hash_cfg {
+  struct rcu_head rcu;
+  u32 spec;
+  siphash_key_t key;
+};
+
+/* One netlink socket is shared by every bundle_inbox qdisc; messages are told apart by bundle_id. */
+static struct sock *bundle_nl_sock;
+static LIST_HEAD(bundle_inbox_qdiscs);
+static DEFINE_SPINLOCK(bundle_inbox_lock);
+
 void send_to_dp(struct tbf_sched_data *q, char *msg, int msg_size) {
 	int res;
 	struct sk_buff *skb_out;
@@ -207,7 +250,7 @@ void send_to_dp(struct tbf_sched_data *q, char *msg, int msg_size) {
 	memcpy(nlmsg_data(nlh), msg, msg_size); 
 
 	res = nlmsg_multicast(
-		q->nl_sock,
+		bundle_nl_sock,
 		skb_out,
 		0,
 		BUNDLE_GROUP,
@@ -362,23 +405,144 @@ fnv_64_buf(void *buf, size_t len, Fnv64_t hval)
     return hval;
 }
 
-// Pseudo-header for packet hashing:
-//    0                   1                   2                   3
-//    0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
-//   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-//   |           Destination Address (ipv4 header bytes 4-6)         |
-//   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-//   | Destination Port (tcp[2:4])   |  Identification (ipv4[4:6])   |
-//   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-static uint32_t hash_header(unsigned char *dst_ip, unsigned char *ports, unsigned char *ipid) {
-  uint32_t hash = 0;
-  //hash = fnv_64_buf(dst_ip, 4, FNV1_64_INIT);
-  // only dst port
-  hash = fnv_64_buf((void*) (ports+2), 2, FNV1_64_INIT);
-  // src and dst port
-  //hash = fnv_64_buf((void*) (ports), 4, FNV1_64_INIT);
-  hash = fnv_64_buf(ipid, 2, hash);
-  return hash;
+// Bytes of a UDP payload we hash, enough to reach past a QUIC short header.
+#define UDP_PAYLOAD_HASHED 32
+
+// Fields the mark hash can cover, in the order they are hashed.
+// Must match hash::HashSpec in the userspace bundler.
+#define HASH_SRC_IP   0x01
+#define HASH_DST_IP   0x02
+#define HASH_SRC_PORT 0x04
+#define HASH_DST_PORT 0x08
+#define HASH_IP_ID    0x10
+#define HASH_TCP_SEQ  0x20
+#define HASH_PKT_ID   0x40
+// Not a field: hash with SipHash-2-4 under the key the inbox gives us, rather than FNV.
+#define HASH_KEYED    0x80
+#define HASH_ALL      0xff
+// Until the inbox tells us otherwise
+#define HASH_DEFAULT  (HASH_DST_PORT | HASH_PKT_ID)
+
+// Hash the packet for epoch boundary identification, over the fields in spec.
+// Fields the packet does not have (ports outside TCP and UDP, the IP ID outside IPv4, the
+// sequence number outside TCP) are skipped.
+//
+// HASH_PKT_ID is the field which best tells packets of a flow apart, by protocol:
+// - TCP: the IPv4 ID or, for IPv6, the sequence number.
+// - UDP: the first UDP_PAYLOAD_HASHED bytes of the payload.
+// - ICMP: the first 8 bytes of the ICMP header.
+// - anything else: the first 8 bytes of the transport header, then the IPv4 ID if there is one.
+//
+// IPv6 extension headers are skipped with ipv6_skip_exthdr(), which stops at the fragment header
+// of a fragment other than the first. hash::skip_ipv6_ext_headers in the outbox walks them the
+// same way; hash::tests::check_ipv6_ext_headers has a packet with extension headers and its hash.
+//
+// The fields are hashed with FNV, or with SipHash under key if spec has HASH_KEYED.
+// This must match hash::MarkHasher in the outbox.
+// Returns false if the packet is neither IPv4 nor IPv6, has no transport header, or is too short.
+static bool hash_packet(struct sk_buff *skb, u32 spec, const siphash_key_t *key, uint32_t *hash) {
+  unsigned char buf[sizeof(struct udphdr) + UDP_PAYLOAD_HASHED];
+  // both addresses, the ports, the IP ID, the sequence number and the largest packet ID
+  unsigned char fields[2 * sizeof(struct in6_addr) + 2 + 2 + 2 + 4 + UDP_PAYLOAD_HASHED];
+  size_t len = 0;
+  unsigned char *t;
+  unsigned char *saddr;
+  unsigned char *daddr;
+  size_t addr_len;
+  int toff;
+  int udp_payload = 0;
+  __be16 ipid = 0;
+  __be16 frag_off;
+  bool v4;
+  bool ports;
+  u8 proto;
+  u64 h;
+
+  switch (skb->protocol) {
+  case htons(ETH_P_IP):
+    v4 = true;
+    proto = ip_hdr(skb)->protocol;
+    ipid = ip_hdr(skb)->id;
+    saddr = (unsigned char *) &ip_hdr(skb)->saddr;
+    daddr = (unsigned char *) &ip_hdr(skb)->daddr;
+    addr_len = 4;
+    toff = skb_transport_offset(skb);
+    break;
+  case htons(ETH_P_IPV6):
+    v4 = false;
+    proto = ipv6_hdr(skb)->nexthdr;
+    saddr = (unsigned char *) &ipv6_hdr(skb)->saddr;
+    daddr = (unsigned char *) &ipv6_hdr(skb)->daddr;
+    addr_len = 16;
+    // not skb_transport_offset(), which may or may not be past the extension headers
+    toff = ipv6_skip_exthdr(skb, skb_network_offset(skb) + sizeof(struct ipv6hdr), &proto, &frag_off);
+    if (toff < 0)
+      return false;
+    break;
+  default:
+    return false;
+  }
+
+  t = skb_header_pointer(skb, toff, 8, buf);
+  if (!t)
+    return false;
+
+  if (proto == IPPROTO_UDP) {
+    // go by the UDP length, as the outbox may not capture the whole datagram
+    udp_payload = (int) ntohs(((struct udphdr *) t)->len) - (int) sizeof(struct udphdr);
+    udp_payload = clamp_t(int, udp_payload, 0, UDP_PAYLOAD_HASHED);
+    t = skb_header_pointer(skb, toff, sizeof(struct udphdr) + udp_payload, buf);
+    if (!t)
+      return false;
+  }
+
+  ports = proto == IPPROTO_TCP || proto == IPPROTO_UDP;
+
+#define HASH_FIELD(p, n) do { memcpy(fields + len, (p), (n)); len += (n); } while (0)
+  if (spec & HASH_SRC_IP)
+    HASH_FIELD(saddr, addr_len);
+  if (spec & HASH_DST_IP)
+    HASH_FIELD(daddr, addr_len);
+  if ((spec & HASH_SRC_PORT) && ports)
+    HASH_FIELD(t, 2);
+  if ((spec & HASH_DST_PORT) && ports)
+    HASH_FIELD(t + 2, 2);
+  if ((spec & HASH_IP_ID) && v4)
+    HASH_FIELD(&ipid, 2);
+  if ((spec & HASH_TCP_SEQ) && proto == IPPROTO_TCP)
+    HASH_FIELD(t + 4, 4);
+  if (spec & HASH_PKT_ID) {
+    switch (proto) {
+    case IPPROTO_TCP:
+      if (v4)
+        HASH_FIELD(&ipid, 2);
+      else
+        HASH_FIELD(t + 4, 4);
+      break;
+    case IPPROTO_UDP:
+      HASH_FIELD(t + sizeof(struct udphdr), udp_payload);
+      break;
+    case IPPROTO_ICMP:
+    case IPPROTO_ICMPV6:
+      HASH_FIELD(t, 8);
+      break;
+    default:
+      HASH_FIELD(t, 8);
+      if (v4)
+        HASH_FIELD(&ipid, 2);
+      break;
+    }
+  }
+#undef HASH_FIELD
+
+  if (spec & HASH_KEYED)
+    h = siphash(fields, len, key);
+  else
+    h = fnv_64_buf(fields, len, FNV1_64_INIT);
+
+  // only truncate at the end, as the outbox does
+  *hash = (uint32_t) h;
+  return true;
 }
 
 static struct sk_buff *tbf_dequeue(struct Qdisc *sch)
@@ -388,10 +552,11 @@ static struct sk_buff *tbf_dequeue(struct Qdisc *sch)
 #endif
   struct tbf_sched_data *q = qdisc_priv(sch);
   struct sk_buff *skb;
-  struct iphdr *ip_header;
   unsigned char *transport_header; 
   struct tcphdr *tcp_header;
+  const struct bundle_hash_cfg *hash_cfg;
   uint32_t hash;
+  bool hashed;
 
   skb = q->qdisc->ops->peek(q->qdisc);
 
@@ -424,12 +589,20 @@ static struct sk_buff *tbf_dequeue(struct Qdisc *sch)
       q->epoch_pkts_sent += skb_is_gso(skb) ? skb_shinfo(skb)->gso_segs : 1;
 
       transport_header = skb_transport_header(skb);
-      if (transport_header) { 
-          ip_header = (struct iphdr *)skb_network_header(skb);
-          hash = hash_header((unsigned char*) &(ip_header->daddr), transport_header, (unsigned char*) &(ip_header->id));
+      rcu_read_lock();
+      hash_cfg = rcu_dereference(q->hash_cfg);
+      hashed = transport_header && hash_packet(skb, hash_cfg->spec, &hash_cfg->key, &hash);
+      rcu_read_unlock();
+      if (hashed) { 
           if (hash % q->epoch_sample_rate == 0) {
               struct FeedbackMsg fmsg = {
-                  .bundle_id = 42,
+                  .hdr = {
+                      .magic = BUNDLER_MAGIC,
+                      .version = BUNDLER_PROTOCOL_VERSION,
+                      .msg_type = BUNDLER_MSG_QDISC_FEEDBACK,
+                      .len = sizeof(struct FeedbackMsg),
+                  },
+                  .bundle_id = q->bundle_id,
                   .marked_packet_hash = hash,
                   .curr_qlen = sch->q.qlen,
                   .epoch_bytes_sent = q->epoch_bytes_sent,
@@ -739,25 +912,69 @@ done:
 
 
 struct __attribute__((packed, aligned(4))) QDiscUpdateMsg {
+    struct BundlerMsgHdr hdr;
     u32 bundle_id;
+    u32 ifindex;
     u32 sample_rate;
+    u32 hash_spec;
+    u8 hash_key[16];
 };
 
-struct tbf_sched_data *sch_bundle_inbox_q;
-
 void tbf_nl_recv_msg(struct sk_buff *skb) {
     struct QDiscUpdateMsg msg;
     struct nlmsghdr *nlh = nlmsg_hdr(skb);
+    struct tbf_sched_data *q;
+    struct bundle_hash_cfg *old_cfg;
+    struct bundle_hash_cfg *new_cfg;
 
-    if (sch_bundle_inbox_q == NULL) {
+    if (nlmsg_len(nlh) < sizeof(struct QDiscUpdateMsg)) {
         return;
     }
 
     memcpy(&msg, nlmsg_data(nlh), sizeof(struct QDiscUpdateMsg));
-    if (msg.sample_rate != 0) {
-        sch_bundle_inbox_q->epoch_sample_rate = msg.sample_rate;
-        pr_info("[sch_bundle_inbox] epoch_len %u\n", msg.sample_rate);
+    if (msg.hdr.magic != BUNDLER_MAGIC || msg.hdr.msg_type != BUNDLER_MSG_QDISC_UPDATE) {
+        return;
+    }
+
+    if (msg.hdr.version != BUNDLER_PROTOCOL_VERSION) {
+        pr_info("[sch_bundle_inbox] ignoring update: protocol version %u, expected %u\n",
+                msg.hdr.version, BUNDLER_PROTOCOL_VERSION);
+        return;
+    }
+
+    // the qdisc on the given interface belongs to the given bundle
+    spin_lock_bh(&bundle_inbox_lock);
+    list_for_each_entry(q, &bundle_inbox_qdiscs, bundles) {
+        if ((u32) q->ifindex != msg.ifindex) {
+            continue;
+        }
+
+        q->bundle_id = msg.bundle_id;
+        if (msg.sample_rate != 0) {
+            q->epoch_sample_rate = msg.sample_rate;
+            pr_info("[sch_bundle_inbox] bundle %u epoch_len %u\n", msg.bundle_id, msg.sample_rate);
+        }
+
+        if ((msg.hash_spec & ~HASH_KEYED) != 0 && (msg.hash_spec & ~HASH_ALL) == 0) {
+            new_cfg = kmalloc(sizeof(*new_cfg), GFP_ATOMIC);
+            if (!new_cfg) {
+                pr_err("[sch_bundle_inbox] bundle %u: no memory to update hash_spec\n", msg.bundle_id);
+                continue;
+            }
+
+            // the key is all zeros unless the spec is keyed; do not log it
+            new_cfg->spec = msg.hash_spec;
+            new_cfg->key.key[0] = get_unaligned_le64(msg.hash_key);
+            new_cfg->key.key[1] = get_unaligned_le64(msg.hash_key + 8);
+            old_cfg = rcu_dereference_protected(q->hash_cfg, lockdep_is_held(&bundle_inbox_lock));
+            rcu_assign_pointer(q->hash_cfg, new_cfg);
+            if (msg.hash_spec != old_cfg->spec) {
+                pr_info("[sch_bundle_inbox] bundle %u hash_spec %#x\n", msg.bundle_id, msg.hash_spec);
+            }
+            kfree_rcu(old_cfg, rcu);
+        }
     }
+    spin_unlock_bh(&bundle_inbox_lock);
     return;
 }
 
@@ -769,10 +986,8 @@ static int tbf_init(struct Qdisc *sch, struct nlattr *opt,
 #endif
 {
   struct tbf_sched_data *q = qdisc_priv(sch);
-	struct netlink_kernel_cfg cfg = {
-		.input = tbf_nl_recv_msg,
-	};
-  sch_bundle_inbox_q = q;
+  struct bundle_hash_cfg *hash_cfg;
+  INIT_LIST_HEAD(&q->bundles);
 
   q->t_c = ktime_get_ns();
   qdisc_watchdog_init(&q->watchdog, sch);
@@ -782,17 +997,23 @@ static int tbf_init(struct Qdisc *sch, struct nlattr *opt,
     return -EINVAL;
   }
 
+  q->ifindex = qdisc_dev(sch)->ifindex;
+  q->bundle_id = DEFAULT_BUNDLE_ID;
   q->epoch_sample_rate = PACKET_SAMPLE_RATE;
+  hash_cfg = kzalloc(sizeof(*hash_cfg), GFP_KERNEL);
+  if (!hash_cfg) {
+    return -ENOMEM;
+  }
+  hash_cfg->spec = HASH_DEFAULT;
+  RCU_INIT_POINTER(q->hash_cfg, hash_cfg);
 	q->epoch_bytes_sent = 0;
 	q->epoch_pkts_sent = 0;
 
-	q->nl_sock = netlink_kernel_create(&init_net, NETLINK_USERSOCK, &cfg);
-	if (!q->nl_sock) {
-		printk(KERN_INFO "[sch_bundle_inbox] init: error creating netlink socket\n");
-		return -EINVAL;
-	}
+  spin_lock_bh(&bundle_inbox_lock);
+  list_add(&q->bundles, &bundle_inbox_qdiscs);
+  spin_unlock_bh(&bundle_inbox_lock);
 
-	printk(KERN_INFO "bundle_inbox_init: created netlink socket\n");
+	printk(KERN_INFO "bundle_inbox_init: ifindex %d\n", q->ifindex);
 #if LINUX_VERSION_CODE >= KERNEL_VERSION(4,15,0) && LINUX_VERSION_CODE <= KERNEL_VERSION(4,16,0)
   return tbf_change(sch, opt);
 #elif LINUX_VERSION_CODE >= KERNEL_VERSION(4,16,0)
@@ -803,9 +1024,17 @@ static int tbf_init(struct Qdisc *sch, struct nlattr *opt,
 static void tbf_destroy(struct Qdisc *sch)
 {
   struct tbf_sched_data *q = qdisc_priv(sch);
-  if (q->nl_sock) {
-    netlink_kernel_release(q->nl_sock);
-    q->nl_sock = NULL;
+  struct bundle_hash_cfg *hash_cfg;
+  spin_lock_bh(&bundle_inbox_lock);
+  if (!list_empty(&q->bundles)) {
+    list_del_init(&q->bundles);
+  }
+  // off the list, so no update can swap it any more
+  hash_cfg = rcu_dereference_protected(q->hash_cfg, lockdep_is_held(&bundle_inbox_lock));
+  RCU_INIT_POINTER(q->hash_cfg, NULL);
+  spin_unlock_bh(&bundle_inbox_lock);
+  if (hash_cfg) {
+    kfree_rcu(hash_cfg, rcu);
   }
   qdisc_watchdog_cancel(&q->watchdog);
 #if LINUX_VERSION_CODE >= KERNEL_VERSION(4,20,0)
@@ -934,9 +1163,21 @@ static struct Qdisc_ops tbf_qdisc_ops __read_mostly = {
 
 static int __init tbf_module_init(void)
 {
-    int ok = register_qdisc(&tbf_qdisc_ops);
+    int ok;
+	struct netlink_kernel_cfg cfg = {
+		.input = tbf_nl_recv_msg,
+	};
+
+	bundle_nl_sock = netlink_kernel_create(&init_net, NETLINK_USERSOCK, &cfg);
+	if (!bundle_nl_sock) {
+		printk(KERN_INFO "[sch_bundle_inbox] init: error creating netlink socket\n");
+		return -EINVAL;
+	}
+
+    ok = register_qdisc(&tbf_qdisc_ops);
     if (ok < 0) {
         pr_info("[sch_bundle_inbox] register_qdisc failed: %d\n", ok);
+        netlink_kernel_release(bundle_nl_sock);
         return ok;
     }
 
@@ -961,6 +1202,7 @@ static void __exit tbf_module_exit(void)
 {
   printk(KERN_INFO "[sch_bundle_inbox] exit\n");
   unregister_qdisc(&tbf_qdisc_ops);
+  netlink_kernel_release(bundle_nl_sock);
 }
 module_init(tbf_module_init)
 module_exit(tbf_module_exit)
@@ -85,7 +85,7 @@ fn setup_qdisc(
     iface: &str,
     self_port: u16,
     verbose: bool,
-    matches: clap::ArgMatches,
+    matches: &clap::ArgMatches,
 ) -> (u32, u32) {
     use std::path::PathBuf;
     info!(logger, "Installing bundler qdisc"; "interface" => iface);
@@ -278,10 +278,21 @@ fn main() {
             Arg::with_name("iface")
                 .short("i")
                 .long("iface")
-                .help("Interface to listen on")
+                .help("Interface to listen on, once per bundle")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("bundle_id")
+                .long("bundle_id")
+                .help("Id of the bundle on each interface, in the same order as --iface")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .default_value("42"),
+        )
         .arg(
             Arg::with_name("port")
                 .short("p")
@@ -308,8 +319,10 @@ fn main() {
         .arg(
             Arg::with_name("outbox")
                 .long("outbox")
-                .help("address of outbox")
-                .takes_value(true),
+                .help("addresses of each bundle's outboxes, comma-separated, in the same order as --iface")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
         )
         .arg(
             Arg::with_name("tc_lib_dir")
@@ -343,6 +356,51 @@ fn main() {
                 .takes_value(true)
                 .required(false)
         )
+        .arg(
+            Arg::with_name("psk_file")
+                .long("psk_file")
+                .takes_value(true)
+                .required(false)
+                .help("file holding a key shared with the outboxes; if given, messages are authenticated with it")
+        )
+        .arg(
+            Arg::with_name("liveness_timeout_ms")
+                .long("liveness_timeout_ms")
+                .takes_value(true)
+                .default_value("2000")
+                .help("consider an outbox lost after hearing nothing from it for this long")
+        )
+        .arg(
+            Arg::with_name("on_outbox_lost")
+                .long("on_outbox_lost")
+                .takes_value(true)
+                .possible_values(&["hold", "decay", "remove"])
+                .default_value("hold")
+                .help("while an outbox is lost, hold its bundle's rate, halve it every second down to --rate_floor, or remove shaping")
+        )
+        .arg(
+            Arg::with_name("rate_floor")
+                .long("rate_floor")
+                .takes_value(true)
+                .default_value("125000")
+                .help("lowest rate, in bytes/s, to decay to with --on_outbox_lost decay")
+        )
+        .arg(
+            Arg::with_name("hash_spec")
+                .long("hash_spec")
+                .takes_value(true)
+                .default_value("dst_port,pkt_id")
+                .help("packet fields to hash for marks, from src_ip, dst_ip, src_port, dst_port, ip_id, tcp_seq, pkt_id, plus keyed to hash with a key derived from --psk_file; must match the outboxes'")
+        )
+        .arg(
+            Arg::with_name("rate_estimator")
+                .long("rate_estimator")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(&["sum", "ewma", "max", "median"])
+                .help("how each bundle turns measurement epochs into send and receive rates, in the same order as --iface: the sum over a window of epochs (the default), a moving average, a windowed max, or the median epoch")
+        )
         .arg(
             Arg::with_name("sip")
                 .long("sip")
@@ -352,7 +410,21 @@ fn main() {
         )
         .get_matches();
 
-    let iface = String::from(matches.value_of("iface").unwrap());
+    let ifaces: Vec<String> = matches.values_of("iface").unwrap().map(String::from).collect();
+    let bundle_ids: Vec<u32> = matches
+        .values_of("bundle_id")
+        .unwrap()
+        .map(|b| b.parse().unwrap())
+        .collect();
+    let outboxes: Vec<Vec<String>> = match matches.values_of("outbox") {
+        Some(o) => o.map(|a| a.split(',').map(String::from).collect()).collect(),
+        None => vec![vec![]; ifaces.len()],
+    };
+    use bundler::inbox::RateEstimatorKind;
+    let rate_estimators: Vec<RateEstimatorKind> = match matches.values_of("rate_estimator") {
+        Some(r) => r.map(|k| k.parse().unwrap()).collect(),
+        None => vec![RateEstimatorKind::WindowSum; ifaces.len()],
+    };
     let listen_port = value_t!(matches.value_of("port"), u16).unwrap();
     let sample_rate = matches.value_of("sample_rate").unwrap().parse().unwrap();
     let dynamic_sample_rate = matches
@@ -360,24 +432,76 @@ fn main() {
         .unwrap()
         .parse::<bool>()
         .unwrap();
-    let outbox = matches.value_of("outbox").map(String::from);
 
     let log = portus::algs::make_logger();
 
-    let verbose = matches.is_present("verbose");
+    let psk = matches.value_of("psk_file").map(|f| {
+        bundler::serialize::auth::read_key_file(f).expect("read pre-shared key")
+    });
+
+    if bundle_ids.len() != ifaces.len()
+        || outboxes.len() != ifaces.len()
+        || rate_estimators.len() != ifaces.len()
+    {
+        error!(log, "need one --bundle_id (and --outbox and --rate_estimator, if given) per --iface";
+            "ifaces" => ifaces.len(),
+            "bundle_ids" => bundle_ids.len(),
+            "outboxes" => outboxes.len(),
+            "rate_estimators" => rate_estimators.len(),
+        );
+        return;
+    }
+
+    use bundler::inbox::session::{LivenessConfig, LostPolicy};
+    let liveness = LivenessConfig {
+        timeout: std::time::Duration::from_millis(
+            value_t!(matches.value_of("liveness_timeout_ms"), u64).unwrap(),
+        ),
+        on_lost: match matches.value_of("on_outbox_lost").unwrap() {
+            "decay" => LostPolicy::Decay {
+                floor: value_t!(matches.value_of("rate_floor"), u32).unwrap(),
+            },
+            "remove" => LostPolicy::Remove,
+            _ => LostPolicy::Hold,
+        },
+    };
 
-    let (handle_major, handle_minor) = setup_qdisc(&log, &iface, listen_port, verbose, matches);
+    let hash_spec = value_t!(matches.value_of("hash_spec"), bundler::hash::HashSpec).unwrap();
+    if hash_spec.contains(bundler::hash::HashSpec::KEYED) && psk.is_none() {
+        error!(log, "keyed mark hashing needs a pre-shared key"; "hash_spec" => %hash_spec);
+        return;
+    }
+
+    let verbose = matches.is_present("verbose");
 
-    use bundler::inbox::Runtime;
+    use bundler::inbox::{BundleConfig, Runtime};
     use minion::Cancellable;
+    let bundles = ifaces
+        .into_iter()
+        .zip(bundle_ids)
+        .zip(outboxes)
+        .zip(rate_estimators)
+        .map(|(((iface, bundle_id), outboxes), rate_estimator)| {
+            let handle = setup_qdisc(&log, &iface, listen_port, verbose, &matches);
+            BundleConfig {
+                bundle_id,
+                iface,
+                handle,
+                outboxes,
+                hash_spec,
+                rate_estimator,
+            }
+        })
+        .collect();
+
     let mut r = Runtime::new(
         log,
         listen_port,
-        outbox,
-        iface,
-        (handle_major, handle_minor),
+        bundles,
         dynamic_sample_rate,
         sample_rate,
+        psk,
+        liveness,
     )
     .unwrap();
     r.run().unwrap()
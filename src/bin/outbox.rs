@@ -6,14 +6,13 @@ extern crate time;
 #[cfg(target_os = "linux")]
 fn main() {
     use clap::{value_t, App, Arg};
-    use pcap::{Capture, Device};
 
-    use bundler::serialize;
-    use bundler::serialize::OutBoxFeedbackMsg;
+    use bundler::serialize::auth::{Opener, Sealer};
+    use bundler::serialize::{PathStats, SessionMsg};
 
-    use std::net::UdpSocket;
     use std::sync::mpsc;
     use std::thread;
+    use std::time::Instant;
     let matches = App::new("outbox")
         .version("0.1")
         .arg(
@@ -43,9 +42,52 @@ fn main() {
         .arg(
             Arg::with_name("inbox")
                 .long("inbox")
-                .help("address of inbox")
+                .help("address of inbox; if not given, wait for the inbox to contact us")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("bundle_id")
+                .long("bundle_id")
+                .help("id of the bundle this outbox serves")
+                .default_value("42"),
+        )
+        .arg(
+            Arg::with_name("psk_file")
+                .long("psk_file")
+                .help("file holding a key shared with the inbox; if given, messages are authenticated with it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("liveness_timeout_ms")
+                .long("liveness_timeout_ms")
+                .help("consider the inbox lost, and handshake again, after hearing nothing from it for this long")
+                .default_value("2000"),
+        )
+        .arg(
+            Arg::with_name("feedback_batch_us")
+                .long("feedback_batch_us")
+                .help("hold feedback back for up to this long to send several marks per datagram; 0 sends each mark right away")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("hash_spec")
+                .long("hash_spec")
+                .help("packet fields to hash for marks, from src_ip, dst_ip, src_port, dst_port, ip_id, tcp_seq, pkt_id, plus keyed to hash with a key derived from --psk_file; must match the inbox's")
+                .default_value("dst_port,pkt_id"),
+        )
+        .arg(
+            Arg::with_name("capture")
+                .long("capture")
+                .help("how to capture packets: through libpcap, or from an AF_PACKET ring, which keeps up with faster links")
+                .possible_values(&["pcap", "af_packet"])
+                .default_value("pcap"),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .help("capture on this many threads, splitting packets between them by flow; more than one needs --capture af_packet")
+                .default_value("1"),
+        )
         .arg(
             Arg::with_name("no_ethernet")
                 .long("no_ethernet")
@@ -57,92 +99,600 @@ fn main() {
 
     let iface = matches.value_of("iface").unwrap();
     let filter = matches.value_of("filter").unwrap();
-    let mut sample_rate = value_t!(matches.value_of("sample_rate"), u32).unwrap();
+    let sample_rate = value_t!(matches.value_of("sample_rate"), u32).unwrap();
     let no_ethernet = matches.is_present("no_ethernet");
+    let bundle_id = value_t!(matches.value_of("bundle_id"), u32).unwrap();
+    let liveness_timeout = std::time::Duration::from_millis(
+        value_t!(matches.value_of("liveness_timeout_ms"), u64).unwrap(),
+    );
+    let batch_delay = std::time::Duration::from_micros(
+        value_t!(matches.value_of("feedback_batch_us"), u64).unwrap(),
+    );
+    let hash_spec = value_t!(matches.value_of("hash_spec"), bundler::hash::HashSpec).unwrap();
+    let workers = value_t!(matches.value_of("workers"), usize).unwrap();
 
-    let devs = Device::list().unwrap();
-    let dev = devs.into_iter().find(|dev| dev.name == iface);
-    let mut cap = Capture::from_device(dev.unwrap())
-        .unwrap()
-        .promisc(false) // Promiscuous mode because the packets are not destined for our IP
-        .snaplen(42) // We only need up to byte 42 to read the sequence number
-        .immediate_mode(true)
-        .open()
-        .unwrap();
-    cap.filter(filter).unwrap();
-
-    let mut inbox = matches.value_of("inbox").map(|a| {
+    let inbox = matches.value_of("inbox").map(|a| {
         use std::net::ToSocketAddrs;
         a.to_socket_addrs().unwrap().next().unwrap()
     });
 
-    let sock = UdpSocket::bind("0.0.0.0:28317").expect("failed to create UDP socket");
+    let log = portus::algs::make_logger();
+
+    use bundler::outbox::af_packet::{AfPacketRing, Fanout, RingConfig};
+    let capture = matches.value_of("capture").unwrap();
+    if workers > 1 && capture != "af_packet" {
+        slog::error!(log, "several workers need --capture af_packet"; "workers" => workers);
+        return;
+    }
+
+    let cap: Result<Capture, _> = match capture {
+        "af_packet" if workers > 1 => {
+            // an idle worker holds the others' marks back until its ring times out, so keep that short
+            let config = RingConfig {
+                fanout: Some(Fanout::New),
+                poll_timeout_ms: 10,
+                ..Default::default()
+            };
+            AfPacketRing::open(iface, filter, !no_ethernet, config).and_then(|first| {
+                // the rest join the group the kernel made for the first
+                let config = RingConfig {
+                    fanout: first.fanout_group().map(Fanout::Join),
+                    ..config
+                };
+                let mut rings = vec![first];
+                for _ in 1..workers {
+                    rings.push(AfPacketRing::open(iface, filter, !no_ethernet, config)?);
+                }
+
+                Ok(Capture::Fanout(rings))
+            })
+        }
+        "af_packet" => AfPacketRing::open(iface, filter, !no_ethernet, Default::default())
+            .map(|r| Capture::Single(Box::new(r))),
+        _ => bundler::outbox::source::live(iface, filter).map(|c| Capture::Single(Box::new(c))),
+    };
+    let cap = match cap {
+        Ok(cap) => cap,
+        Err(e) => {
+            slog::error!(log, "cannot capture packets"; "iface" => iface, "err" => %e);
+            return;
+        }
+    };
+
+    let psk = matches.value_of("psk_file").map(|f| {
+        bundler::serialize::auth::read_key_file(f).expect("read pre-shared key")
+    });
+    if hash_spec.contains(bundler::hash::HashSpec::KEYED) && psk.is_none() {
+        slog::error!(log, "keyed mark hashing needs a pre-shared key"; "hash_spec" => %hash_spec);
+        return;
+    }
+
+    let sealer = psk.as_ref().map(|k| Sealer::new(k));
+    let reports = ReportReader {
+        auth: psk.as_ref().map(|k| Opener::new(k)),
+        drops: bundler::drops::DropCounter::new("message from inbox"),
+    };
+
+    let sock = bundler::bind_udp(28317).expect("failed to create UDP socket");
     let recv_sock = sock.try_clone().expect("Clone recv_sock");
-    if let None = inbox {
-        let mut buf = [0u8; 64];
-        match recv_sock.recv_from(&mut buf) {
-            Ok((bytes, addr)) => {
-                inbox = Some(addr);
-                if bytes == 8 {
-                    let msg = serialize::OutBoxReportMsg::from_slice(&buf);
-                    sample_rate = msg.epoch_length_packets;
+    recv_sock
+        .set_read_timeout(Some(HEARTBEAT_INTERVAL))
+        .expect("set UDP socket timeout");
+
+    let (tx, rx) = crossbeam::unbounded::<(u64, u32, u64, PathStats)>();
+    let (s, r) = mpsc::channel();
+    let (peer_tx, peer_rx) = mpsc::channel();
+    let session = InboxSession {
+        log: log.clone(),
+        bundle_id,
+        sk: recv_sock.try_clone().expect("Clone session sock"),
+        auth: sealer.clone(),
+        id: SessionMsg::new_session_id(),
+        inbox,
+        peer: None,
+        alive: false,
+        last_heard: std::time::Instant::now(),
+        liveness_timeout,
+        epoch_length: sample_rate,
+        last_init: None,
+        last_heartbeat: None,
+        new_peer: peer_tx,
+        mark_updates: s,
+        hash_spec,
+        psk,
+    };
+
+    let mut feedback = FeedbackSender {
+        log: log.clone(),
+        bundle_id,
+        sk: sock,
+        auth: sealer,
+        targets: peer_rx,
+        inbox: None,
+        batch: false,
+        path_stats: false,
+        max_delay: batch_delay,
+        seq: 0,
+        pending: vec![],
+        oldest: None,
+        drops: bundler::drops::DropCounter::new("feedback to inbox"),
+    };
+    thread::spawn(move || loop {
+        let next = match feedback.deadline() {
+            Some(d) => match rx.recv_timeout(d.saturating_duration_since(Instant::now())) {
+                Ok(x) => Some(x),
+                Err(crossbeam::RecvTimeoutError::Timeout) => None,
+                Err(crossbeam::RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    slog::error!(feedback.log, "Error getting next OutBoxFeedbackMsg to send"; "err" => ?e);
+                    break;
                 }
+            },
+        };
+
+        feedback.update_target();
+        if let Some((ts, hash, recvd, stats)) = next {
+            feedback.push(ts, hash, recvd, stats);
+        }
+
+        if let Some(d) = feedback.deadline() {
+            if d <= Instant::now() {
+                feedback.flush();
             }
-            Err(e) => println!("{:?}", e),
         }
+    });
+
+    // if the session gives up, the outbox stops as its mark updates do
+    let session_log = log.clone();
+    thread::spawn(move || match session.run(recv_sock, reports) {
+        Err(SessionEnd::Incompatible(e)) => slog::crit!(session_log, "incompatible inbox"; "err" => %e),
+        _ => slog::debug!(session_log, "outbox stopped, ending session with inbox"),
+    });
+
+    slog::info!(&log, "starting outbox");
+
+    let hasher = bundler::hash::MarkHasher::new(hash_spec);
+    let res = match cap {
+        Capture::Single(src) => {
+            bundler::outbox::start_outbox(src, tx, r, sample_rate, no_ethernet, hasher, log.clone())
+        }
+        Capture::Fanout(rings) => bundler::outbox::fanout::start_fanout_outbox(
+            rings,
+            tx,
+            r,
+            sample_rate,
+            no_ethernet,
+            hasher,
+            log.clone(),
+        ),
+    };
+    if let Err(e) = res {
+        slog::error!(log, "outbox stopped"; "err" => %e);
     }
+}
 
-    let (tx, rx) = crossbeam::unbounded::<(u64, u32, u64)>();
-    let log = portus::algs::make_logger();
-    let feedback_log = log.clone();
-    let recv_log = log.clone();
+/// Where packets come from: one source, or several rings in a fanout group, one per worker.
+#[cfg(target_os = "linux")]
+enum Capture {
+    Single(Box<dyn bundler::outbox::source::PacketSource>),
+    Fanout(Vec<bundler::outbox::af_packet::AfPacketRing>),
+}
 
-    thread::spawn(move || loop {
-        let (ts, hash, recvd) = match rx.recv() {
-            Ok(x) => x,
-            Err(e) => {
-                slog::error!(feedback_log, "Error getting next OutBoxFeedbackMsg to send"; "err" => ?e);
-                break;
+/// Where feedback should go, told to the feedback thread whenever it changes.
+#[cfg(target_os = "linux")]
+struct FeedbackTarget {
+    inbox: std::net::SocketAddr,
+    // the inbox restarted, so numbering feedback starts over
+    new_session: bool,
+    // the inbox accepts batched feedback
+    batch: bool,
+    // the inbox accepts feedback with upstream loss statistics
+    path_stats: bool,
+}
+
+/// Numbers feedback and sends it to the inbox, batching it if the inbox supports that and we are
+/// allowed to hold feedback back for `max_delay`.
+#[cfg(target_os = "linux")]
+struct FeedbackSender {
+    log: slog::Logger,
+    bundle_id: u32,
+    sk: std::net::UdpSocket,
+    auth: Option<bundler::serialize::auth::Sealer>,
+    targets: std::sync::mpsc::Receiver<FeedbackTarget>,
+    inbox: Option<std::net::SocketAddr>,
+    batch: bool,
+    path_stats: bool,
+    max_delay: std::time::Duration,
+    seq: u32,
+    pending: Vec<bundler::serialize::FeedbackRecord>,
+    oldest: Option<std::time::Instant>,
+    drops: bundler::drops::DropCounter,
+}
+
+#[cfg(target_os = "linux")]
+impl FeedbackSender {
+    fn update_target(&mut self) {
+        while let Ok(t) = self.targets.try_recv() {
+            if t.new_session {
+                self.pending.clear();
+                self.oldest = None;
+                self.seq = 0;
+            } else if t.batch != self.batch {
+                // send what we have the way the inbox took it so far
+                self.flush();
             }
-        };
-        let msg = OutBoxFeedbackMsg {
-            bundle_id: 42,
+
+            self.inbox = Some(t.inbox);
+            self.batch = t.batch;
+            self.path_stats = t.path_stats;
+        }
+    }
+
+    /// When the pending batch must be sent.
+    fn deadline(&self) -> Option<std::time::Instant> {
+        self.oldest.map(|t| t + self.max_delay)
+    }
+
+    fn push(&mut self, ts: u64, hash: u32, recvd: u64, stats: bundler::serialize::PathStats) {
+        if self.inbox.is_none() {
+            slog::trace!(self.log, "no session with inbox, dropping feedback");
+            return;
+        }
+
+        self.pending.push(bundler::serialize::FeedbackRecord {
             marked_packet_hash: hash,
             epoch_bytes: recvd,
             epoch_time: ts,
+            stats: if self.path_stats { Some(stats) } else { None },
+        });
+
+        if !self.batch
+            || self.max_delay == std::time::Duration::from_secs(0)
+            || self.pending.len() >= bundler::serialize::MAX_FEEDBACK_BATCH
+        {
+            self.flush();
+        } else if self.oldest.is_none() {
+            self.oldest = Some(std::time::Instant::now());
+        }
+    }
+
+    fn flush(&mut self) {
+        self.oldest = None;
+        let to = match self.inbox {
+            Some(addr) if !self.pending.is_empty() => addr,
+            _ => return,
         };
 
-        sock.send_to(msg.as_bytes().as_slice(), inbox.unwrap())
-            .expect("failed to send on UDP socket");
-    });
+        let count = self.pending.len() as u32;
+        let now = time::get_time();
+        let mut buf = bundler::serialize::feedback_datagram(
+            self.bundle_id,
+            self.seq,
+            self.pending.split_off(0),
+            self.batch,
+            // same clock as the pcap timestamps of the records
+            now.sec as u64 * 1_000_000_000 + now.nsec as u64,
+        );
+        self.seq = self.seq.wrapping_add(count);
 
-    let (s, r) = mpsc::channel();
-    thread::spawn(move || {
+        if let Some(ref sealer) = self.auth {
+            sealer.seal(&mut buf);
+        }
+
+        // e.g. the route to the inbox went away; the session notices if it stays away
+        if let Err(e) = self.sk.send_to(buf.as_slice(), to) {
+            slog::debug!(self.log, "failed to send feedback"; "to" => %to, "err" => %e);
+            self.drops.add(&self.log, "send failed");
+        }
+    }
+}
+
+/// How often to retry reaching the inbox while we have no session with it.
+#[cfg(target_os = "linux")]
+const INIT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+#[cfg(target_os = "linux")]
+const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Why the session with the inbox ended.
+#[cfg(target_os = "linux")]
+enum SessionEnd {
+    /// Our inbox speaks another protocol version.
+    Incompatible(bundler::serialize::DecodeError),
+    /// The outbox stopped taking updates.
+    OutboxStopped,
+}
+
+/// The outbox's side of the session with the inbox.
+/// We send inits to the inbox given on the command line until it answers, and answer inits from
+/// any inbox. An inbox whose session id changes has restarted, so feedback numbering restarts too.
+/// Once the session is up we send heartbeats, and if the inbox goes quiet we send inits again.
+#[cfg(target_os = "linux")]
+struct InboxSession {
+    log: slog::Logger,
+    bundle_id: u32,
+    sk: std::net::UdpSocket,
+    auth: Option<bundler::serialize::auth::Sealer>,
+    id: u64,
+    inbox: Option<std::net::SocketAddr>,
+    // the inbox's session id, once we have a session
+    peer: Option<u64>,
+    alive: bool,
+    last_heard: std::time::Instant,
+    liveness_timeout: std::time::Duration,
+    epoch_length: u32,
+    last_init: Option<std::time::Instant>,
+    last_heartbeat: Option<std::time::Instant>,
+    // tells the feedback thread where the inbox is
+    new_peer: std::sync::mpsc::Sender<FeedbackTarget>,
+    mark_updates: std::sync::mpsc::Sender<bundler::outbox::MarkUpdate>,
+    hash_spec: bundler::hash::HashSpec,
+    // to derive the mark hash key of each inbox session, if the hash is keyed
+    psk: Option<Vec<u8>>,
+}
+
+#[cfg(target_os = "linux")]
+impl InboxSession {
+    /// Runs until our inbox turns out to speak another protocol version, or the outbox stops.
+    fn run(mut self, sk: std::net::UdpSocket, mut reports: ReportReader) -> Result<(), SessionEnd> {
         let mut recv_buf = [0u8; 64];
         loop {
-            match recv_sock.recv(&mut recv_buf) {
-                Ok(bytes) => {
-                    if bytes == 8 {
-                        let msg = serialize::OutBoxReportMsg::from_slice(&recv_buf);
-                        s.send(msg.epoch_length_packets).unwrap();
+            match sk.recv_from(&mut recv_buf) {
+                Ok((bytes, from)) => {
+                    let from = bundler::unmap_addr(from);
+                    let from_peer = self.peer.is_some() && self.inbox == Some(from);
+                    let msg = reports
+                        .decode(&self.log, from, &recv_buf[..bytes], from_peer)
+                        .map_err(SessionEnd::Incompatible)?;
+                    match msg {
+                        Some(InboxMsg::Session(msg)) => self.got_session_msg(from, msg)?,
+                        Some(InboxMsg::Report(msg)) => self.got_report(from, msg)?,
+                        Some(InboxMsg::Heartbeat(msg)) => self.got_heartbeat(from, msg),
+                        None => (),
                     }
                 }
-                Err(e) => slog::warn!(recv_log, "Error getting OutBoxReportMsg"; "err" => ?e),
+                Err(ref e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut => {}
+                Err(e) => slog::warn!(self.log, "Error receiving from inbox"; "err" => ?e),
+            }
+
+            if self.alive && self.last_heard.elapsed() > self.liveness_timeout {
+                slog::warn!(self.log, "lost contact with inbox";
+                    "silent_ms" => self.last_heard.elapsed().as_millis() as u64,
+                );
+                self.alive = false;
+            }
+
+            match self.inbox {
+                Some(addr) if !self.alive => self.send_init(addr),
+                Some(addr) => self.send_heartbeat(addr),
+                None => (),
             }
         }
-    });
+    }
 
-    slog::info!(&log, "starting outbox");
+    fn heard(&mut self) {
+        self.last_heard = std::time::Instant::now();
+        if !self.alive {
+            slog::info!(self.log, "inbox alive");
+            self.alive = true;
+        }
+    }
+
+    fn got_heartbeat(&mut self, from: std::net::SocketAddr, msg: bundler::serialize::HeartbeatMsg) {
+        if msg.bundle_id != self.bundle_id {
+            return;
+        }
+
+        if self.peer != Some(msg.session_id) || self.inbox != Some(from) {
+            // this inbox does not know us (any more)
+            slog::debug!(self.log, "heartbeat from unknown inbox"; "from" => %from);
+            self.send_init(from);
+            return;
+        }
+
+        self.heard();
+    }
 
-    bundler::outbox::start_outbox(
-        cap,
-        tx,
-        r,
-        sample_rate,
-        no_ethernet,
-        log,
-    )
-    .expect("outbox returned error");
+    fn got_session_msg(
+        &mut self,
+        from: std::net::SocketAddr,
+        msg: bundler::serialize::SessionMsg,
+    ) -> Result<(), SessionEnd> {
+        if msg.bundle_id != self.bundle_id {
+            slog::warn!(self.log, "SessionMsg for another bundle"; "bundle" => msg.bundle_id);
+            return Ok(());
+        }
+
+        if !msg.ack {
+            self.send_session_msg(from, true);
+        }
+
+        let new_session = self.peer != Some(msg.session_id);
+        if new_session || self.inbox != Some(from) {
+            slog::info!(self.log, "session established";
+                "inbox" => %from,
+                "session" => msg.session_id,
+                "restarted" => new_session && self.peer.is_some(),
+                "capabilities" => msg.capabilities & bundler::serialize::CAPABILITIES,
+            );
+            if msg.hash_spec != self.hash_spec.bits() {
+                slog::warn!(self.log, "inbox hashes packets differently, marks will not match";
+                    "ours" => %self.hash_spec,
+                    "inbox" => bundler::hash::HashSpec::describe(msg.hash_spec),
+                );
+            }
+            if new_session && self.hash_spec.contains(bundler::hash::HashSpec::KEYED) {
+                if let Some(ref psk) = self.psk {
+                    let key = bundler::serialize::auth::mark_hash_key(psk, msg.session_id);
+                    self.update_marks(bundler::outbox::MarkUpdate::HashKey(key))?;
+                }
+            }
+            self.peer = Some(msg.session_id);
+            self.inbox = Some(from);
+            self.new_peer
+                .send(FeedbackTarget {
+                    inbox: from,
+                    new_session,
+                    batch: msg.capabilities & bundler::serialize::CAP_BATCHED_FEEDBACK != 0,
+                    path_stats: msg.capabilities & bundler::serialize::CAP_PATH_STATS != 0,
+                })
+                .unwrap_or(());
+        }
+
+        self.heard();
+        self.set_epoch_length(msg.epoch_length_packets)
+    }
+
+    fn got_report(
+        &mut self,
+        from: std::net::SocketAddr,
+        msg: bundler::serialize::OutBoxReportMsg,
+    ) -> Result<(), SessionEnd> {
+        if msg.bundle_id != self.bundle_id {
+            slog::warn!(self.log, "OutBoxReportMsg for another bundle";
+                "bundle" => msg.bundle_id,
+            );
+            return Ok(());
+        }
+
+        if self.peer.is_none() || self.inbox != Some(from) {
+            // this inbox does not know us (any more)
+            slog::debug!(self.log, "OutBoxReportMsg from unknown inbox"; "from" => %from);
+            self.send_init(from);
+            return Ok(());
+        }
+
+        self.heard();
+        self.set_epoch_length(msg.epoch_length_packets)
+    }
+
+    fn set_epoch_length(&mut self, epoch_length: u32) -> Result<(), SessionEnd> {
+        if epoch_length == 0 || epoch_length == self.epoch_length {
+            return Ok(());
+        }
+
+        self.epoch_length = epoch_length;
+        self.update_marks(bundler::outbox::MarkUpdate::EpochLength(epoch_length))
+    }
+
+    fn update_marks(&self, update: bundler::outbox::MarkUpdate) -> Result<(), SessionEnd> {
+        self.mark_updates.send(update).map_err(|_| SessionEnd::OutboxStopped)
+    }
+
+    fn send_init(&mut self, to: std::net::SocketAddr) {
+        if let Some(t) = self.last_init {
+            if t.elapsed() < INIT_INTERVAL {
+                return;
+            }
+        }
+
+        self.last_init = Some(std::time::Instant::now());
+        self.send_session_msg(to, false);
+    }
+
+    fn send_heartbeat(&mut self, to: std::net::SocketAddr) {
+        if let Some(t) = self.last_heartbeat {
+            if t.elapsed() < HEARTBEAT_INTERVAL {
+                return;
+            }
+        }
+
+        self.last_heartbeat = Some(std::time::Instant::now());
+        let msg = bundler::serialize::HeartbeatMsg {
+            bundle_id: self.bundle_id,
+            session_id: self.id,
+        };
+        self.send(to, msg.as_bytes());
+    }
+
+    fn send_session_msg(&self, to: std::net::SocketAddr, ack: bool) {
+        let msg = bundler::serialize::SessionMsg {
+            bundle_id: self.bundle_id,
+            ack,
+            session_id: self.id,
+            epoch_length_packets: self.epoch_length,
+            capabilities: bundler::serialize::CAPABILITIES,
+            hash_spec: self.hash_spec.bits(),
+        };
+        self.send(to, msg.as_bytes());
+    }
+
+    fn send(&self, to: std::net::SocketAddr, mut buf: Vec<u8>) {
+        if let Some(ref sealer) = self.auth {
+            sealer.seal(&mut buf);
+        }
+
+        if let Err(e) = self.sk.send_to(&buf, to) {
+            slog::warn!(self.log, "failed to send to inbox"; "to" => %to, "err" => ?e);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+enum InboxMsg {
+    Report(bundler::serialize::OutBoxReportMsg),
+    Session(bundler::serialize::SessionMsg),
+    Heartbeat(bundler::serialize::HeartbeatMsg),
+}
+
+/// Decodes messages from the inbox, dropping and counting the ones we cannot use.
+#[cfg(target_os = "linux")]
+struct ReportReader {
+    auth: Option<bundler::serialize::auth::Opener>,
+    drops: bundler::drops::DropCounter,
+}
+
+#[cfg(target_os = "linux")]
+impl ReportReader {
+    /// Messages we cannot use are dropped and counted. Only a version mismatch we can trust, as it
+    /// is authenticated or `from_peer`, our inbox's address, is an error: anyone else could send
+    /// one to make us give up.
+    fn decode(
+        &mut self,
+        log: &slog::Logger,
+        from: std::net::SocketAddr,
+        buf: &[u8],
+        from_peer: bool,
+    ) -> Result<Option<InboxMsg>, bundler::serialize::DecodeError> {
+        use bundler::serialize::auth::AuthError;
+        use bundler::serialize::{
+            DecodeError, Header, HeartbeatMsg, MsgType, OutBoxReportMsg, SessionMsg,
+        };
+
+        if let Some(ref mut opener) = self.auth {
+            if let Err(e) = opener.open(from, buf) {
+                let reason = match e {
+                    AuthError::Replayed(_) => "replayed",
+                    _ => "unauthenticated",
+                };
+                slog::debug!(log, "dropping message from inbox"; "from" => %from, "err" => %e);
+                self.drops.add(log, reason);
+                return Ok(None);
+            }
+        }
+
+        let msg = Header::from_slice(buf).and_then(|h| match h.msg_type {
+            MsgType::SessionInit | MsgType::SessionAck => {
+                SessionMsg::from_slice(buf).map(InboxMsg::Session)
+            }
+            MsgType::Heartbeat => HeartbeatMsg::from_slice(buf).map(InboxMsg::Heartbeat),
+            _ => OutBoxReportMsg::from_slice(buf).map(InboxMsg::Report),
+        });
+        match msg {
+            Ok(msg) => Ok(Some(msg)),
+            // A peer speaking another protocol version cannot be talked to.
+            Err(e @ DecodeError::VersionMismatch { .. }) if from_peer || self.auth.is_some() => Err(e),
+            Err(e) => {
+                slog::debug!(log, "malformed message from inbox"; "from" => %from, "err" => %e);
+                self.drops.add(log, "malformed");
+                Ok(None)
+            }
+        }
+    }
 }
 
 #[cfg(not(target_os = "linux"))]
@@ -1,6 +1,7 @@
-use bundler::{IP_HEADER_LENGTH, MAC_HEADER_LENGTH};
+use bundler::outbox::source::{self, Next, PacketSource};
+use bundler::MAC_HEADER_LENGTH;
 use minion::Cancellable;
-use slog::{info, debug, o, Drain};
+use slog::{info, debug, error, o, Drain};
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::mpsc;
@@ -15,6 +16,12 @@ struct Opt {
     outbox_dump_file: std::path::PathBuf,
     #[structopt(long = "with_ethernet")]
     with_ethernet: bool,
+    #[structopt(long = "bundle_id", default_value = "42")]
+    bundle_id: u32,
+    #[structopt(long = "hash_spec", default_value = "dst_port,pkt_id")]
+    hash_spec: bundler::hash::HashSpec,
+    #[structopt(long = "rate_estimator", default_value = "sum")]
+    rate_estimator: bundler::inbox::RateEstimatorKind,
 }
 
 fn make_logger() -> slog::Logger {
@@ -32,8 +39,8 @@ fn main() {
     let log = make_logger();
     let root_log = log.new(o!("node" => "script"));
 
-    let inbox_capture = pcap::Capture::from_file(opt.inbox_dump_file.clone()).unwrap();
-    let outbox_capture = pcap::Capture::from_file(opt.outbox_dump_file.clone()).unwrap();
+    let inbox_capture = source::offline(&opt.inbox_dump_file).unwrap();
+    let outbox_capture = source::offline(&opt.outbox_dump_file).unwrap();
 
     let (outbox_report_tx, epoch_length_adjust_rx) = glue();
     let (outbox_feedback_tx, outbox_feedback_rx) = crossbeam::bounded(0);
@@ -44,6 +51,8 @@ fn main() {
         inbox_capture,
         qdisc_ctl_rx,
         opt.with_ethernet,
+        opt.bundle_id,
+        mark_hasher(opt.hash_spec),
     );
 
     info!(root_log, "starting inbox playback");
@@ -55,13 +64,17 @@ fn main() {
     // signal to know when it's done constructing.
     let (s, r) = mpsc::channel::<()>();
     let log1 = log.new(o!("node" => "inbox_runtime"));
+    let bundle_id = opt.bundle_id;
+    let rate_estimator = opt.rate_estimator;
     std::thread::spawn(move || {
         let mut rt = new_inbox_runtime(
             log1,
+            bundle_id,
             qdisc_match_rx,
             outbox_feedback_rx,
             outbox_report_tx,
             qdisc_ctl_tx,
+            rate_estimator,
         )
         .unwrap();
         s.send(()).unwrap();
@@ -94,8 +107,10 @@ struct InboxCapturePlayer {
     bytes_recv: u64,
     epoch_sample_rate: u32,
     with_ethernet: bool,
-    ip_header_start: usize,
-    tcp_header_start: usize,
+    bundle_id: u32,
+    hasher: bundler::hash::MarkHasher,
+    unparsed_pkts: u64,
+    marks: bundler::hash::MarkCounts,
 }
 
 impl InboxCapturePlayer {
@@ -104,12 +119,12 @@ impl InboxCapturePlayer {
         cap: pcap::Capture<pcap::Offline>,
         qdisc_ctl_rx: mpsc::Receiver<u32>,
         with_ethernet: bool,
+        bundle_id: u32,
+        hasher: bundler::hash::MarkHasher,
     ) -> (
         Self,
         crossbeam::Receiver<bundler::serialize::QDiscFeedbackMsg>,
     ) {
-        let ip_header_start = if !with_ethernet { 0 } else { MAC_HEADER_LENGTH };
-        let tcp_header_start = ip_header_start + IP_HEADER_LENGTH;
         let (tx, rx) = crossbeam::bounded(0);
         (
             InboxCapturePlayer {
@@ -120,8 +135,10 @@ impl InboxCapturePlayer {
                 bytes_recv: 0,
                 epoch_sample_rate: 128,
                 with_ethernet,
-                ip_header_start,
-                tcp_header_start,
+                bundle_id,
+                hasher,
+                unparsed_pkts: 0,
+                marks: Default::default(),
             },
             rx,
         )
@@ -140,28 +157,40 @@ impl minion::Cancellable for InboxCapturePlayer {
             Err(mpsc::TryRecvError::Empty) => (),
             Err(mpsc::TryRecvError::Disconnected) => unreachable!(),
         }
-        match self.cap.next() {
-            Ok(pkt) => {
-                let now = pkt.header.ts;
-                let now = now.tv_sec as u64 * 1_000_000_000 + now.tv_usec as u64 * 1_000; // ns since epoch
+        match self.cap.next_packet() {
+            Ok(Next::Packet(pkt)) => {
+                let now = pkt.ts_ns;
                 let data = pkt.data;
 
-                self.bytes_recv += pkt.header.len as u64;
+                self.bytes_recv += u64::from(pkt.wire_len);
                 if !self.with_ethernet {
                     self.bytes_recv += MAC_HEADER_LENGTH as u64;
                 }
 
-                let hash =
-                    bundler::hash::hash_packet(self.ip_header_start, self.tcp_header_start, data);
+                let hdrs = match bundler::hash::parse_headers(data, self.with_ethernet) {
+                    Ok(h) => h,
+                    Err(e) => {
+                        self.unparsed_pkts += 1;
+                        debug!(self.log, "skipping packet"; "err" => %e, "unparsed_pkts" => self.unparsed_pkts);
+                        return Ok(minion::LoopState::Continue);
+                    }
+                };
+
+                let hash = match self.hasher.hash(&hdrs, data) {
+                    Some(h) => h,
+                    None => return Ok(minion::LoopState::Continue),
+                };
                 if hash % self.epoch_sample_rate == 0 {
+                    self.marks.add(hdrs.transport);
                     debug!(self.log, "inbox qdisc epoch";
-                        "ip" => ?bundler::hash::unpack_ips(data, self.ip_header_start),
-                        "ports" => ?bundler::hash::unpack_ports(data, self.tcp_header_start),
-                        "ipid" => ?&data[self.ip_header_start+4..self.ip_header_start+6],
+                        "ip" => ?bundler::hash::unpack_ips(data, &hdrs),
+                        "ports" => ?bundler::hash::unpack_ports(data, hdrs.transport_header_start),
+                        "transport" => ?hdrs.transport,
+                        "id" => ?bundler::hash::packet_id(data, &hdrs),
                         "hash" => hash,
                     );
                     let msg = bundler::serialize::QDiscFeedbackMsg {
-                        bundle_id: 42,
+                        bundle_id: self.bundle_id,
                         marked_packet_hash: hash,
                         curr_qlen: 100,
                         epoch_bytes: self.bytes_recv,
@@ -173,31 +202,44 @@ impl minion::Cancellable for InboxCapturePlayer {
 
                 Ok(minion::LoopState::Continue)
             }
+            Ok(Next::Idle) => Ok(minion::LoopState::Continue),
             _ => {
-                info!(self.log, "inbox playback done");
+                info!(self.log, "inbox playback done";
+                    "unparsed_pkts" => self.unparsed_pkts,
+                    "marks" => %self.marks,
+                );
                 Ok(minion::LoopState::Break)
             }
         }
     }
 }
 
+/// There is no handshake to agree on a key during playback, so both sides hash under a fixed one.
+fn mark_hasher(spec: bundler::hash::HashSpec) -> bundler::hash::MarkHasher {
+    let mut hasher = bundler::hash::MarkHasher::new(spec);
+    if spec.contains(bundler::hash::HashSpec::KEYED) {
+        hasher.key = Some(bundler::serialize::auth::mark_hash_key(b"playback", 0));
+    }
+
+    hasher
+}
+
 /// Communication between inbox <--> outbox
 fn glue() -> (
     mpsc::Sender<bundler::serialize::OutBoxReportMsg>,
-    mpsc::Receiver<u32>,
+    mpsc::Receiver<bundler::outbox::MarkUpdate>,
 ) {
     let (outbox_report_tx, outbox_report_rx): (
         mpsc::Sender<bundler::serialize::OutBoxReportMsg>,
         mpsc::Receiver<bundler::serialize::OutBoxReportMsg>,
     ) = mpsc::channel();
-    let (epoch_length_adjust_tx, epoch_length_adjust_rx): (mpsc::Sender<u32>, mpsc::Receiver<u32>) =
-        mpsc::channel();
+    let (epoch_length_adjust_tx, epoch_length_adjust_rx) = mpsc::channel();
 
     std::thread::spawn(move || loop {
         match outbox_report_rx.recv() {
             Ok(msg) => {
                 epoch_length_adjust_tx
-                    .send(msg.epoch_length_packets)
+                    .send(bundler::outbox::MarkUpdate::EpochLength(msg.epoch_length_packets))
                     .unwrap();
             }
             Err(_) => {
@@ -209,75 +251,89 @@ fn glue() -> (
     (outbox_report_tx, epoch_length_adjust_rx)
 }
 
-fn start_outbox<T: pcap::Activated + Send + 'static>(
+fn start_outbox<S: PacketSource + Send + 'static>(
     log: slog::Logger,
     outbox_opt: Opt,
-    outbox_capture: pcap::Capture<T>,
-    epoch_length_adjust_rx: mpsc::Receiver<u32>,
-    outbox_feedback_tx: crossbeam::Sender<bundler::serialize::OutBoxFeedbackMsg>,
+    outbox_capture: S,
+    epoch_length_adjust_rx: mpsc::Receiver<bundler::outbox::MarkUpdate>,
+    outbox_feedback_tx: crossbeam::Sender<bundler::inbox::readers::OutboxEvent>,
 ) {
     // outbox sends on tx when it sees an epoch boundary packet
-    let (epoch_boundary_tx, epoch_boundary_rx) = crossbeam::bounded::<(u64, u32, u64)>(0);
+    let (epoch_boundary_tx, epoch_boundary_rx) = crossbeam::bounded::<(u64, u32, u64, bundler::serialize::PathStats)>(0);
+    let bundle_id = outbox_opt.bundle_id;
+    // there is no session with the played back outbox, so this address is never checked
+    let outbox_addr = ([127, 0, 0, 1], 28317).into();
 
+    let mut seq = 0u32;
     std::thread::spawn(move || loop {
-        let (ts, hash, recvd) = match epoch_boundary_rx.recv() {
-            Ok((ts, hash, recvd)) => (ts, hash, recvd),
+        let (ts, hash, recvd, stats) = match epoch_boundary_rx.recv() {
+            Ok(x) => x,
             Err(_) => break,
         };
 
         let msg = bundler::serialize::OutBoxFeedbackMsg {
-            bundle_id: 42,
+            bundle_id,
+            seq,
             marked_packet_hash: hash,
             epoch_bytes: recvd,
             epoch_time: ts,
+            stats: Some(stats),
         };
+        seq = seq.wrapping_add(1);
 
-        outbox_feedback_tx.send(msg).unwrap();
+        outbox_feedback_tx
+            .send(bundler::inbox::readers::OutboxEvent::Feedback(outbox_addr, msg, 0))
+            .unwrap();
     });
 
     std::thread::spawn(move || {
         info!(log.clone(), "starting outbox playback");
-        bundler::outbox::start_outbox(
+        if let Err(e) = bundler::outbox::start_outbox(
             outbox_capture,
             epoch_boundary_tx,
             epoch_length_adjust_rx,
             128,
             !outbox_opt.with_ethernet,
+            mark_hasher(outbox_opt.hash_spec),
             log.clone(),
-        )
-        .unwrap_err();
+        ) {
+            error!(log, "outbox stopped"; "err" => %e);
+        }
+
         info!(log.clone(), "outbox done");
     });
 }
 
 fn new_inbox_runtime(
     log: slog::Logger,
+    bundle_id: u32,
     qdisc_recv: crossbeam::Receiver<bundler::serialize::QDiscFeedbackMsg>,
-    outbox_recv: crossbeam::Receiver<bundler::serialize::OutBoxFeedbackMsg>,
+    outbox_recv: crossbeam::Receiver<bundler::inbox::readers::OutboxEvent>,
     outbox_report: mpsc::Sender<bundler::serialize::OutBoxReportMsg>,
     qdisc_ctl: mpsc::Sender<u32>,
+    rate_estimator: bundler::inbox::RateEstimatorKind,
 ) -> Option<bundler::inbox::Runtime<FakeInboxQdisc>> {
     let qdisc: FakeInboxQdisc = FakeInboxQdisc {
+        bundle_id,
         cwnd_bytes: 0,
         rate_bytes_per_sec: 0,
         observed_sending_bytes_per_sec: 0,
-        rtt_ns: 0,
-        min_rtt_ns: 0,
+        rtt: Default::default(),
         curr_epoch_length: 0,
         outbox_report,
         qdisc_ctl,
     };
     let qdisc = Rc::new(RefCell::new(qdisc));
-    bundler::inbox::Runtime::with_qdisc(qdisc, qdisc_recv, outbox_recv, log)
+    bundler::inbox::Runtime::with_qdiscs(vec![(bundle_id, qdisc, None, rate_estimator)], qdisc_recv, outbox_recv, log)
 }
 
 /// Does nothing - the actual "qdisc" functionality is based on the pcap trace
 struct FakeInboxQdisc {
+    bundle_id: u32,
     cwnd_bytes: u32,
     rate_bytes_per_sec: u32,
     observed_sending_bytes_per_sec: u64,
-    rtt_ns: u64,
-    min_rtt_ns: u64,
+    rtt: bundler::inbox::datapath::RttEstimate,
     curr_epoch_length: u32,
     outbox_report: mpsc::Sender<bundler::serialize::OutBoxReportMsg>,
     qdisc_ctl: mpsc::Sender<u32>,
@@ -294,9 +350,8 @@ impl bundler::inbox::datapath::Datapath for FakeInboxQdisc {
         Ok(())
     }
 
-    fn update_rtt(&mut self, rtt_ns: u64) -> Result<(), ()> {
-        self.rtt_ns = rtt_ns;
-        self.min_rtt_ns = std::cmp::min(self.min_rtt_ns, rtt_ns);
+    fn update_rtt(&mut self, rtt: bundler::inbox::datapath::RttEstimate) -> Result<(), ()> {
+        self.rtt = rtt;
         Ok(())
     }
 
@@ -312,7 +367,7 @@ impl bundler::inbox::datapath::Datapath for FakeInboxQdisc {
         self.curr_epoch_length = epoch_length_packets;
         // tell the outbox what the epoch length is
         let msg = bundler::serialize::OutBoxReportMsg {
-            bundle_id: 42,
+            bundle_id: self.bundle_id,
             epoch_length_packets,
         };
 
@@ -323,11 +378,12 @@ impl bundler::inbox::datapath::Datapath for FakeInboxQdisc {
         Ok(())
     }
 
-    fn update_send_rate(&mut self, observed_sending_bytes_per_sec: u64) {
+    fn update_send_rate(&mut self, observed_sending_bytes_per_sec: u64, mean_packet_bytes: u32) {
         self.observed_sending_bytes_per_sec = observed_sending_bytes_per_sec;
         let epoch_length = bundler::inbox::datapath::get_epoch_length(
             self.observed_sending_bytes_per_sec as f64,
-            self.min_rtt_ns as f64 / 1e9,
+            self.rtt.min_rtt_ns as f64 / 1e9,
+            mean_packet_bytes,
         );
         self.set_epoch_length(epoch_length).unwrap_or_else(|_| ());
     }
@@ -335,4 +391,19 @@ impl bundler::inbox::datapath::Datapath for FakeInboxQdisc {
     fn get_curr_epoch_length(&self) -> u32 {
         self.curr_epoch_length
     }
+
+    fn get_mtu(&self) -> u32 {
+        // the traces do not say, so assume Ethernet
+        1500
+    }
+
+    fn get_curr_rate(&self) -> Option<u32> {
+        Some(self.rate_bytes_per_sec)
+    }
+
+    fn remove_shaping(&mut self) -> Result<(), ()> {
+        self.rate_bytes_per_sec = 0;
+        self.cwnd_bytes = 0;
+        Ok(())
+    }
 }
@@ -0,0 +1,110 @@
+//! Counting the messages we drop, without a log line for each.
+
+use slog::warn;
+use std::time::{Duration, Instant};
+
+/// Report drops at most this often.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counts messages dropped for each reason, e.g. "malformed" or "replayed".
+///
+/// Anyone can send us junk, so callers log each drop at debug only. The counts are logged at warn
+/// on the first drop and then at most every `REPORT_INTERVAL`, so a flood of junk cannot flood the
+/// log.
+pub struct DropCounter {
+    // what we drop, e.g. "message from outbox"
+    what: &'static str,
+    counts: Vec<(&'static str, u64)>,
+    // drops since the last report
+    unreported: u64,
+    last_report: Option<Instant>,
+}
+
+impl DropCounter {
+    pub fn new(what: &'static str) -> Self {
+        DropCounter {
+            what,
+            counts: vec![],
+            unreported: 0,
+            last_report: None,
+        }
+    }
+
+    /// How many messages were dropped for `reason`.
+    pub fn get(&self, reason: &str) -> u64 {
+        self.counts
+            .iter()
+            .find(|&&(r, _)| r == reason)
+            .map(|&(_, n)| n)
+            .unwrap_or(0)
+    }
+
+    /// Count a message dropped for `reason`. Returns whether the counts were reported.
+    pub fn add(&mut self, log: &slog::Logger, reason: &'static str) -> bool {
+        self.add_at(log, reason, Instant::now())
+    }
+
+    fn add_at(&mut self, log: &slog::Logger, reason: &'static str, now: Instant) -> bool {
+        match self.counts.iter_mut().find(|&&mut (r, _)| r == reason) {
+            Some(c) => c.1 += 1,
+            None => self.counts.push((reason, 1)),
+        }
+        self.unreported += 1;
+
+        if let Some(t) = self.last_report {
+            if now.duration_since(t) < REPORT_INTERVAL {
+                return false;
+            }
+        }
+
+        warn!(log, "dropping messages";
+            "what" => self.what,
+            "dropped" => self.unreported,
+            "total" => %self,
+        );
+        self.unreported = 0;
+        self.last_report = Some(now);
+        true
+    }
+}
+
+impl std::fmt::Display for DropCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, &(reason, n)) in self.counts.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+
+            write!(f, "{}={}", reason, n)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DropCounter, REPORT_INTERVAL};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn check_drop_counter() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut d = DropCounter::new("message from outbox");
+        let t = Instant::now();
+
+        // the first drop is reported right away, the rest of a burst only once the interval is up
+        assert!(d.add_at(&log, "malformed", t));
+        for i in 0..100 {
+            assert!(!d.add_at(&log, "unauthenticated", t + Duration::from_millis(i)));
+        }
+        assert_eq!(d.unreported, 100);
+        assert!(d.add_at(&log, "malformed", t + REPORT_INTERVAL));
+        assert_eq!(d.unreported, 0);
+
+        assert_eq!(d.get("malformed"), 2);
+        assert_eq!(d.get("unauthenticated"), 100);
+        assert_eq!(d.get("replayed"), 0);
+        assert_eq!(d.to_string(), "malformed=2 unauthenticated=100");
+    }
+}
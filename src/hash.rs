@@ -1,5 +1,361 @@
-/// Take the FNV hash of the packet for epoch boundary identification.
-/// Use (dst ip, dst port, IP ID) to identify packets.
+use bytes::{BigEndian, ByteOrder, LittleEndian};
+use siphasher::sip::SipHasher24;
+use std::hash::Hasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Key for keyed mark hashing; see `MarkHasher`.
+pub type HashKey = [u8; 16];
+
+pub const IPV6_HEADER_LENGTH: usize = 40;
+const NEXT_HEADER_IN_IPV6_HEADER: usize = 6;
+// IPv6 extension headers, as the kernel's ipv6_ext_hdr() knows them
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_AUTH: u8 = 51;
+const IPV6_NO_NEXT_HEADER: u8 = 59;
+const IPV6_DEST_OPTS: u8 = 60;
+/// Bytes of the transport header we read whatever the protocol: enough for the ports and the TCP
+/// sequence number, or for an ICMP header.
+const TRANSPORT_BYTES_NEEDED: usize = 8;
+/// Bytes of a UDP payload we hash. A QUIC short header is at most 25 bytes (flags, a connection
+/// ID of up to 20 bytes and a packet number of up to 4), so this always reaches into the packet
+/// number or the encrypted payload, which differ from packet to packet.
+pub const UDP_PAYLOAD_HASHED: usize = 32;
+const UDP_HEADER_LENGTH: usize = 8;
+const QUIC_PORT: u16 = 443;
+/// Set in the first byte of every QUIC packet (RFC 9000, section 17).
+const QUIC_FIXED_BIT: u8 = 0x40;
+/// An 802.1Q tag: the tag protocol identifier, then 2 bytes of tag control information.
+const VLAN_TAG_LENGTH: usize = 4;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+// used for QinQ before 802.1ad
+const ETHERTYPE_QINQ_OLD: u16 = 0x9100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+/// The kinds of traffic we hash differently.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+    /// UDP to or from port 443 which looks like QUIC. Hashed like any UDP.
+    Quic,
+    /// ICMP or ICMPv6.
+    Icmp,
+    Other,
+}
+
+impl Transport {
+    fn classify(protocol: u8, transport: &[u8]) -> Self {
+        match protocol {
+            crate::IP_PROTO_TCP => Transport::Tcp,
+            crate::IP_PROTO_UDP => {
+                let (sport, dport) = unpack_ports(transport, 0);
+                let quic = (sport == QUIC_PORT || dport == QUIC_PORT)
+                    && transport.len() > UDP_HEADER_LENGTH
+                    && transport[UDP_HEADER_LENGTH] & QUIC_FIXED_BIT != 0;
+                if quic {
+                    Transport::Quic
+                } else {
+                    Transport::Udp
+                }
+            }
+            crate::IP_PROTO_ICMP | crate::IP_PROTO_ICMPV6 => Transport::Icmp,
+            _ => Transport::Other,
+        }
+    }
+}
+
+/// Which fields of a packet its mark hash covers. The outbox and the inbox's qdisc must agree on
+/// it, or their marks will not match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HashSpec(u32);
+
+impl HashSpec {
+    pub const SRC_IP: HashSpec = HashSpec(0x01);
+    pub const DST_IP: HashSpec = HashSpec(0x02);
+    /// TCP and UDP only.
+    pub const SRC_PORT: HashSpec = HashSpec(0x04);
+    /// TCP and UDP only.
+    pub const DST_PORT: HashSpec = HashSpec(0x08);
+    /// IPv4 only.
+    pub const IP_ID: HashSpec = HashSpec(0x10);
+    /// TCP only.
+    pub const TCP_SEQ: HashSpec = HashSpec(0x20);
+    /// Whichever field best tells packets of a flow apart for their protocol; see `packet_id`.
+    pub const PKT_ID: HashSpec = HashSpec(0x40);
+    /// Not a field: hash with SipHash under a key shared by inbox and outbox, rather than FNV.
+    pub const KEYED: HashSpec = HashSpec(0x80);
+
+    const NAMES: [(HashSpec, &'static str); 8] = [
+        (HashSpec::SRC_IP, "src_ip"),
+        (HashSpec::DST_IP, "dst_ip"),
+        (HashSpec::SRC_PORT, "src_port"),
+        (HashSpec::DST_PORT, "dst_port"),
+        (HashSpec::IP_ID, "ip_id"),
+        (HashSpec::TCP_SEQ, "tcp_seq"),
+        (HashSpec::PKT_ID, "pkt_id"),
+        (HashSpec::KEYED, "keyed"),
+    ];
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// None if no field or an unknown field is set.
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        let known = HashSpec::NAMES.iter().fold(0, |acc, (f, _)| acc | f.0);
+        if bits & !HashSpec::KEYED.0 == 0 || bits & !known != 0 {
+            None
+        } else {
+            Some(HashSpec(bits))
+        }
+    }
+
+    /// `bits` as field names, or in hex if they are not a valid spec.
+    pub fn describe(bits: u32) -> String {
+        match HashSpec::from_bits(bits) {
+            Some(spec) => spec.to_string(),
+            None => format!("{:#x}", bits),
+        }
+    }
+
+    pub fn contains(self, field: HashSpec) -> bool {
+        self.0 & field.0 == field.0
+    }
+}
+
+impl std::ops::BitOr for HashSpec {
+    type Output = HashSpec;
+    fn bitor(self, other: HashSpec) -> HashSpec {
+        HashSpec(self.0 | other.0)
+    }
+}
+
+impl Default for HashSpec {
+    fn default() -> Self {
+        HashSpec::DST_PORT | HashSpec::PKT_ID
+    }
+}
+
+/// Field names separated by commas, e.g. `dst_port,tcp_seq`.
+impl std::str::FromStr for HashSpec {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bits = 0;
+        for name in s.split(',').map(str::trim) {
+            match HashSpec::NAMES.iter().find(|(_, n)| *n == name) {
+                Some((f, _)) => bits |= f.0,
+                None => return Err(format!("unknown hash field {:?}", name)),
+            }
+        }
+
+        HashSpec::from_bits(bits).ok_or_else(|| String::from("hash spec names no fields"))
+    }
+}
+
+impl std::fmt::Display for HashSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names: Vec<_> = HashSpec::NAMES
+            .iter()
+            .filter(|(field, _)| self.contains(*field))
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// How many marked packets there were of each kind.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MarkCounts {
+    pub tcp: u64,
+    pub udp: u64,
+    pub quic: u64,
+    pub icmp: u64,
+    pub other: u64,
+}
+
+impl MarkCounts {
+    pub fn add(&mut self, transport: Transport) {
+        match transport {
+            Transport::Tcp => self.tcp += 1,
+            Transport::Udp => self.udp += 1,
+            Transport::Quic => self.quic += 1,
+            Transport::Icmp => self.icmp += 1,
+            Transport::Other => self.other += 1,
+        }
+    }
+}
+
+impl std::fmt::Display for MarkCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "tcp={} udp={} quic={} icmp={} other={}",
+            self.tcp, self.udp, self.quic, self.icmp, self.other
+        )
+    }
+}
+
+/// Where the headers of a captured packet are.
+#[derive(Clone, Copy, Debug)]
+pub struct Headers {
+    pub version: IpVersion,
+    pub ip_header_start: usize,
+    pub transport_header_start: usize,
+    pub protocol: u8,
+    pub transport: Transport,
+}
+
+/// Why a captured packet could not be parsed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParseError {
+    /// The capture ends before the bytes we hash.
+    Truncated,
+    /// Neither IPv4 nor IPv6.
+    NotIp,
+    /// The IPv4 header length (IHL) is shorter than the fixed header.
+    BadHeaderLength,
+    /// An IPv6 header chain ending in No Next Header.
+    NoTransport,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::Truncated => write!(f, "packet truncated"),
+            ParseError::NotIp => write!(f, "not an IP packet"),
+            ParseError::BadHeaderLength => write!(f, "bad IPv4 header length"),
+            ParseError::NoTransport => write!(f, "no transport header"),
+        }
+    }
+}
+
+/// Find the IP and transport headers of a captured packet. If `ethernet` is set, the packet
+/// starts with an Ethernet header, possibly followed by 802.1Q or QinQ tags; otherwise it starts
+/// with the IP header.
+///
+/// IPv6 extension headers are skipped as `skip_ipv6_ext_headers` does.
+pub fn parse_headers(pkt: &[u8], ethernet: bool) -> Result<Headers, ParseError> {
+    let (ip_header_start, ethertype) = if ethernet {
+        let (start, ethertype) = skip_link_header(pkt)?;
+        (start, Some(ethertype))
+    } else {
+        (0, None)
+    };
+
+    let version = match (ethertype, pkt.get(ip_header_start).ok_or(ParseError::Truncated)? >> 4) {
+        (None, 4) | (Some(ETHERTYPE_IPV4), 4) => IpVersion::V4,
+        (None, 6) | (Some(ETHERTYPE_IPV6), 6) => IpVersion::V6,
+        _ => return Err(ParseError::NotIp),
+    };
+
+    let (transport_header_start, protocol) = match version {
+        IpVersion::V4 => {
+            let ihl = (pkt[ip_header_start] & 0x0f) as usize * 4;
+            if ihl < crate::IP_HEADER_LENGTH {
+                return Err(ParseError::BadHeaderLength);
+            }
+
+            let protocol = *pkt.get(ip_header_start + crate::PROTO_IN_IP_HEADER).ok_or(ParseError::Truncated)?;
+            (ip_header_start + ihl, protocol)
+        }
+        IpVersion::V6 => {
+            let next = *pkt.get(ip_header_start + NEXT_HEADER_IN_IPV6_HEADER).ok_or(ParseError::Truncated)?;
+            skip_ipv6_ext_headers(pkt, ip_header_start + IPV6_HEADER_LENGTH, next)?
+        }
+    };
+
+    if pkt.len() < transport_header_start + TRANSPORT_BYTES_NEEDED {
+        return Err(ParseError::Truncated);
+    }
+
+    let transport = &pkt[transport_header_start..];
+    let hdrs = Headers {
+        version,
+        ip_header_start,
+        transport_header_start,
+        protocol,
+        transport: Transport::classify(protocol, transport),
+    };
+
+    if let Transport::Udp | Transport::Quic = hdrs.transport {
+        if transport.len() < UDP_HEADER_LENGTH + udp_payload_hashed(transport) {
+            return Err(ParseError::Truncated);
+        }
+    }
+
+    Ok(hdrs)
+}
+
+/// Skip the IPv6 extension headers starting at `start`, the first of type `next`, as the kernel's
+/// `ipv6_skip_exthdr` does for the qdisc: hop-by-hop, routing and destination options headers, AH,
+/// and the fragment header of a first fragment. A later fragment has no transport header, so we
+/// stop at its fragment header.
+/// Returns where the transport header starts, and its protocol.
+fn skip_ipv6_ext_headers(pkt: &[u8], mut start: usize, mut next: u8) -> Result<(usize, u8), ParseError> {
+    loop {
+        match next {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_FRAGMENT | IPV6_AUTH | IPV6_DEST_OPTS => (),
+            IPV6_NO_NEXT_HEADER => return Err(ParseError::NoTransport),
+            _ => return Ok((start, next)),
+        }
+
+        let hdr = pkt.get(start..start + 2).ok_or(ParseError::Truncated)?;
+        let len = match next {
+            IPV6_FRAGMENT => {
+                let frag_off = pkt.get(start + 2..start + 4).ok_or(ParseError::Truncated)?;
+                if BigEndian::read_u16(frag_off) & !0x7 != 0 {
+                    return Ok((start, next));
+                }
+
+                8
+            }
+            IPV6_AUTH => (hdr[1] as usize + 2) * 4,
+            _ => (hdr[1] as usize + 1) * 8,
+        };
+
+        next = hdr[0];
+        start += len;
+    }
+}
+
+/// How much of a UDP datagram's payload we hash. Going by the UDP length rather than by how much
+/// was captured means the qdisc, which sees the whole datagram, hashes the same bytes.
+fn udp_payload_hashed(udp: &[u8]) -> usize {
+    let len = BigEndian::read_u16(&udp[4..6]) as usize;
+    len.saturating_sub(UDP_HEADER_LENGTH).min(UDP_PAYLOAD_HASHED)
+}
+
+/// Skip the Ethernet header and any VLAN tags.
+/// Returns where the network header starts, and its ethertype.
+fn skip_link_header(pkt: &[u8]) -> Result<(usize, u16), ParseError> {
+    // the ethertype is in the last 2 bytes of the Ethernet header, and of each VLAN tag
+    let mut start = crate::MAC_HEADER_LENGTH;
+    loop {
+        if pkt.len() < start {
+            return Err(ParseError::Truncated);
+        }
+
+        match BigEndian::read_u16(&pkt[start - 2..start]) {
+            ETHERTYPE_VLAN | ETHERTYPE_QINQ | ETHERTYPE_QINQ_OLD => start += VLAN_TAG_LENGTH,
+            ethertype => return Ok((start, ethertype)),
+        }
+    }
+}
+
+/// Take the FNV hash of the packet for epoch boundary identification, over the fields `spec`
+/// picks, in the order `HashSpec` lists them. Fields a packet does not have are skipped.
+/// This ignores `HashSpec::KEYED`; `MarkHasher` honors it.
 ///
 /// UDP Header
 ///
@@ -52,39 +408,337 @@
 ///   |                    Options                    |    Padding    |
 ///   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 ///
-/// Pseudo-header for packet hashing:
+/// Pseudo-header for packet hashing with the default spec, for TCP:
 ///    0                   1                   2                   3
 ///    0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
 ///   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-///   |           Destination Address (ipv4[16:20])                   |
-///   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 ///   | Destination Port (tcp[2:4])   |  Identification (ipv4[4:6])   |
 ///   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-pub fn hash_packet(ip_header_start: usize, tcp_header_start: usize, pkt: &[u8]) -> u32 {
-    use std::hash::Hasher;
+///
+/// IPv6 has no per-packet IP ID, and the flow label is the same for every packet of a flow, so
+/// the TCP sequence number stands in for it:
+///    0                   1                   2                   3
+///    0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+///   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///   | Destination Port (tcp[2:4])   |  Sequence Number (tcp[4:8])   |
+///   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+                               +
+///   |                               |
+///   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///
+/// This must match `hash_packet` in qdisc/sch_bundle_inbox.c.
+pub fn hash_packet(spec: HashSpec, hdrs: &Headers, pkt: &[u8]) -> u32 {
     let mut h = fnv::FnvHasher::default();
-    //let dst_ip = &pkt[ip_header_start + 16..ip_header_start + 20];
-    //let src_port = &pkt[tcp_header_start..tcp_header_start + 2];
-    let dst_port = &pkt[tcp_header_start + 2..tcp_header_start + 4];
-    let ipid = &pkt[ip_header_start + 4..ip_header_start + 6];
-    //h.write(dst_ip);
-    //h.write(src_port);
-    h.write(dst_port);
-    h.write(ipid);
+    write_fields(&mut h, spec, hdrs, pkt);
     h.finish() as u32
 }
 
-pub fn unpack_ips(pkt: &[u8], ip_header_start: usize) -> (std::net::Ipv4Addr, std::net::Ipv4Addr) {
-    (
-        std::net::Ipv4Addr::from(*arrayref::array_ref!(pkt, ip_header_start + 12, 4)),
-        std::net::Ipv4Addr::from(*arrayref::array_ref!(pkt, ip_header_start + 16, 4)),
-    )
+fn write_fields<H: Hasher>(h: &mut H, spec: HashSpec, hdrs: &Headers, pkt: &[u8]) {
+    let ip = &pkt[hdrs.ip_header_start..];
+    let t = &pkt[hdrs.transport_header_start..];
+    let (src_ip, dst_ip) = match hdrs.version {
+        IpVersion::V4 => (&ip[12..16], &ip[16..20]),
+        IpVersion::V6 => (&ip[8..24], &ip[24..40]),
+    };
+    let has_ports = match hdrs.transport {
+        Transport::Tcp | Transport::Udp | Transport::Quic => true,
+        Transport::Icmp | Transport::Other => false,
+    };
+
+    if spec.contains(HashSpec::SRC_IP) {
+        h.write(src_ip);
+    }
+    if spec.contains(HashSpec::DST_IP) {
+        h.write(dst_ip);
+    }
+    if spec.contains(HashSpec::SRC_PORT) && has_ports {
+        h.write(&t[0..2]);
+    }
+    if spec.contains(HashSpec::DST_PORT) && has_ports {
+        h.write(&t[2..4]);
+    }
+    if spec.contains(HashSpec::IP_ID) && hdrs.version == IpVersion::V4 {
+        h.write(&ip[4..6]);
+    }
+    if spec.contains(HashSpec::TCP_SEQ) && hdrs.transport == Transport::Tcp {
+        h.write(&t[4..8]);
+    }
+    if spec.contains(HashSpec::PKT_ID) {
+        let (first, second) = packet_id(pkt, hdrs);
+        h.write(first);
+        h.write(second);
+    }
+}
+
+/// Computes mark hashes: over the fields of `spec`, and if it is keyed, with SipHash-2-4 under
+/// `key` rather than with FNV. FNV is fast, but an endpoint in the bundle can pick IP IDs and
+/// ports whose hash it knows, and so choose which of its packets are marks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MarkHasher {
+    pub spec: HashSpec,
+    pub key: Option<HashKey>,
+}
+
+impl MarkHasher {
+    pub fn new(spec: HashSpec) -> Self {
+        MarkHasher { spec, key: None }
+    }
+
+    /// None if the spec is keyed, but we have no key yet.
+    pub fn hash(&self, hdrs: &Headers, pkt: &[u8]) -> Option<u32> {
+        if !self.spec.contains(HashSpec::KEYED) {
+            return Some(hash_packet(self.spec, hdrs, pkt));
+        }
+
+        let key = self.key?;
+        let mut h = SipHasher24::new_with_keys(
+            LittleEndian::read_u64(&key[0..8]),
+            LittleEndian::read_u64(&key[8..16]),
+        );
+        write_fields(&mut h, self.spec, hdrs, pkt);
+        Some(h.finish() as u32)
+    }
+}
+
+/// The fields which tell a packet apart from others of its flow, in order:
+///
+/// - TCP: the IPv4 ID or, for IPv6, the sequence number.
+/// - UDP and QUIC: the first `UDP_PAYLOAD_HASHED` bytes of the payload. Many QUIC stacks send
+///   with IP ID 0, and QUIC packet numbers are encrypted, so the payload is the only per-packet
+///   field.
+/// - ICMP: the first 8 bytes of the ICMP header, which hold the type, code and checksum, and for
+///   echo messages the identifier and sequence number.
+/// - Anything else: the first 8 bytes of the transport header, then the IPv4 ID if there is one.
+pub fn packet_id<'a>(pkt: &'a [u8], hdrs: &Headers) -> (&'a [u8], &'a [u8]) {
+    let ip = &pkt[hdrs.ip_header_start..];
+    let t = &pkt[hdrs.transport_header_start..];
+    match (hdrs.transport, hdrs.version) {
+        (Transport::Tcp, IpVersion::V4) => (&ip[4..6], &[]),
+        (Transport::Tcp, IpVersion::V6) => (&t[4..8], &[]),
+        (Transport::Udp, _) | (Transport::Quic, _) => {
+            (&t[UDP_HEADER_LENGTH..UDP_HEADER_LENGTH + udp_payload_hashed(t)], &[])
+        }
+        (Transport::Other, IpVersion::V4) => (&t[0..8], &ip[4..6]),
+        (Transport::Icmp, _) | (Transport::Other, IpVersion::V6) => (&t[0..8], &[]),
+    }
+}
+
+pub fn unpack_ips(pkt: &[u8], hdrs: &Headers) -> (IpAddr, IpAddr) {
+    let ip = &pkt[hdrs.ip_header_start..];
+    match hdrs.version {
+        IpVersion::V4 => (
+            Ipv4Addr::from(*arrayref::array_ref!(ip, 12, 4)).into(),
+            Ipv4Addr::from(*arrayref::array_ref!(ip, 16, 4)).into(),
+        ),
+        IpVersion::V6 => (
+            Ipv6Addr::from(*arrayref::array_ref!(ip, 8, 16)).into(),
+            Ipv6Addr::from(*arrayref::array_ref!(ip, 24, 16)).into(),
+        ),
+    }
 }
 
 pub fn unpack_ports(pkt: &[u8], tcp_header_start: usize) -> (u16, u16) {
-    use bytes::{BigEndian, ByteOrder};
     (
         BigEndian::read_u16(&pkt[tcp_header_start..tcp_header_start + 2]),
         BigEndian::read_u16(&pkt[tcp_header_start + 2..tcp_header_start + 4]),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        hash_packet, packet_id, parse_headers, unpack_ips, HashSpec, IpVersion, MarkHasher, ParseError, Transport,
+    };
+
+    #[test]
+    fn check_parse_headers() {
+        let mut v4 = vec![0u8; 40];
+        v4[0] = 0x45;
+        v4[4..6].copy_from_slice(&[0x12, 0x34]);
+        v4[9] = crate::IP_PROTO_TCP;
+        v4[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        v4[22..24].copy_from_slice(&[0, 80]);
+        let h = parse_headers(&v4, false).unwrap();
+        assert_eq!((h.version, h.transport_header_start, h.protocol), (IpVersion::V4, 20, crate::IP_PROTO_TCP));
+        assert_eq!(unpack_ips(&v4, &h).1, std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+        let mut v6 = vec![0u8; 60];
+        v6[0] = 0x60;
+        v6[6] = crate::IP_PROTO_TCP;
+        v6[39] = 1;
+        v6[42..44].copy_from_slice(&[0, 80]);
+        v6[44..48].copy_from_slice(&[0, 0, 0x12, 0x34]);
+        let h6 = parse_headers(&v6, false).unwrap();
+        assert_eq!((h6.version, h6.transport_header_start, h6.protocol), (IpVersion::V6, 40, crate::IP_PROTO_TCP));
+        assert_eq!(unpack_ips(&v6, &h6).1, std::net::Ipv6Addr::LOCALHOST);
+
+        // the IPv6 hash covers the sequence number, not the fixed header
+        let before = hash_packet(HashSpec::default(), &h6, &v6);
+        v6[1] = 0xff;
+        assert_eq!(hash_packet(HashSpec::default(), &h6, &v6), before);
+        v6[47] = 0x35;
+        assert_ne!(hash_packet(HashSpec::default(), &h6, &v6), before);
+
+        assert_eq!(parse_headers(&v6[..47], false).unwrap_err(), ParseError::Truncated);
+        assert_eq!(parse_headers(&[0u8; 60], false).unwrap_err(), ParseError::NotIp);
+    }
+
+    #[test]
+    fn check_parse_vlan_and_options() {
+        // Ethernet, then QinQ and 802.1Q tags, then IPv4 with 8 bytes of options
+        let mut pkt = vec![0u8; 14 + 8 + 28 + 20];
+        pkt[12..14].copy_from_slice(&[0x88, 0xa8]);
+        pkt[16..18].copy_from_slice(&[0x81, 0x00]);
+        pkt[20..22].copy_from_slice(&[0x08, 0x00]);
+        pkt[22] = 0x47;
+        pkt[22 + 9] = crate::IP_PROTO_TCP;
+        let h = parse_headers(&pkt, true).unwrap();
+        assert_eq!((h.ip_header_start, h.transport_header_start), (22, 50));
+        assert_eq!(h.protocol, crate::IP_PROTO_TCP);
+
+        // the ethertype and the IP version disagree
+        pkt[20..22].copy_from_slice(&[0x86, 0xdd]);
+        assert_eq!(parse_headers(&pkt, true).unwrap_err(), ParseError::NotIp);
+
+        pkt[20..22].copy_from_slice(&[0x08, 0x00]);
+        pkt[22] = 0x44;
+        assert_eq!(parse_headers(&pkt, true).unwrap_err(), ParseError::BadHeaderLength);
+
+        pkt[22] = 0x4f;
+        assert_eq!(parse_headers(&pkt, true).unwrap_err(), ParseError::Truncated);
+        assert_eq!(parse_headers(&pkt[..16], true).unwrap_err(), ParseError::Truncated);
+    }
+
+    #[test]
+    fn check_hash_transports() {
+        // IPv4 UDP to port 443, carrying a QUIC short header packet with 40 bytes of payload
+        let mut pkt = vec![0u8; 20 + 8 + 40];
+        pkt[0] = 0x45;
+        pkt[9] = crate::IP_PROTO_UDP;
+        pkt[22..24].copy_from_slice(&443u16.to_be_bytes());
+        pkt[24..26].copy_from_slice(&48u16.to_be_bytes());
+        pkt[28] = 0x40;
+        let h = parse_headers(&pkt, false).unwrap();
+        assert_eq!(h.transport, Transport::Quic);
+        assert_eq!(packet_id(&pkt, &h).0, &pkt[28..60]);
+
+        // the IP ID is not hashed, the payload is, up to 32 bytes of it
+        let before = hash_packet(HashSpec::default(), &h, &pkt);
+        pkt[4] = 1;
+        pkt[60] = 1;
+        assert_eq!(hash_packet(HashSpec::default(), &h, &pkt), before);
+        pkt[59] = 1;
+        assert_ne!(hash_packet(HashSpec::default(), &h, &pkt), before);
+
+        // the capture must hold the hashed payload
+        assert_eq!(parse_headers(&pkt[..50], false).unwrap_err(), ParseError::Truncated);
+
+        // a short datagram to another port
+        pkt[22..24].copy_from_slice(&53u16.to_be_bytes());
+        pkt[24..26].copy_from_slice(&12u16.to_be_bytes());
+        let h = parse_headers(&pkt[..32], false).unwrap();
+        assert_eq!(h.transport, Transport::Udp);
+        assert_eq!(packet_id(&pkt, &h).0, &pkt[28..32]);
+
+        // ICMP echo: the identifier and sequence number
+        pkt[9] = crate::IP_PROTO_ICMP;
+        let h = parse_headers(&pkt, false).unwrap();
+        assert_eq!(h.transport, Transport::Icmp);
+        assert_eq!(packet_id(&pkt, &h), (&pkt[20..28], &[][..]));
+
+        pkt[9] = 47;
+        let h = parse_headers(&pkt, false).unwrap();
+        assert_eq!(h.transport, Transport::Other);
+        assert_eq!(packet_id(&pkt, &h), (&pkt[20..28], &pkt[4..6]));
+    }
+
+    #[test]
+    fn check_hash_spec() {
+        let spec: HashSpec = "dst_port, tcp_seq".parse().unwrap();
+        assert_eq!(spec, HashSpec::DST_PORT | HashSpec::TCP_SEQ);
+        assert_eq!(spec.to_string(), "dst_port,tcp_seq");
+        assert_eq!(HashSpec::default().to_string().parse(), Ok(HashSpec::default()));
+        assert!("dst_port,flow_label".parse::<HashSpec>().is_err());
+        assert_eq!(HashSpec::from_bits(0), None);
+        assert_eq!(HashSpec::from_bits(0x100), None);
+        assert!("keyed".parse::<HashSpec>().is_err());
+
+        // IPv4 TCP with IP ID 0: the default spec only tells packets apart by IP ID
+        let mut pkt = vec![0u8; 40];
+        pkt[0] = 0x45;
+        pkt[9] = crate::IP_PROTO_TCP;
+        let h = parse_headers(&pkt, false).unwrap();
+        let before = (hash_packet(HashSpec::default(), &h, &pkt), hash_packet(spec, &h, &pkt));
+        pkt[27] = 1;
+        assert_eq!(hash_packet(HashSpec::default(), &h, &pkt), before.0);
+        assert_ne!(hash_packet(spec, &h, &pkt), before.1);
+
+        // fields the packet does not have are skipped
+        pkt[9] = crate::IP_PROTO_ICMP;
+        let h = parse_headers(&pkt, false).unwrap();
+        assert_eq!(hash_packet(HashSpec::PKT_ID, &h, &pkt), hash_packet(HashSpec::default(), &h, &pkt));
+    }
+
+    /// The qdisc's `hash_packet` comment points here: both must skip the same extension headers.
+    #[test]
+    fn check_ipv6_ext_headers() {
+        // IPv6, an 8-byte hop-by-hop header, the fragment header of a first fragment, then TCP
+        let mut pkt = vec![0u8; 40 + 8 + 8 + 20];
+        pkt[0] = 0x60;
+        pkt[6] = 0;
+        pkt[23] = 1;
+        pkt[39] = 2;
+        pkt[40] = 44;
+        pkt[48] = crate::IP_PROTO_TCP;
+        pkt[50..52].copy_from_slice(&[0x00, 0x01]);
+        pkt[56..58].copy_from_slice(&[0x9c, 0x40]);
+        pkt[58..60].copy_from_slice(&[0x01, 0xbb]);
+        pkt[60..64].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        let h = parse_headers(&pkt, false).unwrap();
+        assert_eq!((h.transport_header_start, h.protocol), (56, crate::IP_PROTO_TCP));
+        assert_eq!(packet_id(&pkt, &h).0, &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(hash_packet(HashSpec::default(), &h, &pkt), 3155913091);
+        let five_tuple: HashSpec = "src_ip,dst_ip,src_port,dst_port,pkt_id".parse().unwrap();
+        assert_eq!(hash_packet(five_tuple, &h, &pkt), 1307314882);
+
+        // a later fragment: the hash starts at its fragment header
+        pkt[50..52].copy_from_slice(&[0x05, 0xb9]);
+        let h = parse_headers(&pkt, false).unwrap();
+        assert_eq!((h.transport_header_start, h.protocol), (48, 44));
+        assert_eq!(h.transport, Transport::Other);
+
+        // nothing after the hop-by-hop header
+        pkt[40] = 59;
+        assert_eq!(parse_headers(&pkt, false).unwrap_err(), ParseError::NoTransport);
+        pkt[40] = 60;
+        pkt[49] = 100;
+        assert_eq!(parse_headers(&pkt, false).unwrap_err(), ParseError::Truncated);
+    }
+
+    #[test]
+    fn check_keyed_hash() {
+        let mut pkt = vec![0u8; 40];
+        pkt[0] = 0x45;
+        pkt[9] = crate::IP_PROTO_TCP;
+        let h = parse_headers(&pkt, false).unwrap();
+
+        let spec = HashSpec::default() | HashSpec::KEYED;
+        let mut hasher = MarkHasher::new(spec);
+        assert_eq!(hasher.hash(&h, &pkt), None);
+        assert_eq!(MarkHasher::new(HashSpec::default()).hash(&h, &pkt), Some(hash_packet(HashSpec::default(), &h, &pkt)));
+
+        let mut key = [0u8; 16];
+        for (i, k) in key.iter_mut().enumerate() {
+            *k = i as u8;
+        }
+        hasher.key = Some(key);
+        let keyed = hasher.hash(&h, &pkt).unwrap();
+        hasher.key = Some([0xaa; 16]);
+        assert_ne!(hasher.hash(&h, &pkt).unwrap(), keyed);
+
+        // SipHash-2-4 reference vector: key 00..0f, message 00..0e, read as the kernel reads keys
+        use std::hash::Hasher;
+        let mut sip = siphasher::sip::SipHasher24::new_with_keys(0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908);
+        sip.write(&key[0..15]);
+        assert_eq!(sip.finish(), 0xa129_ca61_49be_45e5);
+    }
+}
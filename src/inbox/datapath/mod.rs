@@ -1,18 +1,37 @@
-pub fn get_epoch_length(rate_bytes: f64, rtt_sec: f64) -> u32 {
-    let inflight_bdp = rate_bytes * rtt_sec / 1500.0;
+pub fn get_epoch_length(rate_bytes: f64, rtt_sec: f64, packet_bytes: u32) -> u32 {
+    let inflight_bdp = rate_bytes * rtt_sec / f64::from(packet_bytes);
     // round to power of 2
     let inflight_bdp_rounded = crate::round_down_power_of_2(inflight_bdp as u32);
 
     std::cmp::min(std::cmp::max(inflight_bdp_rounded >> 2, 4), 1024)
 }
 
+/// What we know about the bundle's RTT, from the round trips of its marks.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RttEstimate {
+    /// The latest sample.
+    pub latest_ns: u64,
+    /// Smoothed RTT and RTT variation, as RFC 6298 computes them.
+    pub srtt_ns: u64,
+    pub rttvar_ns: u64,
+    /// The lowest sample in a recent window, so a route change is noticed.
+    pub min_rtt_ns: u64,
+}
+
 pub trait Datapath {
     fn set_approx_cwnd(&mut self, cwnd_bytes: u32) -> Result<(), ()>;
     fn set_rate(&mut self, rate: u32) -> Result<(), ()>;
-    fn update_rtt(&mut self, rtt_ns: u64) -> Result<(), ()>;
+    fn update_rtt(&mut self, rtt: RttEstimate) -> Result<(), ()>;
     fn set_epoch_length(&mut self, epoch_length_packets: u32) -> Result<(), portus::Error>;
-    fn update_send_rate(&mut self, observed_sending_bytes_per_sec: u64);
+    /// `mean_packet_bytes` sizes epochs in packets.
+    fn update_send_rate(&mut self, observed_sending_bytes_per_sec: u64, mean_packet_bytes: u32);
     fn get_curr_epoch_length(&self) -> u32;
+    /// MTU of the interface the bundle leaves through.
+    fn get_mtu(&self) -> u32;
+    /// The rate currently enforced, if any.
+    fn get_curr_rate(&self) -> Option<u32>;
+    /// Stop limiting the bundle's traffic until the next `set_rate` or `set_approx_cwnd`.
+    fn remove_shaping(&mut self) -> Result<(), ()>;
 }
 
 #[cfg(target_os = "linux")]
@@ -1,32 +1,32 @@
+use crate::hash::MarkHasher;
 use crate::inbox::nl::*;
-use crate::serialize;
 use crate::serialize::QDiscUpdateMsg;
 use portus::ipc;
 use portus::ipc::netlink;
 use portus::ipc::Ipc;
 use slog;
 use slog::{trace, debug, info};
-use std::cmp::min;
 
 use super::get_epoch_length;
-use super::Datapath;
+use super::{Datapath, RttEstimate};
 
 pub struct Qdisc {
     logger: slog::Logger,
+    bundle_id: u32,
+    ifindex: i32,
+    mtu: u32,
     rtnl_sock: *mut nl_sock,
     qdisc: *mut rtnl_qdisc,
     update_sock: netlink::Socket<ipc::Blocking>,
-    outbox_report: std::net::UdpSocket,
-    outbox_addr: Option<std::net::SocketAddr>,
-    outbox_found: std::sync::mpsc::Receiver<std::net::SocketAddr>,
-    rtt_ns: u64,
-    min_rtt_ns: u64,
+    rtt: RttEstimate,
     observed_sending_bytes_per_sec: u64,
+    mean_packet_bytes: u32,
     rate_bytes_per_sec: u32,
     cwnd_bytes: u32,
     curr_set_rate: u32,
     use_dynamic_epoch: bool,
     curr_epoch_length: u32,
+    hasher: MarkHasher,
 }
 
 impl Drop for Qdisc {
@@ -39,11 +39,11 @@ impl Drop for Qdisc {
 
 impl Datapath for Qdisc {
     fn set_approx_cwnd(&mut self, cwnd_bytes: u32) -> Result<(), ()> {
-        if cwnd_bytes / 1500 == 0 {
+        if cwnd_bytes / self.mean_packet_bytes == 0 {
             return Err(());
         }
 
-        debug!(self.logger, "set cwnd"; "cwnd_pkts" => cwnd_bytes / 1500);
+        debug!(self.logger, "set cwnd"; "cwnd_pkts" => cwnd_bytes / self.mean_packet_bytes);
         self.cwnd_bytes = cwnd_bytes;
         self.__set_rate()
     }
@@ -54,9 +54,8 @@ impl Datapath for Qdisc {
         self.__set_rate()
     }
 
-    fn update_rtt(&mut self, rtt_ns: u64) -> Result<(), ()> {
-        self.rtt_ns = rtt_ns;
-        self.min_rtt_ns = min(self.min_rtt_ns, rtt_ns);
+    fn update_rtt(&mut self, rtt: RttEstimate) -> Result<(), ()> {
+        self.rtt = rtt;
         self.__set_rate()
     }
 
@@ -75,32 +74,19 @@ impl Datapath for Qdisc {
         );
 
         self.curr_epoch_length = epoch_length_packets;
-        self.check_outbox_found();
-        if let Some(addr) = self.outbox_addr {
-            // tell the outbox what the epoch length is
-            let msg = serialize::OutBoxReportMsg {
-                bundle_id: 42,
-                epoch_length_packets,
-            };
-
-            self.outbox_report
-                .send_to(&msg.as_bytes(), addr)
-                .unwrap_or_else(|_| 0);
-        }
-
-        let msg = QDiscUpdateMsg {
-            bundle_id: 42,
-            sample_rate: epoch_length_packets,
-        };
-
-        self.update_sock.send(&msg.as_bytes())
+        self.send_update(epoch_length_packets)
     }
 
-    fn update_send_rate(&mut self, observed_sending_bytes_per_sec: u64) {
+    fn update_send_rate(&mut self, observed_sending_bytes_per_sec: u64, mean_packet_bytes: u32) {
         self.observed_sending_bytes_per_sec = observed_sending_bytes_per_sec;
+        if mean_packet_bytes > 0 {
+            self.mean_packet_bytes = mean_packet_bytes;
+        }
+
         let epoch_length = get_epoch_length(
             self.observed_sending_bytes_per_sec as f64,
-            self.min_rtt_ns as f64 / 1e9,
+            self.rtt.min_rtt_ns as f64 / 1e9,
+            self.mean_packet_bytes,
         );
         self.set_epoch_length(epoch_length).unwrap_or_else(|_| ());
     }
@@ -108,16 +94,44 @@ impl Datapath for Qdisc {
     fn get_curr_epoch_length(&self) -> u32 {
         self.curr_epoch_length
     }
+
+    fn get_mtu(&self) -> u32 {
+        self.mtu
+    }
+
+    fn get_curr_rate(&self) -> Option<u32> {
+        if self.curr_set_rate == 0x3fff_ffff {
+            None
+        } else {
+            Some(self.curr_set_rate)
+        }
+    }
+
+    fn remove_shaping(&mut self) -> Result<(), ()> {
+        debug!(self.logger, "remove shaping");
+        self.rate_bytes_per_sec = 0x3fff_ffff;
+        self.cwnd_bytes = 0x3fff_ffff;
+        self.curr_set_rate = 0x3fff_ffff;
+
+        unsafe {
+            rtnl_qdisc_tbf_set_rate(self.qdisc, i32::MAX, 100_000, 0);
+            let ret = rtnl_qdisc_add(self.rtnl_sock, self.qdisc, NLM_F_REPLACE as i32);
+            if ret < 0 {
+                return Err(());
+            }
+            Ok(())
+        }
+    }
 }
 
 impl Qdisc {
     pub fn bind(
         logger: slog::Logger,
+        bundle_id: u32,
         if_name: String,
         (tc_maj, tc_min): (u32, u32),
         use_dynamic_epoch: bool,
-        outbox_found_rx: std::sync::mpsc::Receiver<std::net::SocketAddr>,
-        outbox_report: std::net::UdpSocket,
+        hasher: MarkHasher,
     ) -> Result<Self, failure::Error> {
         unsafe {
             let mut all_links: *mut nl_cache = std::mem::uninitialized();
@@ -134,6 +148,10 @@ impl Qdisc {
             let link =
                 rtnl_link_get_by_name(all_links, std::ffi::CString::new(if_name).unwrap().as_ptr());
             let ifindex = rtnl_link_get_ifindex(link);
+            let mtu = rtnl_link_get_mtu(link);
+            if mtu == 0 {
+                failure::bail!("rtnl_link_get_mtu failed");
+            }
 
             // println!("nitems={:#?}", nl_cache_nitems(all_qdiscs));
             //println!("first={:#?}", nl_cache_get_first(all_qdiscs));
@@ -150,31 +168,49 @@ impl Qdisc {
 
             let update_sock = netlink::Socket::<ipc::Blocking>::new().unwrap();
 
-            Ok(Qdisc {
+            let q = Qdisc {
                 logger,
+                bundle_id,
+                ifindex,
+                mtu,
                 rtnl_sock,
                 qdisc,
                 update_sock,
-                outbox_report,
-                outbox_addr: None,
-                outbox_found: outbox_found_rx,
-                rtt_ns: 0x3fff_ffff,
-                min_rtt_ns: 0x3fff_ffff,
+                rtt: RttEstimate {
+                    latest_ns: 0x3fff_ffff,
+                    srtt_ns: 0x3fff_ffff,
+                    rttvar_ns: 0,
+                    min_rtt_ns: 0x3fff_ffff,
+                },
                 observed_sending_bytes_per_sec: 0x3fff_ffff,
+                mean_packet_bytes: mtu + crate::MAC_HEADER_LENGTH as u32,
                 rate_bytes_per_sec: 0x3fff_ffff,
                 cwnd_bytes: 0x3fff_ffff,
                 curr_set_rate: 0x3fff_ffff,
                 use_dynamic_epoch,
                 curr_epoch_length: 4,
-            })
+                hasher,
+            };
+
+            // tell the qdisc which bundle it belongs to and how to hash, without changing its
+            // sample rate
+            if let Err(e) = q.send_update(0) {
+                failure::bail!("announcing bundle to qdisc failed: {:?}", e);
+            }
+            Ok(q)
         }
     }
 
-    fn check_outbox_found(&mut self) {
-        match self.outbox_found.try_recv() {
-            Ok(addr) => self.outbox_addr = Some(addr),
-            Err(_) => return,
-        }
+    fn send_update(&self, sample_rate: u32) -> Result<(), portus::Error> {
+        let msg = QDiscUpdateMsg {
+            bundle_id: self.bundle_id,
+            ifindex: self.ifindex as u32,
+            sample_rate,
+            hash_spec: self.hasher.spec.bits(),
+            hash_key: self.hasher.key.unwrap_or_default(),
+        };
+
+        self.update_sock.send(&msg.as_bytes())
     }
 
     fn __set_rate(&mut self) -> Result<(), ()> {
@@ -182,7 +218,7 @@ impl Qdisc {
             return Ok(());
         }
 
-        let rtt_sec = self.rtt_ns as f64 / 1e9;
+        let rtt_sec = self.rtt.srtt_ns as f64 / 1e9;
         let cwnd_effective_rate = self.cwnd_bytes as f64 / rtt_sec;
         let rate = std::cmp::min(self.rate_bytes_per_sec, cwnd_effective_rate as u32);
 
@@ -0,0 +1,107 @@
+/// The fraction of an epoch's bytes the qdisc's and the outbox's byte clocks may disagree by
+/// without loss, as they do not count quite the same headers.
+const BYTE_CLOCK_SKEW: f64 = 0.01;
+/// Gain of the smoothed loss rate.
+const RATE_GAIN: f64 = 0.125;
+
+/// What one measurement epoch tells us about loss.
+#[derive(Clone, Copy, Debug)]
+pub struct EpochLoss {
+    /// Whether the epoch starts at the previously matched mark, so that the qdisc and outbox
+    /// counted bytes over the same packets.
+    pub aligned: bool,
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+    /// Marks dropped unmatched so far.
+    pub unmatched_marks: u64,
+    /// Feedback messages lost on the way to us so far.
+    pub lost_feedback: u64,
+    /// Packets the outbox saw go missing in the epoch, if it reports them.
+    pub reported_lost: Option<u32>,
+    /// Mean packet size, to turn byte counts into packets.
+    pub packet_bytes: u32,
+}
+
+/// Estimates the packets lost between the qdisc and the outbox.
+///
+/// Each signal only sees some losses, so an epoch's estimate is the largest of:
+/// - the packets the outbox saw go missing, which only covers TCP flows;
+/// - the bytes the qdisc sent within an aligned epoch that the outbox did not receive, beyond
+///   the skew between the byte clocks;
+/// - the marks that never matched, less those whose feedback was lost instead.
+#[derive(Default)]
+pub struct LossEstimator {
+    /// Smoothed fraction of sent packets which were lost, over aligned epochs.
+    pub rate: f64,
+    unmatched_marks: u64,
+    lost_feedback: u64,
+}
+
+impl LossEstimator {
+    /// Returns how many packets were lost in the epoch.
+    pub fn on_epoch(&mut self, e: EpochLoss) -> u32 {
+        let unmatched = e.unmatched_marks.saturating_sub(self.unmatched_marks);
+        // feedback counted as lost may turn up later, so the total can shrink
+        let lost_feedback = e.lost_feedback.saturating_sub(self.lost_feedback);
+        self.unmatched_marks = e.unmatched_marks;
+        self.lost_feedback = e.lost_feedback;
+
+        let mark_lost = unmatched.saturating_sub(lost_feedback);
+        let mut lost = std::cmp::max(u64::from(e.reported_lost.unwrap_or(0)), mark_lost);
+        if !e.aligned || e.sent_bytes == 0 || e.packet_bytes == 0 {
+            return lost as u32;
+        }
+
+        let skew = (e.sent_bytes as f64 * BYTE_CLOCK_SKEW) as u64;
+        let packet_bytes = u64::from(e.packet_bytes);
+        let byte_lost = e.sent_bytes.saturating_sub(e.recv_bytes + skew) / packet_bytes;
+        lost = std::cmp::max(lost, byte_lost);
+
+        let sent_pkts = std::cmp::max(1, e.sent_bytes / packet_bytes);
+        let epoch_rate = (lost as f64 / sent_pkts as f64).min(1.0);
+        self.rate += RATE_GAIN * (epoch_rate - self.rate);
+        lost as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EpochLoss, LossEstimator};
+
+    #[test]
+    fn check_loss_estimate() {
+        let mut l = LossEstimator::default();
+        let epoch = EpochLoss {
+            aligned: true,
+            sent_bytes: 151_400,
+            recv_bytes: 150_000,
+            unmatched_marks: 0,
+            lost_feedback: 0,
+            reported_lost: None,
+            packet_bytes: 1514,
+        };
+
+        // less than the byte clocks' skew apart
+        assert_eq!(l.on_epoch(epoch), 0);
+        assert_eq!(l.rate, 0.0);
+
+        // ten packets missing beyond the skew, though the outbox only saw the TCP ones go
+        let lossy = EpochLoss {
+            recv_bytes: 151_400 - 11 * 1514,
+            reported_lost: Some(4),
+            ..epoch
+        };
+        assert_eq!(l.on_epoch(lossy), 10);
+        assert!(l.rate > 0.0);
+
+        // two marks never matched, but one's feedback was lost
+        let marks = EpochLoss {
+            aligned: false,
+            unmatched_marks: 2,
+            lost_feedback: 1,
+            ..epoch
+        };
+        assert_eq!(l.on_epoch(marks), 1);
+        assert_eq!(l.on_epoch(EpochLoss { aligned: false, ..marks }), 0);
+    }
+}
@@ -1,78 +1,331 @@
+use fnv::FnvHashMap;
 use std::collections::VecDeque;
 
-#[derive(Clone, Copy)]
+/// Most marks we remember; beyond this, the oldest are dropped as expired.
+const MAX_MARKS: usize = 4096;
+/// Marks older than this many RTTs have lost their feedback.
+const EXPIRY_RTTS: u64 = 4;
+/// But give feedback at least this long, as the RTT estimate may be too low.
+const MIN_EXPIRY_NS: u64 = 100_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct MarkedInstant {
     pub time: u64,
     pub pkt_hash: u32,
     pub send_byte_clock: u64,
 }
 
+/// Bounds on how a mark and the feedback matching it can relate. Marks are only 32-bit hashes of a
+/// few header fields, so several marks in flight can share a hash; these tell them apart.
+#[derive(Clone, Copy, Debug)]
+pub struct MatchLimits {
+    /// Feedback cannot arrive sooner than this after its mark was sent.
+    pub min_rtt_ns: u64,
+    /// Nor later than this.
+    pub max_rtt_ns: u64,
+    /// The largest fraction of the bytes sent between two marks that may be lost on the way.
+    pub max_loss: f64,
+    /// Largest packet we expect, to turn an epoch length in packets into bytes.
+    pub max_packet_bytes: u64,
+}
+
+impl Default for MatchLimits {
+    fn default() -> Self {
+        MatchLimits {
+            min_rtt_ns: 0,
+            max_rtt_ns: 10_000_000_000,
+            max_loss: 0.5,
+            max_packet_bytes: u64::from(super::DEFAULT_FRAME_BYTES),
+        }
+    }
+}
+
+/// The marks the qdisc sent, oldest first, indexed by hash.
 #[derive(Default)]
 pub struct MarkHistory {
     marks: VecDeque<MarkedInstant>,
+    // position of marks[0] among all marks ever inserted
+    first: u64,
+    // positions of the marks with each hash, oldest first
+    index: FnvHashMap<u32, Vec<u64>>,
+    pub limits: MatchLimits,
+    /// Whether feedback may come back in a different order than the marks were sent, as it does
+    /// when the marked packets go to several outboxes. If so, a match leaves older marks waiting.
+    pub unordered: bool,
+    // send and receive byte clocks of the newest mark we matched
+    last_match: Option<(u64, u64)>,
+    /// Feedback whose hash only matched marks it cannot belong to.
+    pub implausible: u64,
+    /// Feedback which could belong to more than one mark.
+    pub ambiguous: u64,
+    /// Marks dropped unmatched because their feedback is overdue, or to stay within `MAX_MARKS`.
+    pub expired: u64,
+    /// Marks dropped unmatched because feedback matched a newer mark.
+    pub skipped: u64,
 }
 
 impl MarkHistory {
     pub fn insert(&mut self, pkt_hash: u32, time: u64, send_byte_clock: u64) {
+        if self.marks.len() >= MAX_MARKS {
+            if let Some((_, true)) = self.pop_front() {
+                self.expired += 1;
+            }
+        }
+
+        let pos = self.first + self.marks.len() as u64;
         self.marks.push_back(MarkedInstant {
             time,
             pkt_hash,
             send_byte_clock,
-        })
+        });
+        self.index.entry(pkt_hash).or_default().push(pos);
+    }
+
+    /// Marks still waiting for their feedback.
+    pub fn outstanding(&self) -> usize {
+        self.index.values().map(Vec::len).sum()
+    }
+
+    /// Drop the mark at `pos` from the index, so feedback no longer finds it.
+    fn unindex(&mut self, pkt_hash: u32, pos: u64) -> bool {
+        let (found, emptied) = match self.index.get_mut(&pkt_hash) {
+            Some(positions) => match positions.iter().position(|&p| p == pos) {
+                Some(i) => {
+                    positions.remove(i);
+                    (true, positions.is_empty())
+                }
+                None => (false, false),
+            },
+            None => (false, false),
+        };
+        if emptied {
+            self.index.remove(&pkt_hash);
+        }
+
+        found
+    }
+
+    /// Drop the oldest mark. Also returns whether it was still waiting for feedback, rather than
+    /// matched out of order.
+    fn pop_front(&mut self) -> Option<(MarkedInstant, bool)> {
+        let mark = self.marks.pop_front()?;
+        let pos = self.first;
+        self.first += 1;
+        let waiting = self.unindex(mark.pkt_hash, pos);
+        Some((mark, waiting))
     }
 
-    // TODO can implement binary search for perf
-    fn find_idx(&self, pkt_hash: u32) -> Option<usize> {
-        for i in 0..self.marks.len() {
-            if self.marks[i].pkt_hash == pkt_hash {
-                return Some(i);
+    /// Drop marks sent so long before `now` that their feedback must have been lost: a few RTTs
+    /// if we have an RTT estimate, and never later than `limits.max_rtt_ns`.
+    /// Returns how many marks expired.
+    pub fn expire(&mut self, now: u64, rtt_ns: u64) -> u64 {
+        let timeout = if rtt_ns == 0 {
+            self.limits.max_rtt_ns
+        } else {
+            std::cmp::min(
+                std::cmp::max(rtt_ns * EXPIRY_RTTS, MIN_EXPIRY_NS),
+                self.limits.max_rtt_ns,
+            )
+        };
+
+        let mut expired = 0;
+        while let Some(m) = self.marks.front() {
+            if now.saturating_sub(m.time) <= timeout {
+                break;
+            }
+
+            if let Some((_, true)) = self.pop_front() {
+                expired += 1;
             }
         }
 
-        None
+        self.expired += expired;
+        expired
     }
 
-    pub fn get(&mut self, _now: u64, pkt_hash: u32) -> Option<MarkedInstant> {
-        let idx = self.find_idx(pkt_hash)?;
-        self.marks.drain(0..(idx + 1)).last()
+    /// Whether feedback arriving at `now` and saying the outbox had received `recv_byte_clock`
+    /// bytes could be for `mark`.
+    ///
+    /// Between the last matched mark and this one, the outbox cannot have received more bytes than
+    /// the qdisc sent, nor much fewer unless they were lost. Either byte clock may be off by up to
+    /// an epoch, as the two ends do not count quite the same bytes.
+    fn plausible(&self, mark: &MarkedInstant, now: u64, recv_byte_clock: u64, epoch_length: u32) -> bool {
+        let rtt = match now.checked_sub(mark.time) {
+            Some(rtt) => rtt,
+            None => return false,
+        };
+
+        if rtt < self.limits.min_rtt_ns || rtt > self.limits.max_rtt_ns {
+            return false;
+        }
+
+        let (s1, r1) = match self.last_match {
+            Some(m) => m,
+            None => return true,
+        };
+
+        let send_delta = match mark.send_byte_clock.checked_sub(s1) {
+            Some(d) => d,
+            // feedback for older marks may still come from other outboxes
+            None => return self.unordered,
+        };
+
+        // the outbox's byte clock went backwards, so we cannot compare
+        let recv_delta = match recv_byte_clock.checked_sub(r1) {
+            Some(d) => d,
+            None => return true,
+        };
+
+        let slack = u64::from(epoch_length) * self.limits.max_packet_bytes;
+        let least_delivered = (send_delta as f64 * (1.0 - self.limits.max_loss)) as u64;
+        recv_delta <= send_delta + slack && recv_delta + slack >= least_delivered
     }
-}
 
-#[derive(Clone, Copy)]
-pub struct Epoch {
-    pub elapsed_ns: u64,
-    pub bytes: u64,
-}
+    /// The mark that feedback for `pkt_hash` belongs to, dropping it and, unless `unordered`, every
+    /// older mark. None if no mark plausibly matches, or more than one does.
+    pub fn get(
+        &mut self,
+        now: u64,
+        pkt_hash: u32,
+        recv_byte_clock: u64,
+        epoch_length: u32,
+    ) -> Option<MarkedInstant> {
+        let positions = self.index.get(&pkt_hash)?;
+        let mut found = None;
+        let mut ambiguous = false;
+        for &pos in positions {
+            let m = &self.marks[(pos - self.first) as usize];
+            if !self.plausible(m, now, recv_byte_clock, epoch_length) {
+                continue;
+            }
 
-#[derive(Default)]
-pub struct EpochHistory {
-    pub window: usize,
-    sending: VecDeque<Epoch>,
-    receiving: VecDeque<Epoch>,
-}
+            if found.is_some() {
+                ambiguous = true;
+                break;
+            }
 
-fn rate<'a>(epochs: impl Iterator<Item = &'a Epoch>) -> f64 {
-    let (tot_bytes, tot_elapsed_ns) = epochs
-        .map(|e| (e.bytes as f64, e.elapsed_ns as f64))
-        .fold((0.0, 0.0), |(b, t), (c_b, c_t)| (b + c_b, t + c_t));
-    tot_bytes / (tot_elapsed_ns / 1e9)
-}
+            found = Some(pos);
+        }
 
-impl EpochHistory {
-    pub fn got_epoch(&mut self, send_epoch: Epoch, recv_epoch: Epoch) -> (f64, f64) {
-        self.sending.push_back(send_epoch);
-        self.receiving.push_back(recv_epoch);
+        if ambiguous {
+            self.ambiguous += 1;
+            return None;
+        }
+
+        let pos = match found {
+            Some(p) => p,
+            None => {
+                self.implausible += 1;
+                return None;
+            }
+        };
 
-        assert!(self.window > 0);
+        let mark = if self.unordered {
+            let mark = self.marks[(pos - self.first) as usize];
+            self.unindex(pkt_hash, pos);
+            mark
+        } else {
+            while self.first < pos {
+                if let Some((_, true)) = self.pop_front() {
+                    self.skipped += 1;
+                }
+            }
 
-        while self.sending.len() > self.window {
-            self.sending.pop_front();
+            self.pop_front()?.0
+        };
+
+        let newest = match self.last_match {
+            Some((s, _)) => mark.send_byte_clock >= s,
+            None => true,
+        };
+        if newest {
+            self.last_match = Some((mark.send_byte_clock, recv_byte_clock));
         }
 
-        while self.receiving.len() > self.window {
-            self.receiving.pop_front();
+        Some(mark)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MarkHistory, MAX_MARKS};
+
+    #[test]
+    fn check_mark_matching() {
+        let mut h = MarkHistory::default();
+        h.insert(7, 1_000, 10_000);
+        assert_eq!(h.get(2_000, 8, 9_000, 4), None);
+        assert_eq!(h.implausible, 0);
+        assert_eq!(h.get(2_000, 7, 9_000, 4).map(|m| m.time), Some(1_000));
+
+        // the second mark with hash 7 was sent too long ago for this feedback to be its
+        h.insert(7, 3_000, 20_000);
+        h.insert(9, 4_000, 30_000);
+        h.insert(7, 5_000, 40_000);
+        h.limits.max_rtt_ns = 5_000;
+        assert_eq!(h.get(10_000, 7, 39_000, 4).map(|m| m.send_byte_clock), Some(40_000));
+
+        // received far more bytes than were sent since the last match: a collision
+        h.insert(3, 11_000, 50_000);
+        assert_eq!(h.get(12_000, 3, 100_000, 4), None);
+        assert_eq!(h.implausible, 1);
+        assert_eq!(h.get(12_000, 3, 49_000, 4).map(|m| m.time), Some(11_000));
+
+        // both marks fit this feedback, so neither can be trusted
+        h.insert(5, 13_000, 52_000);
+        h.insert(5, 14_000, 53_000);
+        assert_eq!(h.get(15_000, 5, 51_500, 4), None);
+        assert_eq!(h.ambiguous, 1);
+        assert_eq!(h.skipped, 2);
+    }
+
+    #[test]
+    fn check_mark_expiry() {
+        let mut h = MarkHistory::default();
+        for i in 0..MAX_MARKS as u64 + 2 {
+            h.insert(i as u32 % 16, i * 1_000_000, i * 1_500);
         }
+        assert_eq!(h.outstanding(), MAX_MARKS);
+        assert_eq!(h.expired, 2);
+
+        // with a 10ms RTT, marks older than 100ms are overdue
+        let now = (MAX_MARKS as u64 + 1) * 1_000_000;
+        assert_eq!(h.expire(now, 10_000_000), MAX_MARKS as u64 - 101);
+        assert_eq!(h.outstanding(), 101);
+        assert_eq!(h.expired, MAX_MARKS as u64 - 99);
+
+        // the index still finds the remaining marks; only the oldest is in flight long enough
+        h.limits.min_rtt_ns = 90_000_000;
+        let oldest = h.get(now, (MAX_MARKS as u32 - 99) % 16, 0, 4).unwrap();
+        assert_eq!(oldest.time, (MAX_MARKS as u64 - 99) * 1_000_000);
+        assert_eq!(h.skipped, 0);
+        let next = h.get(now, 0, 0, 4).unwrap();
+        assert_eq!(next.time, 4_000_000_000);
+        assert_eq!(h.skipped, 2);
+    }
+
+    #[test]
+    fn check_unordered_marks() {
+        let mut h = MarkHistory {
+            unordered: true,
+            ..Default::default()
+        };
+        h.insert(1, 1_000, 10_000);
+        h.insert(2, 2_000, 20_000);
+        h.insert(3, 3_000, 30_000);
+
+        // the newest mark's feedback comes back first, from another outbox
+        assert_eq!(h.get(4_000, 3, 29_000, 4).map(|m| m.time), Some(3_000));
+        assert_eq!(h.skipped, 0);
+        assert_eq!(h.outstanding(), 2);
+
+        // older marks still match, without moving the byte clocks back
+        assert_eq!(h.get(4_500, 1, 12_000, 4).map(|m| m.time), Some(1_000));
+        assert_eq!(h.last_match, Some((30_000, 29_000)));
 
-        (rate(self.sending.iter()), rate(self.receiving.iter()))
+        // matched marks do not count as expired when they leave
+        assert_eq!(h.expire(200_000_000, 0), 0);
+        assert_eq!(h.expire(20_000_000_000, 0), 1);
+        assert_eq!(h.outstanding(), 0);
     }
 }
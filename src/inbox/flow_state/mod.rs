@@ -2,14 +2,32 @@ use crate::inbox::ConnectionImpl;
 use crate::serialize::OutBoxFeedbackMsg;
 use slog::info;
 
+mod loss;
+use self::loss::{EpochLoss, LossEstimator};
 mod marks;
-use self::marks::{Epoch, EpochHistory, MarkHistory, MarkedInstant};
+use self::marks::{MarkHistory, MarkedInstant};
+mod rate;
+mod recv;
+use self::recv::ReceiveView;
+mod rtt;
+use self::rtt::RttEstimator;
+pub use self::rate::{EpochHistory, RateEstimatorKind};
+use self::rate::Epoch;
+mod seq;
+pub use self::seq::SeqCheck;
+mod size;
+pub use self::size::DEFAULT_FRAME_BYTES;
+use self::size::PacketSize;
 
 /// Calculate and maintain flow measurements.
 pub struct BundleFlowState<'dp, Q: crate::inbox::datapath::Datapath + 'static> {
     pub conn: Option<libccp::Connection<'dp, ConnectionImpl<Q>>>,
     pub marked_packets: MarkHistory,
     pub epoch_history: EpochHistory,
+    /// The outboxes' feedback, merged.
+    pub recv: ReceiveView,
+    pub loss: LossEstimator,
+    pub packet_size: PacketSize,
 
     pub prev_send_time: u64,
     pub prev_send_byte_clock: u64,
@@ -18,13 +36,20 @@ pub struct BundleFlowState<'dp, Q: crate::inbox::datapath::Datapath + 'static> {
 
     pub send_rate: f64,
     pub recv_rate: f64,
-    pub rtt_estimate: u64,
+    pub rtt: RttEstimator,
 
     pub bdp_estimate_packets: u32,
     pub acked_bytes: u32, // estimate with number of received packets in last epoch
-    pub lost_bytes: u32,
+    pub lost_pkts: u32,
+    pub reordered_pkts: u32,
 
+    /// Packets in the qdisc's queue when it last sent a mark.
     pub curr_qlen: u32,
+    /// How long a packet joining the qdisc's queue waits there.
+    pub inbox_qdelay_ns: u64,
+    /// How far the smoothed RTT is above the minimum, from queues in the network. Marks are
+    /// taken as packets leave the qdisc, so this excludes our own queue.
+    pub network_qdelay_ns: u64,
 }
 
 impl<'dp, Q: crate::inbox::datapath::Datapath> Default for BundleFlowState<'dp, Q> {
@@ -33,17 +58,23 @@ impl<'dp, Q: crate::inbox::datapath::Datapath> Default for BundleFlowState<'dp,
             conn: None,
             marked_packets: Default::default(),
             epoch_history: Default::default(),
+            recv: Default::default(),
+            loss: Default::default(),
+            packet_size: Default::default(),
             prev_send_time: Default::default(),
             prev_send_byte_clock: Default::default(),
             prev_recv_time: Default::default(),
             prev_recv_byte_clock: Default::default(),
             send_rate: Default::default(),
             recv_rate: Default::default(),
-            rtt_estimate: Default::default(),
+            rtt: Default::default(),
             bdp_estimate_packets: Default::default(),
             acked_bytes: Default::default(),
-            lost_bytes: Default::default(),
+            lost_pkts: Default::default(),
+            reordered_pkts: Default::default(),
             curr_qlen: Default::default(),
+            inbox_qdelay_ns: Default::default(),
+            network_qdelay_ns: Default::default(),
         }
     }
 }
@@ -86,12 +117,15 @@ impl<'dp, Q: crate::inbox::datapath::Datapath> BundleFlowState<'dp, Q> {
         let r2_bytes = recv_mark.epoch_bytes;
 
         // rtt is current time - sent mark time
-        self.rtt_estimate = now.saturating_sub(s2);
+        let rtt = self.rtt.on_sample(now, now.saturating_sub(s2));
+        self.network_qdelay_ns = rtt.srtt_ns.saturating_sub(rtt.min_rtt_ns);
 
         if r1 > r2 || r1_bytes > r2_bytes || s1 > s2 || s1_bytes > s2_bytes {
             return;
         }
 
+        // the first match after a reset has no previous mark to start its epoch at
+        let aligned = s1 != 0;
         let send_epoch_ns = s2 - s1;
         let recv_epoch_ns = r2 - r1;
 
@@ -122,12 +156,24 @@ impl<'dp, Q: crate::inbox::datapath::Datapath> BundleFlowState<'dp, Q> {
             "epoch_window" => self.epoch_history.window,
         );
 
-        let rtt_s = self.rtt_estimate as f64 / 1e9;
+        let rtt_s = self.rtt.estimate().srtt_ns as f64 / 1e9;
         let bdp_estimate_bytes = send_rate as f64 * rtt_s;
-        self.bdp_estimate_packets = (bdp_estimate_bytes / 1514.0) as u32;
+        self.bdp_estimate_packets = (bdp_estimate_bytes / f64::from(self.packet_size.mean())) as u32;
         self.acked_bytes = recv_epoch_bytes as u32;
-        let delta = send_epoch_bytes.saturating_sub(recv_epoch_bytes);
-        self.lost_bytes = if delta > 0 { delta as u32 } else { 0 };
+        // losses add up until the next invoke
+        let lost = self.loss.on_epoch(EpochLoss {
+            aligned,
+            sent_bytes: send_epoch_bytes,
+            recv_bytes: recv_epoch_bytes,
+            unmatched_marks: self.marked_packets.expired + self.marked_packets.skipped,
+            lost_feedback: self.recv.lost(),
+            reported_lost: recv_mark.stats.map(|s| s.lost_pkts),
+            packet_bytes: self.packet_size.mean(),
+        });
+        self.lost_pkts += lost;
+        if let Some(stats) = recv_mark.stats {
+            self.reordered_pkts += stats.reordered_pkts;
+        }
 
         // s2 now becomes s1 and r2 becomes r1
         self.prev_send_time = s2;
@@ -138,12 +184,51 @@ impl<'dp, Q: crate::inbox::datapath::Datapath> BundleFlowState<'dp, Q> {
         self.update_primitives()
     }
 
+    /// Measure a bundle whose send and receive rates come from `rate_estimator`, and whose largest
+    /// packets are `max_packet_bytes`.
+    pub fn new(rate_estimator: RateEstimatorKind, max_packet_bytes: u32) -> Self {
+        let mut fs = BundleFlowState {
+            epoch_history: EpochHistory::new(rate_estimator),
+            packet_size: PacketSize::new(max_packet_bytes),
+            ..Default::default()
+        };
+        fs.marked_packets.limits.max_packet_bytes = u64::from(max_packet_bytes);
+        fs
+    }
+
+    /// Forget all measurements, e.g. because the outbox restarted, but keep the connection.
+    pub fn reset(&mut self) {
+        let window = self.epoch_history.window;
+        *self = BundleFlowState {
+            conn: self.conn.take(),
+            ..BundleFlowState::new(self.epoch_history.kind(), self.packet_size.max())
+        };
+        self.epoch_history.window = window;
+    }
+
+    /// The qdisc has `qlen` packets queued, which it sends at `enforced_rate`, or as fast as they
+    /// come if it is not shaping.
+    pub fn got_qlen(&mut self, qlen: u32, enforced_rate: Option<u32>) {
+        self.curr_qlen = qlen;
+        let drain_rate = enforced_rate.map_or(self.send_rate, f64::from);
+        self.inbox_qdelay_ns = if drain_rate > 0.0 {
+            (f64::from(qlen) * f64::from(self.packet_size.mean()) / drain_rate * 1e9) as u64
+        } else {
+            0
+        };
+    }
+
     pub fn did_invoke(&mut self) {
         self.acked_bytes = 0;
-        self.lost_bytes = 0;
+        self.lost_pkts = 0;
+        self.reordered_pkts = 0;
         self.update_primitives()
     }
 
+    /// libccp's primitives are a fixed set, with no field for the smoothed RTT, RTT variation or
+    /// minimum RTT, so datapath programs only get the latest RTT sample. The rest of the estimate
+    /// goes to the `Datapath` and into the logs. Nor is there one for `inbox_qdelay_ns` or
+    /// `network_qdelay_ns`, which are only logged; programs get our queue's backlog instead.
     fn update_primitives(&mut self) {
         // set primitives
         if let Some(c) = self.conn.as_mut() {
@@ -151,12 +236,113 @@ impl<'dp, Q: crate::inbox::datapath::Datapath> BundleFlowState<'dp, Q> {
                 libccp::Primitives::default()
                     .with_rate_outgoing(self.send_rate as u64)
                     .with_rate_incoming(self.recv_rate as u64)
-                    .with_rtt_sample_us(self.rtt_estimate / 1_000)
+                    // datapath programs smooth RTT samples themselves
+                    .with_rtt_sample_us(self.rtt.estimate().latest_ns / 1_000)
                     .with_bytes_acked(self.acked_bytes)
-                    .with_packets_acked(self.acked_bytes / 1514)
-                    .with_lost_pkts_sample(self.lost_bytes / 1514)
-                    .with_bytes_pending(self.curr_qlen), // quick hack
+                    .with_packets_acked(self.acked_bytes / self.packet_size.mean())
+                    .with_lost_pkts_sample(self.lost_pkts)
+                    // with rate_outgoing, this gives the delay in our own queue
+                    .with_bytes_pending(self.curr_qlen.saturating_mul(self.packet_size.mean())),
             );
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BundleFlowState, MarkedInstant, RateEstimatorKind};
+    use crate::inbox::datapath::{Datapath, RttEstimate};
+    use crate::serialize::OutBoxFeedbackMsg;
+
+    struct NoDatapath;
+
+    impl Datapath for NoDatapath {
+        fn set_approx_cwnd(&mut self, _: u32) -> Result<(), ()> {
+            Ok(())
+        }
+        fn set_rate(&mut self, _: u32) -> Result<(), ()> {
+            Ok(())
+        }
+        fn update_rtt(&mut self, _: RttEstimate) -> Result<(), ()> {
+            Ok(())
+        }
+        fn set_epoch_length(&mut self, _: u32) -> Result<(), portus::Error> {
+            Ok(())
+        }
+        fn update_send_rate(&mut self, _: u64, _: u32) {}
+        fn get_curr_epoch_length(&self) -> u32 {
+            0
+        }
+        fn get_mtu(&self) -> u32 {
+            1500
+        }
+        fn get_curr_rate(&self) -> Option<u32> {
+            None
+        }
+        fn remove_shaping(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    fn flow_state() -> BundleFlowState<'static, NoDatapath> {
+        BundleFlowState::new(RateEstimatorKind::WindowSum, 1_000)
+    }
+
+    #[test]
+    fn check_inbox_qdelay() {
+        let mut fs = flow_state();
+        fs.send_rate = 40_000.0;
+
+        // shaping: the queue drains at the enforced rate
+        fs.got_qlen(10, Some(20_000));
+        assert_eq!(fs.curr_qlen, 10);
+        assert_eq!(fs.inbox_qdelay_ns, 500_000_000);
+
+        // not shaping: the queue drains as fast as packets come
+        fs.got_qlen(10, None);
+        assert_eq!(fs.inbox_qdelay_ns, 250_000_000);
+
+        // no rate to drain at yet
+        fs.got_qlen(10, Some(0));
+        assert_eq!(fs.inbox_qdelay_ns, 0);
+        fs.send_rate = 0.0;
+        fs.got_qlen(10, None);
+        assert_eq!(fs.inbox_qdelay_ns, 0);
+
+        fs.send_rate = 40_000.0;
+        fs.got_qlen(0, None);
+        assert_eq!((fs.curr_qlen, fs.inbox_qdelay_ns), (0, 0));
+    }
+
+    #[test]
+    fn check_network_qdelay() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut fs = flow_state();
+        fs.epoch_history.window = 1;
+        let mark = |time, bytes| MarkedInstant {
+            time,
+            pkt_hash: 0,
+            send_byte_clock: bytes,
+        };
+        let feedback = |time, bytes| OutBoxFeedbackMsg {
+            bundle_id: 0,
+            seq: 0,
+            marked_packet_hash: 0,
+            epoch_bytes: bytes,
+            epoch_time: time,
+            stats: None,
+        };
+
+        // the first sample is the minimum
+        let (sent, recvd) = (mark(1_000_000, 10_000), feedback(41_000_000, 10_000));
+        fs.update_measurements(81_000_000, sent, recvd, &log);
+        assert_eq!(fs.network_qdelay_ns, 0);
+
+        // srtt = 7/8 * 80ms + 1/8 * 160ms = 90ms, min_rtt stays at 80ms
+        let (sent, recvd) = (mark(2_000_000, 20_000), feedback(82_000_000, 20_000));
+        fs.update_measurements(162_000_000, sent, recvd, &log);
+        let rtt = fs.rtt.estimate();
+        assert_eq!((rtt.srtt_ns, rtt.min_rtt_ns), (90_000_000, 80_000_000));
+        assert_eq!(fs.network_qdelay_ns, 10_000_000);
+    }
+}
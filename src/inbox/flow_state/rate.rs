@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+
+/// How many windows of epochs `WindowedMax` takes the maximum over, as BBR keeps its bottleneck
+/// bandwidth for about ten round trips.
+const MAX_FILTER_WINDOWS: usize = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Epoch {
+    pub elapsed_ns: u64,
+    pub bytes: u64,
+}
+
+impl Epoch {
+    /// None for an epoch which took no time.
+    fn rate(&self) -> Option<f64> {
+        if self.elapsed_ns == 0 {
+            None
+        } else {
+            Some(self.bytes as f64 / (self.elapsed_ns as f64 / 1e9))
+        }
+    }
+}
+
+/// Turns a series of epochs into a rate, in bytes per second.
+pub trait RateEstimator {
+    /// Take the latest epoch and return the current estimate.
+    /// `window` is about how many epochs make up a round trip.
+    fn got_epoch(&mut self, epoch: Epoch, window: usize) -> f64;
+}
+
+/// The bytes over the time of the last `window` epochs.
+#[derive(Default)]
+pub struct WindowSum {
+    epochs: VecDeque<Epoch>,
+}
+
+impl RateEstimator for WindowSum {
+    fn got_epoch(&mut self, epoch: Epoch, window: usize) -> f64 {
+        self.epochs.push_back(epoch);
+        while self.epochs.len() > window {
+            self.epochs.pop_front();
+        }
+
+        let (tot_bytes, tot_elapsed_ns) = self
+            .epochs
+            .iter()
+            .map(|e| (e.bytes as f64, e.elapsed_ns as f64))
+            .fold((0.0, 0.0), |(b, t), (c_b, c_t)| (b + c_b, t + c_t));
+        tot_bytes / (tot_elapsed_ns / 1e9)
+    }
+}
+
+/// Moving average of epoch rates, which weighs each epoch by `1 / window`.
+#[derive(Default)]
+pub struct Ewma {
+    rate: Option<f64>,
+}
+
+impl RateEstimator for Ewma {
+    fn got_epoch(&mut self, epoch: Epoch, window: usize) -> f64 {
+        if let Some(r) = epoch.rate() {
+            let gain = 1.0 / window as f64;
+            self.rate = Some(match self.rate {
+                Some(prev) => prev + gain * (r - prev),
+                None => r,
+            });
+        }
+
+        self.rate.unwrap_or(0.0)
+    }
+}
+
+/// Keeps the epoch rates of the last `window` epochs, for filters which pick one of them.
+#[derive(Default)]
+struct RecentRates {
+    rates: VecDeque<f64>,
+}
+
+impl RecentRates {
+    fn push(&mut self, epoch: Epoch, len: usize) {
+        if let Some(r) = epoch.rate() {
+            self.rates.push_back(r);
+        }
+
+        while self.rates.len() > len {
+            self.rates.pop_front();
+        }
+    }
+}
+
+/// The highest epoch rate in the last `MAX_FILTER_WINDOWS` windows, like BBR's bottleneck
+/// bandwidth filter. Ignores dips from epochs which did not have enough data to send.
+#[derive(Default)]
+pub struct WindowedMax {
+    recent: RecentRates,
+}
+
+impl RateEstimator for WindowedMax {
+    fn got_epoch(&mut self, epoch: Epoch, window: usize) -> f64 {
+        self.recent.push(epoch, window * MAX_FILTER_WINDOWS);
+        self.recent.rates.iter().copied().fold(0.0, f64::max)
+    }
+}
+
+/// The median epoch rate of the last `window` epochs, which ignores outliers either way.
+#[derive(Default)]
+pub struct Median {
+    recent: RecentRates,
+}
+
+impl RateEstimator for Median {
+    fn got_epoch(&mut self, epoch: Epoch, window: usize) -> f64 {
+        self.recent.push(epoch, window);
+        let mut rates: Vec<f64> = self.recent.rates.iter().copied().collect();
+        if rates.is_empty() {
+            return 0.0;
+        }
+
+        rates.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let mid = rates.len() / 2;
+        if rates.len() % 2 == 1 {
+            rates[mid]
+        } else {
+            (rates[mid - 1] + rates[mid]) / 2.0
+        }
+    }
+}
+
+/// Which `RateEstimator` a bundle uses for its send and receive rates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateEstimatorKind {
+    WindowSum,
+    Ewma,
+    WindowedMax,
+    Median,
+}
+
+impl RateEstimatorKind {
+    const NAMES: [(RateEstimatorKind, &'static str); 4] = [
+        (RateEstimatorKind::WindowSum, "sum"),
+        (RateEstimatorKind::Ewma, "ewma"),
+        (RateEstimatorKind::WindowedMax, "max"),
+        (RateEstimatorKind::Median, "median"),
+    ];
+
+    pub fn build(self) -> Box<dyn RateEstimator> {
+        match self {
+            RateEstimatorKind::WindowSum => Box::new(WindowSum::default()),
+            RateEstimatorKind::Ewma => Box::new(Ewma::default()),
+            RateEstimatorKind::WindowedMax => Box::new(WindowedMax::default()),
+            RateEstimatorKind::Median => Box::new(Median::default()),
+        }
+    }
+}
+
+impl std::str::FromStr for RateEstimatorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RateEstimatorKind::NAMES
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(k, _)| *k)
+            .ok_or_else(|| format!("unknown rate estimator: {}", s))
+    }
+}
+
+impl std::fmt::Display for RateEstimatorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = RateEstimatorKind::NAMES
+            .iter()
+            .find(|(k, _)| k == self)
+            .map_or("?", |(_, name)| name);
+        write!(f, "{}", name)
+    }
+}
+
+/// The send and receive epochs measured so far, turned into rates.
+pub struct EpochHistory {
+    /// How many epochs make up about a round trip.
+    pub window: usize,
+    kind: RateEstimatorKind,
+    sending: Box<dyn RateEstimator>,
+    receiving: Box<dyn RateEstimator>,
+}
+
+impl Default for EpochHistory {
+    fn default() -> Self {
+        EpochHistory::new(RateEstimatorKind::WindowSum)
+    }
+}
+
+impl EpochHistory {
+    pub fn new(kind: RateEstimatorKind) -> Self {
+        EpochHistory {
+            window: 0,
+            kind,
+            sending: kind.build(),
+            receiving: kind.build(),
+        }
+    }
+
+    pub fn kind(&self) -> RateEstimatorKind {
+        self.kind
+    }
+
+    pub fn got_epoch(&mut self, send_epoch: Epoch, recv_epoch: Epoch) -> (f64, f64) {
+        assert!(self.window > 0);
+        (
+            self.sending.got_epoch(send_epoch, self.window),
+            self.receiving.got_epoch(recv_epoch, self.window),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Epoch, RateEstimatorKind};
+
+    #[test]
+    fn check_rate_estimators() {
+        // 1, 3, 2 and 10 MB/s epochs, the last an outlier
+        let epochs: Vec<Epoch> = [1_000, 3_000, 2_000, 10_000]
+            .iter()
+            .map(|&bytes| Epoch {
+                elapsed_ns: 1_000_000,
+                bytes,
+            })
+            .collect();
+        let run = |kind: &str, window: usize| {
+            let mut e = kind.parse::<RateEstimatorKind>().unwrap().build();
+            epochs.iter().map(|&ep| e.got_epoch(ep, window)).last().unwrap()
+        };
+
+        let close = |a: f64, b: f64| (a - b).abs() < 1.0;
+        assert!(close(run("sum", 2), 6e6));
+        assert!(close(run("ewma", 1), 10e6));
+        assert!(close(run("ewma", 2), 6e6));
+        assert!(close(run("max", 1), 10e6));
+        assert!(close(run("median", 3), 3e6));
+        assert!(close(run("median", 4), 2.5e6));
+
+        assert!("mean".parse::<RateEstimatorKind>().is_err());
+        assert_eq!(RateEstimatorKind::WindowedMax.to_string(), "max");
+    }
+}
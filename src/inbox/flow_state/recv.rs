@@ -0,0 +1,136 @@
+use super::seq::{FeedbackSeq, SeqCheck};
+use crate::serialize::OutBoxFeedbackMsg;
+use fnv::FnvHashMap;
+use std::net::SocketAddr;
+
+/// What we know of one outbox's clocks.
+#[derive(Default)]
+struct OutboxClock {
+    seq: FeedbackSeq,
+    // its byte clock counts from here, as far as the view is concerned
+    start_bytes: u64,
+    // bytes counted before its byte clock last started over
+    base_bytes: u64,
+    last_bytes: u64,
+    // our time less its time, when it first reported
+    offset_ns: Option<i64>,
+}
+
+impl OutboxClock {
+    fn bytes(&self) -> u64 {
+        self.base_bytes + self.last_bytes - self.start_bytes
+    }
+}
+
+/// A bundle's receive side, merged from the feedback of each of its outboxes.
+///
+/// The view's byte clock is the sum of the outboxes' byte clocks, each counted from when the view
+/// first heard from it. Outbox clocks are not synchronized, so their times are shifted onto one
+/// timeline by how far apart each outbox's clock and ours were at its first report.
+///
+/// The first outbox to report anchors the view: its clocks are used as they are, so a bundle with
+/// a single outbox sees exactly what it reports.
+#[derive(Default)]
+pub struct ReceiveView {
+    outboxes: FnvHashMap<SocketAddr, OutboxClock>,
+    // offset of the anchoring outbox
+    anchor_offset_ns: Option<i64>,
+}
+
+impl ReceiveView {
+    /// How many outboxes have reported.
+    pub fn outboxes(&self) -> usize {
+        self.outboxes.len()
+    }
+
+    /// Feedback messages lost on the way from any outbox.
+    pub fn lost(&self) -> u64 {
+        self.outboxes.values().map(|o| o.seq.lost).sum()
+    }
+
+    /// Feedback messages from any outbox which came too late to use.
+    pub fn reordered(&self) -> u64 {
+        self.outboxes.values().map(|o| o.seq.reordered).sum()
+    }
+
+    /// Check `seq` against the feedback the outbox at `from` sent before.
+    pub fn check_seq(&mut self, from: SocketAddr, seq: u32) -> SeqCheck {
+        self.outboxes.entry(from).or_default().seq.check(seq)
+    }
+
+    /// Turn the byte clock and time of feedback arriving at `now` from the outbox at `from` into
+    /// the view's.
+    pub fn merge(&mut self, from: SocketAddr, now: u64, mut msg: OutBoxFeedbackMsg) -> OutBoxFeedbackMsg {
+        let anchored = self.anchor_offset_ns.is_some();
+        let clock = self.outboxes.entry(from).or_default();
+        let offset_ns = match clock.offset_ns {
+            Some(o) => {
+                if msg.epoch_bytes < clock.last_bytes {
+                    // the outbox restarted
+                    clock.base_bytes = clock.bytes();
+                    clock.start_bytes = 0;
+                }
+
+                o
+            }
+            None => {
+                if anchored {
+                    // only count what it receives from now on
+                    clock.start_bytes = msg.epoch_bytes;
+                }
+
+                *clock.offset_ns.get_or_insert(now.wrapping_sub(msg.epoch_time) as i64)
+            }
+        };
+
+        clock.last_bytes = msg.epoch_bytes;
+        let anchor_offset_ns = *self.anchor_offset_ns.get_or_insert(offset_ns);
+
+        msg.epoch_bytes = self.outboxes.values().map(OutboxClock::bytes).sum();
+        msg.epoch_time = msg.epoch_time.wrapping_add(offset_ns.wrapping_sub(anchor_offset_ns) as u64);
+        msg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReceiveView;
+    use crate::serialize::OutBoxFeedbackMsg;
+
+    #[test]
+    fn check_receive_view() {
+        let a = ([10, 0, 0, 1], 28317).into();
+        let b = ([10, 0, 0, 2], 28317).into();
+        let fb = |seq, epoch_bytes, epoch_time| OutBoxFeedbackMsg {
+            bundle_id: 42,
+            seq,
+            marked_packet_hash: 0,
+            epoch_bytes,
+            epoch_time,
+            stats: None,
+        };
+        let mut v = ReceiveView::default();
+        let merge = |v: &mut ReceiveView, from, now, msg: OutBoxFeedbackMsg| {
+            v.check_seq(from, msg.seq);
+            let m = v.merge(from, now, msg);
+            (m.epoch_bytes, m.epoch_time)
+        };
+
+        // a alone is taken as it is
+        assert_eq!(merge(&mut v, a, 1_000_000, fb(0, 5_000, 400_000)), (5_000, 400_000));
+        assert_eq!(merge(&mut v, a, 2_000_000, fb(1, 8_000, 1_400_000)), (8_000, 1_400_000));
+
+        // b's clock runs 10ms ahead of a's, and it counts from its first report
+        assert_eq!(merge(&mut v, b, 3_000_000, fb(0, 90_000, 12_400_000)), (8_000, 2_400_000));
+        assert_eq!(merge(&mut v, b, 4_000_000, fb(1, 91_000, 13_400_000)), (9_000, 3_400_000));
+        assert_eq!(merge(&mut v, a, 5_000_000, fb(2, 10_000, 4_400_000)), (11_000, 4_400_000));
+
+        // b's byte clock starts over
+        assert_eq!(merge(&mut v, b, 6_000_000, fb(2, 500, 15_400_000)), (11_500, 5_400_000));
+        assert_eq!(v.outboxes(), 2);
+
+        // a skips a feedback message
+        merge(&mut v, a, 7_000_000, fb(4, 12_000, 6_400_000));
+        assert_eq!(v.lost(), 1);
+    }
+}
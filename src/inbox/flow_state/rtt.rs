@@ -0,0 +1,87 @@
+use crate::inbox::datapath::RttEstimate;
+use std::collections::VecDeque;
+
+/// How long a sample can stay the minimum RTT, as in BBR.
+const MIN_RTT_WINDOW_NS: u64 = 10_000_000_000;
+
+/// Smooths RTT samples per RFC 6298 and keeps their windowed minimum.
+#[derive(Default)]
+pub struct RttEstimator {
+    estimate: Option<RttEstimate>,
+    // (time, sample) of the samples which may yet become the minimum, increasing in both
+    min_candidates: VecDeque<(u64, u64)>,
+}
+
+impl RttEstimator {
+    pub fn on_sample(&mut self, now: u64, rtt_ns: u64) -> RttEstimate {
+        let mut e = match self.estimate {
+            Some(prev) => {
+                let delta = std::cmp::max(prev.srtt_ns, rtt_ns) - std::cmp::min(prev.srtt_ns, rtt_ns);
+                // RTTVAR <- (1 - beta) * RTTVAR + beta * |SRTT - R'|, beta = 1/4
+                // SRTT <- (1 - alpha) * SRTT + alpha * R', alpha = 1/8
+                RttEstimate {
+                    latest_ns: rtt_ns,
+                    srtt_ns: (7 * prev.srtt_ns + rtt_ns) / 8,
+                    rttvar_ns: (3 * prev.rttvar_ns + delta) / 4,
+                    min_rtt_ns: 0,
+                }
+            }
+            None => RttEstimate {
+                latest_ns: rtt_ns,
+                srtt_ns: rtt_ns,
+                rttvar_ns: rtt_ns / 2,
+                min_rtt_ns: 0,
+            },
+        };
+
+        // a sample is never the minimum again once a lower one came after it
+        while let Some(&(_, r)) = self.min_candidates.back() {
+            if r < rtt_ns {
+                break;
+            }
+
+            self.min_candidates.pop_back();
+        }
+
+        self.min_candidates.push_back((now, rtt_ns));
+        while let Some(&(t, _)) = self.min_candidates.front() {
+            if now.saturating_sub(t) <= MIN_RTT_WINDOW_NS {
+                break;
+            }
+
+            self.min_candidates.pop_front();
+        }
+        e.min_rtt_ns = self.min_candidates.front().map_or(rtt_ns, |&(_, r)| r);
+
+        self.estimate = Some(e);
+        e
+    }
+
+    /// All zeros until the first sample.
+    pub fn estimate(&self) -> RttEstimate {
+        self.estimate.unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RttEstimator, MIN_RTT_WINDOW_NS};
+
+    #[test]
+    fn check_rtt_estimator() {
+        let mut r = RttEstimator::default();
+        let e = r.on_sample(0, 80_000);
+        assert_eq!((e.srtt_ns, e.rttvar_ns, e.min_rtt_ns), (80_000, 40_000, 80_000));
+
+        let e = r.on_sample(1_000, 160_000);
+        assert_eq!((e.srtt_ns, e.rttvar_ns, e.min_rtt_ns), (90_000, 50_000, 80_000));
+        assert_eq!(e.latest_ns, 160_000);
+
+        // the route changed: the old minimum expires
+        r.on_sample(2_000, 120_000);
+        let e = r.on_sample(MIN_RTT_WINDOW_NS + 1_500, 200_000);
+        assert_eq!(e.min_rtt_ns, 120_000);
+        let e = r.on_sample(MIN_RTT_WINDOW_NS + 3_000, 200_000);
+        assert_eq!(e.min_rtt_ns, 200_000);
+    }
+}
@@ -0,0 +1,79 @@
+/// How far backwards a sequence number may jump before we assume the outbox restarted,
+/// rather than that the feedback was reordered.
+const RESTART_THRESHOLD: i32 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeqCheck {
+    /// The next expected feedback message.
+    InOrder,
+    /// This many messages before this one were lost (or are late).
+    Gap(u32),
+    /// Older than feedback we already used.
+    Stale,
+    /// The sequence numbers started over.
+    Restarted,
+}
+
+/// Tracks the sequence numbers of an outbox's feedback messages.
+#[derive(Default)]
+pub struct FeedbackSeq {
+    next: Option<u32>,
+    pub received: u64,
+    pub lost: u64,
+    pub reordered: u64,
+}
+
+impl FeedbackSeq {
+    pub fn check(&mut self, seq: u32) -> SeqCheck {
+        self.received += 1;
+        let next = match self.next {
+            Some(n) => n,
+            None => {
+                self.next = Some(seq.wrapping_add(1));
+                return SeqCheck::InOrder;
+            }
+        };
+
+        // compare in sequence number space, so wrapping around is fine
+        let delta = seq.wrapping_sub(next) as i32;
+        if delta < -RESTART_THRESHOLD {
+            self.next = Some(seq.wrapping_add(1));
+            return SeqCheck::Restarted;
+        }
+
+        if delta < 0 {
+            // it was counted as lost when we skipped over it
+            self.lost = self.lost.saturating_sub(1);
+            self.reordered += 1;
+            return SeqCheck::Stale;
+        }
+
+        self.next = Some(seq.wrapping_add(1));
+        if delta == 0 {
+            SeqCheck::InOrder
+        } else {
+            self.lost += delta as u64;
+            SeqCheck::Gap(delta as u32)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FeedbackSeq, SeqCheck};
+
+    #[test]
+    fn check_feedback_seq() {
+        let mut s = FeedbackSeq::default();
+        assert_eq!(s.check(u32::MAX - 1), SeqCheck::InOrder);
+        assert_eq!(s.check(u32::MAX), SeqCheck::InOrder);
+        assert_eq!(s.check(2), SeqCheck::Gap(2));
+        assert_eq!(s.lost, 2);
+        assert_eq!(s.check(1), SeqCheck::Stale);
+        assert_eq!((s.lost, s.reordered), (1, 1));
+        assert_eq!(s.check(3), SeqCheck::InOrder);
+        assert_eq!(s.check(u32::MAX - 5000), SeqCheck::Restarted);
+        assert_eq!(s.check(u32::MAX - 4999), SeqCheck::InOrder);
+        assert_eq!(s.received, 7);
+    }
+}
@@ -0,0 +1,79 @@
+/// The largest frame on a 1500-byte MTU Ethernet link, until we know better.
+pub const DEFAULT_FRAME_BYTES: u32 = 1514;
+/// Smaller samples are implausible: they come from epochs with fewer packets than usual.
+const MIN_PACKET_BYTES: f64 = 64.0;
+/// Gain of the moving average. Samples are noisy, as marks are only one in every epoch length
+/// packets on average.
+const GAIN: f64 = 1.0 / 16.0;
+
+/// Estimates the mean size of the bundle's packets, as they are counted by the byte clocks.
+/// The qdisc marks one in every epoch length packets on average, so the bytes sent between two
+/// of its marks, over the epoch length, are a sample of the mean.
+pub struct PacketSize {
+    max_bytes: u32,
+    mean: f64,
+    last_byte_clock: Option<u64>,
+}
+
+impl Default for PacketSize {
+    fn default() -> Self {
+        PacketSize::new(DEFAULT_FRAME_BYTES)
+    }
+}
+
+impl PacketSize {
+    /// `max_bytes` is the largest frame the interface sends, which is where the mean starts.
+    pub fn new(max_bytes: u32) -> Self {
+        PacketSize {
+            max_bytes,
+            mean: f64::from(max_bytes),
+            last_byte_clock: None,
+        }
+    }
+
+    pub fn on_mark(&mut self, byte_clock: u64, epoch_length: u32) {
+        let last = self.last_byte_clock.replace(byte_clock);
+        let bytes = match last.and_then(|l| byte_clock.checked_sub(l)) {
+            Some(b) if epoch_length > 0 => b,
+            _ => return,
+        };
+
+        let sample = (bytes as f64 / f64::from(epoch_length))
+            .max(MIN_PACKET_BYTES)
+            .min(f64::from(self.max_bytes));
+        self.mean += GAIN * (sample - self.mean);
+    }
+
+    pub fn mean(&self) -> u32 {
+        self.mean as u32
+    }
+
+    pub fn max(&self) -> u32 {
+        self.max_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PacketSize;
+
+    #[test]
+    fn check_packet_size() {
+        let mut s = PacketSize::new(9014);
+        assert_eq!(s.mean(), 9014);
+        s.on_mark(1_000, 4);
+        assert_eq!(s.mean(), 9014);
+
+        // 1000-byte packets, then a byte clock which went backwards
+        for i in 1..200 {
+            s.on_mark(1_000 + i * 4_000, 4);
+        }
+        assert_eq!(s.mean(), 1_000);
+        s.on_mark(0, 4);
+        assert_eq!(s.mean(), 1_000);
+
+        // never more than the largest frame
+        s.on_mark(1_000_000, 4);
+        assert!(s.mean() <= 9014);
+    }
+}
@@ -7,12 +7,15 @@
 use self::datapath::qdisc::*;
 
 use self::datapath::Datapath;
-use self::flow_state::BundleFlowState;
-use self::readers::UnixMsgReader;
+use self::flow_state::{BundleFlowState, SeqCheck};
+pub use self::flow_state::RateEstimatorKind;
+use self::readers::{OutboxEvent, UnixMsgReader};
+use self::session::{LivenessConfig, Liveness, LostPolicy, Session};
 use crate::serialize::{OutBoxFeedbackMsg, QDiscFeedbackMsg};
 use crossbeam::select;
 use minion::Cancellable;
-use slog::{debug, info};
+use slog::{debug, error, info};
+use std::net::SocketAddr;
 use std::os::unix::net::UnixDatagram;
 
 #[cfg(target_os = "linux")]
@@ -23,6 +26,7 @@ mod flow_state;
 #[cfg(target_os = "linux")]
 mod nl;
 pub mod readers;
+pub mod session;
 pub mod udp;
 
 pub struct DatapathImpl {
@@ -59,10 +63,15 @@ impl libccp::DatapathOps for DatapathImpl {
 
 pub struct ConnectionImpl<Q: Datapath> {
     qdisc: Rc<RefCell<Q>>, // qdisc handle
+    paused: Rc<Cell<bool>>, // while set, the outbox is lost and its policy decides the rate
 }
 
 impl<Q: Datapath> libccp::CongestionOps for ConnectionImpl<Q> {
     fn set_cwnd(&mut self, cwnd: u32) {
+        if self.paused.get() {
+            return;
+        }
+
         let set = if cwnd == 0 { 15_000 } else { cwnd };
 
         self.qdisc
@@ -72,6 +81,10 @@ impl<Q: Datapath> libccp::CongestionOps for ConnectionImpl<Q> {
     }
 
     fn set_rate_abs(&mut self, rate: u32) {
+        if self.paused.get() {
+            return;
+        }
+
         self.qdisc
             .borrow_mut()
             .set_rate(rate)
@@ -80,26 +93,60 @@ impl<Q: Datapath> libccp::CongestionOps for ConnectionImpl<Q> {
 }
 
 use crossbeam::tick;
-use std::cell::RefCell;
+use fnv::FnvHashMap;
+use slog::o;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-pub struct Runtime<Q>
+/// Where to find a bundle's qdisc, and the outboxes to contact, if any.
+#[derive(Clone, Debug)]
+pub struct BundleConfig {
+    pub bundle_id: u32,
+    pub iface: String,
+    pub handle: (u32, u32),
+    pub outboxes: Vec<String>,
+    pub hash_spec: crate::hash::HashSpec,
+    pub rate_estimator: RateEstimatorKind,
+}
+
+/// What `Runtime::with_qdiscs` needs to set up a bundle: its id, datapath handle, session with
+/// its outboxes, if any, and how to estimate its rates.
+pub type BundleParts<Q> = (u32, Rc<RefCell<Q>>, Option<Session>, RateEstimatorKind);
+
+/// The state kept for each bundle: its measurements, its libccp connection (inside
+/// `flow_state`), its datapath handle and its session with its outboxes.
+struct Bundle<Q>
 where
     Q: Datapath + 'static,
 {
     log: slog::Logger,
-    qdisc_recv: crossbeam::Receiver<QDiscFeedbackMsg>,
-    outbox_recv: crossbeam::Receiver<OutBoxFeedbackMsg>,
     // Do not allow a reference to flow_state to escape.
     // Doing so would be unsafe, because the lifetime is
     // declared as 'static when it is actually the same
     // as the lifetime of Arc<libccp::Datapath>.
     flow_state: BundleFlowState<'static, Q>,
     qdisc: Rc<RefCell<Q>>,
-    invoke_ticker: crossbeam::Receiver<Instant>,
+    // None if feedback does not come over the network, e.g. in playback
+    session: Option<Session>,
+    paused: Rc<Cell<bool>>,
+    last_decay: Instant,
     ready_to_invoke: bool,
+}
+
+/// How often `LostPolicy::Decay` halves the rate.
+const DECAY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Runtime<Q>
+where
+    Q: Datapath + 'static,
+{
+    log: slog::Logger,
+    qdisc_recv: crossbeam::Receiver<QDiscFeedbackMsg>,
+    outbox_recv: crossbeam::Receiver<OutboxEvent>,
+    bundles: FnvHashMap<u32, Bundle<Q>>,
+    invoke_ticker: crossbeam::Receiver<Instant>,
     // Must come last. Since Drop on libccp::Datapath frees
     // libccp state, if Runtime is ever Dropped then this must
     // be dropped last.
@@ -118,56 +165,91 @@ impl Runtime<Qdisc> {
     pub fn new(
         log: slog::Logger,
         listen_port: u16,
-        outbox: Option<String>,
-        iface: String,
-        handle: (u32, u32),
+        bundles: Vec<BundleConfig>,
         use_dynamic_epoch: bool,
         sample_freq: u32,
+        psk: Option<Vec<u8>>,
+        liveness: LivenessConfig,
     ) -> Option<Self> {
+        use crate::hash::{HashSpec, MarkHasher};
+        use crate::serialize::auth::{mark_hash_key, Opener, Sealer};
         use portus::ipc;
         use portus::ipc::netlink;
 
         let nlsk = netlink::Socket::<ipc::Blocking>::new().unwrap();
-        let (qdisc_reader, qdisc_recv) = NlMsgReader::make(nlsk);
+        let (qdisc_reader, qdisc_recv) = NlMsgReader::make(log.clone(), nlsk);
         let _qdisc_recv_handle = qdisc_reader.spawn();
 
-        let (outbox_found_tx, outbox_found_rx) = std::sync::mpsc::channel();
-        if let Some(to) = outbox {
-            use std::net::ToSocketAddrs;
-            outbox_found_tx
-                .send(to.to_socket_addrs().unwrap().next().unwrap())
-                .unwrap_or_else(|_| ());
-        }
-
-        let udpsk = udp::Socket::new(listen_port, outbox_found_tx).unwrap();
-        // udp socket for sending *to* outbox
-        let outbox_report = udpsk.try_clone();
-
-        let (outbox_reader, outbox_recv) = self::readers::UdpMsgReader::make(udpsk);
-        let _outbox_recv_handle = outbox_reader.spawn();
-
-        let mut qdisc = Qdisc::bind(
+        let udpsk = udp::Socket::new(
             log.clone(),
-            iface,
-            handle,
-            use_dynamic_epoch,
-            outbox_found_rx,
-            outbox_report,
+            listen_port,
+            psk.as_ref().map(|k| Opener::new(k)),
         )
-        .ok()?;
+        .unwrap();
+        let sealer = psk.as_ref().map(|k| Sealer::new(k));
+
+        let qdiscs = bundles
+            .into_iter()
+            .map(|b| {
+                let log = log.new(o!("bundle" => b.bundle_id));
+                let outboxes = b
+                    .outboxes
+                    .iter()
+                    .map(|to| {
+                        use std::net::ToSocketAddrs;
+                        to.to_socket_addrs().unwrap().next().unwrap()
+                    })
+                    .collect();
+                // udp socket for sending *to* outboxes
+                let session = Session::new(
+                    log.clone(),
+                    b.bundle_id,
+                    udpsk.try_clone(),
+                    sealer.clone(),
+                    outboxes,
+                    liveness,
+                    b.hash_spec,
+                );
+
+                // the outboxes derive the same key once they learn our session id
+                let mut hasher = MarkHasher::new(b.hash_spec);
+                if b.hash_spec.contains(HashSpec::KEYED) {
+                    match psk {
+                        Some(ref k) => hasher.key = Some(mark_hash_key(k, session.id())),
+                        None => {
+                            error!(log, "keyed mark hashing needs a pre-shared key");
+                            return None;
+                        }
+                    }
+                }
 
-        qdisc.set_epoch_length(sample_freq).unwrap_or_else(|_| ());
+                let mut qdisc = Qdisc::bind(
+                    log,
+                    b.bundle_id,
+                    b.iface,
+                    b.handle,
+                    use_dynamic_epoch,
+                    hasher,
+                )
+                .ok()?;
+                qdisc.set_epoch_length(sample_freq).unwrap_or_else(|_| ());
+
+                Some((b.bundle_id, Rc::new(RefCell::new(qdisc)), Some(session), b.rate_estimator))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let (outbox_reader, outbox_recv) = self::readers::UdpMsgReader::make(log.clone(), udpsk);
+        let _outbox_recv_handle = outbox_reader.spawn();
 
-        let qdisc = Rc::new(RefCell::new(qdisc));
-        Runtime::with_qdisc(qdisc, qdisc_recv, outbox_recv, log)
+        Runtime::with_qdiscs(qdiscs, qdisc_recv, outbox_recv, log)
     }
 }
 
 impl<Q: Datapath> Runtime<Q> {
-    pub fn with_qdisc(
-        qdisc: Rc<RefCell<Q>>,
+    pub fn with_qdiscs(
+        qdiscs: Vec<BundleParts<Q>>,
         qdisc_recv: crossbeam::Receiver<QDiscFeedbackMsg>,
-        outbox_recv: crossbeam::Receiver<OutBoxFeedbackMsg>,
+        outbox_recv: crossbeam::Receiver<OutboxEvent>,
         log: slog::Logger,
     ) -> Option<Self> {
         // unix socket for sending *to* portus
@@ -188,49 +270,312 @@ impl<Q: Datapath> Runtime<Q> {
         info!(log, "Wait for CCP to install datapath program");
         alg_ready.recv().unwrap();
 
-        info!(log, "Initialize bundle flow in libccp");
-        // TODO this is a hack, we are pretending there is only one bundle/flow
-        let dp_info = libccp::FlowInfo::default()
-            .with_init_cwnd(15_000)
-            .with_mss(1514)
-            .with_four_tuple(0, 0, 0, 0);
-
-        // Why the mem::transmute you ask?
-        // This is necessary because the correct lifetime is *self-referential*.
-        // It is safe in this case because:
-        // (1) libccp::Datapath::init(/*..*/) is inside an Arc, and at least one copy of that Arc is inside Runtime
-        // (2) this libccp::Connection is inside BundleFlowState, which is also inside Runtime.
-        // (3) Therefore, libccp::Connection is valid for the lifetime of Runtime, which is
-        // effectively 'static.
-        let conn = libccp::Connection::start(
-            unsafe { std::mem::transmute(dp.as_ref()) },
-            ConnectionImpl {
-                qdisc: qdisc.clone(),
-            },
-            dp_info,
-        )
-        .unwrap();
-
-        let mut fs: BundleFlowState<Q> = Default::default();
-        fs.conn = Some(conn);
-        fs.epoch_history.window = 1;
+        let mut bundles = FnvHashMap::default();
+        for (bundle_id, qdisc, session, rate_estimator) in qdiscs {
+            let log = log.new(o!("bundle" => bundle_id));
+            info!(log, "Initialize bundle flow in libccp"; "rate_estimator" => %rate_estimator);
+            // each bundle is a single flow as far as libccp is concerned
+            // packets are counted as whole frames, so that is what libccp converts bytes with
+            let max_packet_bytes = qdisc.borrow().get_mtu() + crate::MAC_HEADER_LENGTH as u32;
+            let dp_info = libccp::FlowInfo::default()
+                .with_init_cwnd(15_000)
+                .with_mss(max_packet_bytes)
+                .with_four_tuple(0, 0, 0, 0);
+
+            // Why the mem::transmute you ask?
+            // This is necessary because the correct lifetime is *self-referential*.
+            // It is safe in this case because:
+            // (1) libccp::Datapath::init(/*..*/) is inside an Arc, and at least one copy of that Arc is inside Runtime
+            // (2) this libccp::Connection is inside BundleFlowState, which is also inside Runtime.
+            // (3) Therefore, libccp::Connection is valid for the lifetime of Runtime, which is
+            // effectively 'static.
+            let paused = Rc::new(Cell::new(false));
+            let conn = libccp::Connection::start(
+                unsafe { std::mem::transmute(dp.as_ref()) },
+                ConnectionImpl {
+                    qdisc: qdisc.clone(),
+                    paused: paused.clone(),
+                },
+                dp_info,
+            )
+            .unwrap();
+
+            let mut fs: BundleFlowState<Q> = BundleFlowState::new(rate_estimator, max_packet_bytes);
+            fs.conn = Some(conn);
+            fs.epoch_history.window = 1;
+
+            bundles.insert(
+                bundle_id,
+                Bundle {
+                    log,
+                    flow_state: fs,
+                    qdisc,
+                    session,
+                    paused,
+                    last_decay: Instant::now(),
+                    ready_to_invoke: false,
+                },
+            );
+        }
 
         let invoke_ticker = tick(Duration::from_millis(10));
 
-        info!(log, "Inbox ready");
+        info!(log, "Inbox ready"; "bundles" => bundles.len());
         Some(Runtime {
             log,
             qdisc_recv,
             outbox_recv,
-            flow_state: fs,
-            qdisc,
+            bundles,
             invoke_ticker,
-            ready_to_invoke: false,
             datapath: dp,
         })
     }
 }
 
+impl<Q: Datapath> Bundle<Q> {
+    fn got_qdisc_feedback(&mut self, msg: QDiscFeedbackMsg) {
+        // remember the marked packet's send time
+        // so we can get its RTT later
+        // TODO -- this might need to get the current time instead of using the
+        // kernel's
+        debug!(self.log, "inbox epoch";
+            "time" => msg.epoch_time,
+            "hash" => msg.marked_packet_hash,
+            "bytes" => msg.epoch_bytes,
+            "curr_qlen" => msg.curr_qlen,
+        );
+
+        self.flow_state.marked_packets.insert(msg.marked_packet_hash, msg.epoch_time, msg.epoch_bytes);
+        let epoch_length = self.qdisc.borrow().get_curr_epoch_length();
+        self.flow_state.packet_size.on_mark(msg.epoch_bytes, epoch_length);
+        let expired = self.flow_state.marked_packets.expire(msg.epoch_time, self.flow_state.rtt.estimate().srtt_ns);
+        if expired > 0 {
+            debug!(self.log, "marks expired";
+                "expired" => expired,
+                "total_expired" => self.flow_state.marked_packets.expired,
+                "outstanding" => self.flow_state.marked_packets.outstanding(),
+            );
+        }
+        let enforced_rate = self.qdisc.borrow().get_curr_rate();
+        self.flow_state.got_qlen(msg.curr_qlen, enforced_rate);
+    }
+
+    fn got_outbox_event(&mut self, ev: OutboxEvent) {
+        let epoch_length = self.qdisc.borrow().get_curr_epoch_length();
+        match ev {
+            OutboxEvent::Session(from, msg) => {
+                let new = match self.session {
+                    Some(ref mut session) => session.got_session_msg(from, &msg, epoch_length),
+                    None => return,
+                };
+
+                if new {
+                    self.flow_state.reset();
+                    self.ready_to_invoke = false;
+                }
+            }
+            OutboxEvent::Heartbeat(from, msg) => {
+                if let Some(ref mut session) = self.session {
+                    session.got_heartbeat(from, &msg, epoch_length);
+                }
+            }
+            OutboxEvent::Feedback(from, msg, held_ns) => {
+                if let Some(ref mut session) = self.session {
+                    if !session.is_peer(from) {
+                        session.got_unknown_sender(from, epoch_length);
+                        return;
+                    }
+
+                    session.heard(from);
+                }
+
+                if self.paused.get() {
+                    // do not measure until the session is alive again
+                    return;
+                }
+
+                self.got_outbox_feedback(from, msg, held_ns);
+                self.report_epoch_length();
+            }
+        }
+    }
+
+    /// Keep the session going, and apply the lost policy if the outboxes went quiet.
+    fn tick(&mut self) {
+        let epoch_length = self.qdisc.borrow().get_curr_epoch_length();
+        let (liveness, policy) = match self.session {
+            Some(ref mut session) => (session.tick(epoch_length), session.on_lost()),
+            None => return,
+        };
+
+        match liveness {
+            Liveness::Lost => self.outbox_lost(policy),
+            _ if self.paused.get() => {
+                info!(self.log, "resuming congestion control");
+                self.paused.set(false);
+            }
+            _ => (),
+        }
+    }
+
+    fn outbox_lost(&mut self, policy: LostPolicy) {
+        // measurements are stale: stop invoking, and keep libccp from changing the rate
+        let first = !self.paused.replace(true);
+        self.ready_to_invoke = false;
+        if first {
+            self.last_decay = Instant::now();
+        }
+
+        let mut q = self.qdisc.borrow_mut();
+        match policy {
+            LostPolicy::Hold => (),
+            LostPolicy::Remove if first => q.remove_shaping().unwrap_or(()),
+            LostPolicy::Remove => (),
+            LostPolicy::Decay { floor } => {
+                if self.last_decay.elapsed() < DECAY_INTERVAL {
+                    return;
+                }
+
+                self.last_decay = Instant::now();
+                if let Some(rate) = q.get_curr_rate() {
+                    let decayed = std::cmp::max(rate / 2, floor);
+                    if decayed < rate {
+                        debug!(self.log, "decay rate"; "rate" => decayed);
+                        q.set_rate(decayed).unwrap_or(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Measurements may have changed the epoch length; let the outboxes know.
+    fn report_epoch_length(&mut self) {
+        let epoch_length = self.qdisc.borrow().get_curr_epoch_length();
+        if let Some(ref mut session) = self.session {
+            session.report_epoch_length(epoch_length);
+        }
+    }
+
+    fn got_outbox_feedback(&mut self, from: SocketAddr, msg: OutBoxFeedbackMsg, held_ns: u64) {
+        match self.flow_state.recv.check_seq(from, msg.seq) {
+            SeqCheck::InOrder => (),
+            SeqCheck::Gap(n) => {
+                debug!(self.log, "lost outbox feedback";
+                    "outbox" => %from,
+                    "seq" => msg.seq,
+                    "missing" => n,
+                    "lost" => self.flow_state.recv.lost(),
+                );
+            }
+            SeqCheck::Stale => {
+                // the marks this would match were drained by newer feedback
+                debug!(self.log, "discarding stale outbox feedback";
+                    "outbox" => %from,
+                    "seq" => msg.seq,
+                    "reordered" => self.flow_state.recv.reordered(),
+                );
+                return;
+            }
+            SeqCheck::Restarted => {
+                info!(self.log, "outbox feedback sequence restarted"; "outbox" => %from, "seq" => msg.seq);
+            }
+        }
+
+        // check packet marking
+        // feedback sent as soon as its mark was captured would have arrived this much earlier
+        let now = time::precise_time_ns().saturating_sub(held_ns);
+        let msg = self.flow_state.recv.merge(from, now, msg);
+        // feedback from several outboxes need not come back in the order the marks were sent
+        let outboxes = std::cmp::max(
+            self.flow_state.recv.outboxes(),
+            self.session.as_ref().map_or(0, Session::outboxes),
+        );
+        self.flow_state.marked_packets.unordered = outboxes > 1;
+        let epoch_length = self.qdisc.borrow().get_curr_epoch_length();
+        let mark = self.flow_state.marked_packets.get(now, msg.marked_packet_hash, msg.epoch_bytes, epoch_length);
+        if let Some(mi) = mark {
+            let h = msg.marked_packet_hash;
+            self.flow_state.update_measurements(now, mi, msg, &self.log);
+            let rtt = self.flow_state.rtt.estimate();
+            {
+                let mut q = self.qdisc.borrow_mut();
+                q.update_rtt(rtt).unwrap_or_else(|_| ());
+                q.update_send_rate(self.flow_state.send_rate as u64, self.flow_state.packet_size.mean());
+            }
+
+            info!(self.log, "new measurements";
+                "now" => now,
+                "hash" => h,
+                "rtt" => rtt.latest_ns / 1_000,
+                "srtt" => rtt.srtt_ns / 1_000,
+                "rttvar" => rtt.rttvar_ns / 1_000,
+                "min_rtt" => rtt.min_rtt_ns / 1_000,
+                "network_qdelay" => self.flow_state.network_qdelay_ns / 1_000,
+                "inbox_qdelay" => self.flow_state.inbox_qdelay_ns / 1_000,
+                "mean_packet_bytes" => self.flow_state.packet_size.mean(),
+                "rate_outgoing" => self.flow_state.send_rate as u64,
+                "rate_incoming" => self.flow_state.recv_rate as u64,
+                "loss_rate" => self.flow_state.loss.rate,
+                "feedback_lost" => self.flow_state.recv.lost(),
+                "feedback_reordered" => self.flow_state.recv.reordered(),
+                "marks_implausible" => self.flow_state.marked_packets.implausible,
+                "marks_ambiguous" => self.flow_state.marked_packets.ambiguous,
+                "marks_expired" => self.flow_state.marked_packets.expired,
+                "marks_skipped" => self.flow_state.marked_packets.skipped,
+            );
+
+            self.ready_to_invoke = true;
+        } else {
+            debug!(self.log, "no match";
+                "hash" => msg.marked_packet_hash,
+                "implausible" => self.flow_state.marked_packets.implausible,
+                "ambiguous" => self.flow_state.marked_packets.ambiguous,
+            );
+        }
+    }
+
+    fn invoke(&mut self, datapath: &libccp::Datapath) {
+        let conn = self.flow_state.conn.as_mut().unwrap();
+
+        let prims = conn.primitives(datapath);
+        let rtt = self.flow_state.rtt.estimate();
+        info!(self.log, "CCP Invoke";
+              "rtt" => prims.0.rtt_sample_us,
+              "srtt" => rtt.srtt_ns / 1_000,
+              "rttvar" => rtt.rttvar_ns / 1_000,
+              "min_rtt" => rtt.min_rtt_ns / 1_000,
+              "network_qdelay" => self.flow_state.network_qdelay_ns / 1_000,
+              "inbox_qdelay" => self.flow_state.inbox_qdelay_ns / 1_000,
+              "bytes_pending" => prims.0.bytes_pending,
+              "rate_outgoing" => prims.0.rate_outgoing,
+              "rate_incoming" => prims.0.rate_incoming,
+              "acked" => prims.0.packets_acked,
+              "lost_pkts_sample" => prims.0.lost_pkts_sample,
+        );
+
+        // ccp_invoke
+        conn.invoke().unwrap_or_else(|_| ());
+
+        // reset measurements
+        self.flow_state.did_invoke();
+
+        // after ccp_invoke, qdisc might have changed epoch_length
+        // due to new rate being set.
+        // accordingly update the measurement epoch window
+        let epoch_length = {
+            self.qdisc.borrow().get_curr_epoch_length()
+        };
+        self.report_epoch_length();
+
+        let rtt_sec = self.flow_state.rtt.estimate().srtt_ns as f64 / 1e9;
+        let inflight_bdp = self.flow_state.send_rate * rtt_sec / f64::from(self.flow_state.packet_size.mean());
+        let inflight_bdp_rounded = crate::round_down_power_of_2(inflight_bdp as u32);
+
+        let window = inflight_bdp_rounded / epoch_length;
+        self.flow_state.epoch_history.window = std::cmp::max(1, window as usize);
+    }
+}
+
 impl<Q: Datapath> minion::Cancellable for Runtime<Q> {
     type Error = portus::Error;
 
@@ -238,82 +583,30 @@ impl<Q: Datapath> minion::Cancellable for Runtime<Q> {
         select! {
             recv(self.qdisc_recv) -> msg => {
                 if let Ok(msg) = msg {
-                    // remember the marked packet's send time
-                    // so we can get its RTT later
-                    // TODO -- this might need to get the current time instead of using the
-                    // kernel's
-                    debug!(self.log, "inbox epoch";
-                        "time" => msg.epoch_time,
-                        "hash" => msg.marked_packet_hash,
-                        "bytes" => msg.epoch_bytes,
-                        "curr_qlen" => msg.curr_qlen,
-                    );
-
-                    self.flow_state.marked_packets.insert(msg.marked_packet_hash, msg.epoch_time, msg.epoch_bytes);
-                    self.flow_state.curr_qlen = msg.curr_qlen;
+                    match self.bundles.get_mut(&msg.bundle_id) {
+                        Some(b) => b.got_qdisc_feedback(msg),
+                        None => debug!(self.log, "qdisc feedback for unknown bundle";
+                            "bundle" => msg.bundle_id,
+                        ),
+                    }
                 }
             },
-            recv(self.outbox_recv) -> msg => {
-                if let Ok(msg) = msg {
-                    // check packet marking
-                    let now = time::precise_time_ns();
-                    if let Some(mi) = self.flow_state.marked_packets.get(now, msg.marked_packet_hash) {
-                        let h = msg.marked_packet_hash;
-                        self.flow_state.update_measurements(now, mi, msg, &self.log);
-                        {
-                            let mut q = self.qdisc.borrow_mut();
-                            q.update_rtt(self.flow_state.rtt_estimate).unwrap_or_else(|_| ());
-                            q.update_send_rate(self.flow_state.send_rate as u64);
-                        }
-
-                        info!(self.log, "new measurements";
-                            "now" => now,
-                            "hash" => h,
-                            "rtt" => self.flow_state.rtt_estimate / 1_000,
-                            "rate_outgoing" => self.flow_state.send_rate as u64,
-                            "rate_incoming" => self.flow_state.recv_rate as u64,
-                        );
-
-                        self.ready_to_invoke = true;
-                    } else {
-                        debug!(self.log, "no match";
-                            "hash" => msg.marked_packet_hash,
-                        );
+            recv(self.outbox_recv) -> ev => {
+                if let Ok(ev) = ev {
+                    match self.bundles.get_mut(&ev.bundle_id()) {
+                        Some(b) => b.got_outbox_event(ev),
+                        None => debug!(self.log, "outbox message for unknown bundle";
+                            "bundle" => ev.bundle_id(),
+                        ),
                     }
                 }
             },
             recv(self.invoke_ticker) -> _ => {
-                if self.ready_to_invoke {
-                    let conn = self.flow_state.conn.as_mut().unwrap();
-
-                    let prims = conn.primitives(&self.datapath);
-                    info!(self.log, "CCP Invoke";
-                          "rtt" => prims.0.rtt_sample_us,
-                          "rate_outgoing" => prims.0.rate_outgoing,
-                          "rate_incoming" => prims.0.rate_incoming,
-                          "acked" => prims.0.packets_acked,
-                          "lost_pkts_sample" => prims.0.lost_pkts_sample,
-                    );
-
-                    // ccp_invoke
-                    conn.invoke().unwrap_or_else(|_| ());
-
-                    // reset measurements
-                    self.flow_state.did_invoke();
-
-                    // after ccp_invoke, qdisc might have changed epoch_length
-                    // due to new rate being set.
-                    // accordingly update the measurement epoch window
-                    let epoch_length = {
-                        self.qdisc.borrow().get_curr_epoch_length()
-                    };
-
-                    let rtt_sec = self.flow_state.rtt_estimate as f64 / 1e9;
-                    let inflight_bdp = self.flow_state.send_rate * rtt_sec / 1500.0;
-                    let inflight_bdp_rounded = crate::round_down_power_of_2(inflight_bdp as u32);
-
-                    let window = inflight_bdp_rounded / epoch_length;
-                    self.flow_state.epoch_history.window = std::cmp::max(1, window as usize);
+                for b in self.bundles.values_mut() {
+                    b.tick();
+                    if b.ready_to_invoke {
+                        b.invoke(&self.datapath);
+                    }
                 }
             }
         };
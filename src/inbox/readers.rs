@@ -1,8 +1,13 @@
+use crate::drops::DropCounter;
 use crate::inbox::udp;
-use crate::serialize::OutBoxFeedbackMsg;
+use crate::serialize::{
+    DecodeError, Header, HeartbeatMsg, MsgType, OutBoxFeedbackBatchMsg, OutBoxFeedbackMsg,
+    SessionMsg,
+};
 use minion::Cancellable;
 use portus::ipc;
-use slog::warn;
+use slog::{debug, warn};
+use std::net::SocketAddr;
 use std::os::unix::net::UnixDatagram;
 
 use ipc::Ipc;
@@ -14,18 +19,21 @@ pub struct NlMsgReader(
     netlink::Socket<ipc::Blocking>,
     Vec<u8>,
     crossbeam::Sender<crate::serialize::QDiscFeedbackMsg>,
+    slog::Logger,
+    DropCounter,
 );
 
 #[cfg(target_os = "linux")]
 impl NlMsgReader {
     pub fn make(
+        logger: slog::Logger,
         nl: netlink::Socket<ipc::Blocking>,
     ) -> (
         Self,
         crossbeam::Receiver<crate::serialize::QDiscFeedbackMsg>,
     ) {
         let (send, recv) = crossbeam::unbounded();
-        let s = NlMsgReader(nl, vec![0u8; 100], send);
+        let s = NlMsgReader(nl, vec![0u8; 100], send, logger, DropCounter::new("message from qdisc"));
         (s, recv)
     }
 }
@@ -35,30 +43,93 @@ impl Cancellable for NlMsgReader {
     type Error = portus::Error;
 
     fn for_each(&mut self) -> std::result::Result<minion::LoopState, Self::Error> {
-        self.0.recv(&mut self.1[0..100])?;
-        let m = crate::serialize::QDiscFeedbackMsg::from_slice(&self.1[0..32]);
-        self.2.send(m)?;
+        let len = self.0.recv(&mut self.1[0..100])?;
+        match crate::serialize::QDiscFeedbackMsg::from_slice(&self.1[0..len]) {
+            Ok(m) => self.2.send(m)?,
+            Err(e) => {
+                debug!(self.3, "malformed message from qdisc"; "err" => %e);
+                self.4.add(&self.3, "malformed");
+            }
+        }
+
         Ok(minion::LoopState::Continue)
     }
 }
 
-pub struct UdpMsgReader(udp::Socket, Vec<u8>, crossbeam::Sender<OutBoxFeedbackMsg>);
+/// A message from an outbox, and where it came from.
+#[derive(Clone, Debug)]
+pub enum OutboxEvent {
+    /// Also carries how long, in ns, the outbox took from capturing the mark to sending feedback.
+    Feedback(SocketAddr, OutBoxFeedbackMsg, u64),
+    Session(SocketAddr, SessionMsg),
+    Heartbeat(SocketAddr, HeartbeatMsg),
+}
+
+impl OutboxEvent {
+    pub fn bundle_id(&self) -> u32 {
+        match self {
+            OutboxEvent::Feedback(_, m, _) => m.bundle_id,
+            OutboxEvent::Session(_, m) => m.bundle_id,
+            OutboxEvent::Heartbeat(_, m) => m.bundle_id,
+        }
+    }
+}
+
+pub struct UdpMsgReader(
+    udp::Socket,
+    Vec<u8>,
+    crossbeam::Sender<OutboxEvent>,
+    slog::Logger,
+    DropCounter,
+);
 
 impl UdpMsgReader {
-    pub fn make(udp: udp::Socket) -> (Self, crossbeam::Receiver<OutBoxFeedbackMsg>) {
+    pub fn make(
+        logger: slog::Logger,
+        udp: udp::Socket,
+    ) -> (Self, crossbeam::Receiver<OutboxEvent>) {
         let (send, recv) = crossbeam::unbounded();
-        let s = UdpMsgReader(udp, vec![0u8; 28], send);
+        let s = UdpMsgReader(udp, vec![0u8; 1024], send, logger, DropCounter::new("message from outbox"));
         (s, recv)
     }
 }
 
+/// The events in a datagram from the outbox at `from`.
+fn decode_outbox_msg(from: SocketAddr, buf: &[u8]) -> Result<Vec<OutboxEvent>, DecodeError> {
+    Header::from_slice(buf).and_then(|h| match h.msg_type {
+        MsgType::SessionInit | MsgType::SessionAck => {
+            SessionMsg::from_slice(buf).map(|m| vec![OutboxEvent::Session(from, m)])
+        }
+        MsgType::Heartbeat => {
+            HeartbeatMsg::from_slice(buf).map(|m| vec![OutboxEvent::Heartbeat(from, m)])
+        }
+        MsgType::OutBoxFeedbackBatch => OutBoxFeedbackBatchMsg::from_slice(buf).map(|m| {
+            m.into_msgs()
+                .into_iter()
+                .map(|(m, held)| OutboxEvent::Feedback(from, m, held))
+                .collect()
+        }),
+        _ => OutBoxFeedbackMsg::from_slice(buf).map(|m| vec![OutboxEvent::Feedback(from, m, 0)]),
+    })
+}
+
 impl Cancellable for UdpMsgReader {
     type Error = portus::Error;
 
     fn for_each(&mut self) -> std::result::Result<minion::LoopState, Self::Error> {
-        self.0.recv(&mut self.1[0..24])?;
-        let m = OutBoxFeedbackMsg::from_slice(&self.1[0..24]);
-        self.2.send(m)?;
+        let (len, from) = self.0.recv_from(&mut self.1[..])?;
+        match decode_outbox_msg(from, &self.1[0..len]) {
+            Ok(evs) => {
+                for ev in evs {
+                    self.2.send(ev)?;
+                }
+            }
+            Err(e) => {
+                debug!(self.3, "malformed message from outbox"; "from" => %from, "err" => %e);
+                self.4.add(&self.3, "malformed");
+            }
+        }
+
         Ok(minion::LoopState::Continue)
     }
 }
@@ -125,3 +196,32 @@ impl Cancellable for UnixMsgReader {
         Ok(minion::LoopState::Continue)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_outbox_msg, OutboxEvent};
+    use crate::serialize::{feedback_datagram, FeedbackRecord};
+
+    #[test]
+    fn check_held_feedback() {
+        let from = ([10, 0, 0, 1], 28317).into();
+        let record = FeedbackRecord {
+            marked_packet_hash: 7,
+            epoch_bytes: 1_500,
+            epoch_time: 1_000_000,
+            stats: None,
+        };
+        let held = |buf: Vec<u8>| match decode_outbox_msg(from, &buf).unwrap()[..] {
+            [OutboxEvent::Feedback(_, ref m, held)] => (m.seq, held),
+            ref evs => panic!("unexpected events: {:?}", evs),
+        };
+
+        // an inbox without batching takes it as sent right away
+        let buf = feedback_datagram(42, 3, vec![record.clone()], false, 3_000_000);
+        assert_eq!(held(buf), (3, 0));
+
+        // a lone record sent 2ms after its mark was captured
+        let buf = feedback_datagram(42, 4, vec![record], true, 3_000_000);
+        assert_eq!(held(buf), (4, 2_000_000));
+    }
+}
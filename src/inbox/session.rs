@@ -0,0 +1,357 @@
+//! The inbox's side of a bundle's session with its outboxes.
+//!
+//! A bundle may reach its receivers through several outboxes, e.g. redundant border routers at
+//! the same site. They all share the bundle's session id, and each answers it on its own.
+//!
+//! Until an outbox answers, we send it a `SessionMsg` init every `INIT_INTERVAL`, if we know
+//! where it is. Datagrams from any other sender are answered with an init too, so an outbox
+//! which lost its session (or a new outbox) is told who we are.
+//!
+//! Once an outbox's session is established, both sides send a heartbeat every
+//! `HEARTBEAT_INTERVAL`. If we hear nothing from an outbox for `LivenessConfig::timeout`, it is
+//! lost; the bundle is lost once all of its outboxes are.
+
+use crate::hash::HashSpec;
+use crate::serialize::auth::Sealer;
+use crate::serialize::{HeartbeatMsg, OutBoxReportMsg, SessionMsg, CAPABILITIES};
+use slog::{debug, info, warn};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+const INIT_INTERVAL: Duration = Duration::from_secs(1);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+/// Most outboxes one bundle keeps sessions with.
+const MAX_PEERS: usize = 16;
+
+/// What to do with a bundle's rate when we lose contact with all of its outboxes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LostPolicy {
+    /// Keep enforcing the last rate.
+    Hold,
+    /// Halve the rate every second, down to `floor` bytes/s.
+    Decay { floor: u32 },
+    /// Stop shaping the bundle's traffic.
+    Remove,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LivenessConfig {
+    pub timeout: Duration,
+    pub on_lost: LostPolicy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Liveness {
+    /// No session yet.
+    Connecting,
+    /// We hear from at least one outbox.
+    Alive,
+    Lost,
+}
+
+/// An outbox we have a session with.
+struct Peer {
+    addr: SocketAddr,
+    session_id: u64,
+    last_heard: Instant,
+    alive: bool,
+    reported_epoch_length: u32,
+}
+
+pub struct Session {
+    log: slog::Logger,
+    bundle_id: u32,
+    sk: UdpSocket,
+    auth: Option<Sealer>,
+    id: u64,
+    // where to send inits before the outboxes contact us
+    outboxes: Vec<SocketAddr>,
+    peers: Vec<Peer>,
+    last_init: Option<Instant>,
+    last_heartbeat: Option<Instant>,
+    liveness: Liveness,
+    liveness_config: LivenessConfig,
+    hash_spec: HashSpec,
+}
+
+impl Session {
+    pub fn new(
+        log: slog::Logger,
+        bundle_id: u32,
+        sk: UdpSocket,
+        auth: Option<Sealer>,
+        outboxes: Vec<SocketAddr>,
+        liveness_config: LivenessConfig,
+        hash_spec: HashSpec,
+    ) -> Self {
+        Session {
+            log,
+            bundle_id,
+            sk,
+            auth,
+            id: SessionMsg::new_session_id(),
+            outboxes,
+            peers: Vec::new(),
+            last_init: None,
+            last_heartbeat: None,
+            liveness: Liveness::Connecting,
+            liveness_config,
+            hash_spec,
+        }
+    }
+
+    /// Our session id, which the outboxes learn from our `SessionMsg`s.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// How many outboxes the bundle has: those we were told of, or those we have sessions with
+    /// if there are more.
+    pub fn outboxes(&self) -> usize {
+        std::cmp::max(self.outboxes.len(), self.peers.len())
+    }
+
+    /// Whether `from` is an outbox we have a session with.
+    pub fn is_peer(&self, from: SocketAddr) -> bool {
+        self.peers.iter().any(|p| p.addr == from)
+    }
+
+    pub fn on_lost(&self) -> LostPolicy {
+        self.liveness_config.on_lost
+    }
+
+    /// We got a message from `from`, an outbox we have a session with.
+    pub fn heard(&mut self, from: SocketAddr) {
+        let peer = match self.peers.iter_mut().find(|p| p.addr == from) {
+            Some(p) => p,
+            None => return,
+        };
+
+        peer.last_heard = Instant::now();
+        if !peer.alive {
+            info!(self.log, "outbox alive"; "outbox" => %from);
+            peer.alive = true;
+        }
+
+        self.liveness = Liveness::Alive;
+    }
+
+    /// Handle a heartbeat from `from`.
+    pub fn got_heartbeat(&mut self, from: SocketAddr, msg: &HeartbeatMsg, epoch_length: u32) {
+        let known = self
+            .peers
+            .iter()
+            .any(|p| p.addr == from && p.session_id == msg.session_id);
+
+        if known {
+            self.heard(from);
+        } else {
+            self.got_unknown_sender(from, epoch_length);
+        }
+    }
+
+    /// Handle a handshake message from `from`.
+    /// Returns true if this starts a new session with an outbox, in which case measurements from
+    /// before are stale.
+    pub fn got_session_msg(&mut self, from: SocketAddr, msg: &SessionMsg, epoch_length: u32) -> bool {
+        if !msg.ack {
+            self.send_session_msg(from, true, epoch_length);
+        }
+
+        // an outbox keeps its session id if its address changes
+        let new = match self.peers.iter().position(|p| p.session_id == msg.session_id) {
+            Some(i) => {
+                if self.peers[i].addr != from {
+                    info!(self.log, "outbox moved"; "from" => %self.peers[i].addr, "to" => %from);
+                    self.peers.retain(|p| p.addr != from);
+                    if let Some(p) = self.peers.iter_mut().find(|p| p.session_id == msg.session_id) {
+                        p.addr = from;
+                    }
+                }
+
+                false
+            }
+            None => {
+                let peer = Peer {
+                    addr: from,
+                    session_id: msg.session_id,
+                    last_heard: Instant::now(),
+                    alive: false,
+                    reported_epoch_length: epoch_length,
+                };
+                match self.peers.iter().position(|p| p.addr == from) {
+                    Some(i) => {
+                        info!(self.log, "outbox restarted"; "outbox" => %from);
+                        self.peers[i] = peer;
+                    }
+                    None => {
+                        if !self.add_peer(peer) {
+                            return false;
+                        }
+                    }
+                }
+
+                info!(self.log, "session established";
+                    "outbox" => %from,
+                    "session" => msg.session_id,
+                    "outbox_epoch_length" => msg.epoch_length_packets,
+                    "capabilities" => msg.capabilities & CAPABILITIES,
+                    "outboxes" => self.peers.len(),
+                );
+
+                if msg.hash_spec != self.hash_spec.bits() {
+                    warn!(self.log, "outbox hashes packets differently, marks will not match";
+                        "outbox" => %from,
+                        "ours" => %self.hash_spec,
+                        "theirs" => HashSpec::describe(msg.hash_spec),
+                    );
+                }
+
+                true
+            }
+        };
+
+        if let Some(p) = self.peers.iter_mut().find(|p| p.addr == from) {
+            p.reported_epoch_length = epoch_length;
+        }
+
+        self.heard(from);
+        new
+    }
+
+    /// Start a session with another outbox, making room by dropping the lost outbox we have not
+    /// heard from the longest, if there are too many.
+    /// Returns false if there is no room.
+    fn add_peer(&mut self, peer: Peer) -> bool {
+        if self.peers.len() >= MAX_PEERS {
+            let oldest = self
+                .peers
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| !p.alive)
+                .min_by_key(|(_, p)| p.last_heard)
+                .map(|(i, _)| i);
+            match oldest {
+                Some(i) => {
+                    let old = self.peers.remove(i);
+                    info!(self.log, "dropping lost outbox"; "outbox" => %old.addr);
+                }
+                None => {
+                    warn!(self.log, "too many outboxes, ignoring"; "outbox" => %peer.addr);
+                    return false;
+                }
+            }
+        }
+
+        self.peers.push(peer);
+        true
+    }
+
+    /// Someone we have no session with sent us a message meant for this bundle.
+    pub fn got_unknown_sender(&mut self, from: SocketAddr, epoch_length: u32) {
+        debug!(self.log, "message from unknown outbox"; "from" => %from);
+        if self.init_due() {
+            self.send_session_msg(from, false, epoch_length);
+        }
+    }
+
+    /// Called periodically: keep trying to reach the configured outboxes, send heartbeats, and
+    /// notice if outboxes went quiet.
+    pub fn tick(&mut self, epoch_length: u32) -> Liveness {
+        let timeout = self.liveness_config.timeout;
+        for p in self.peers.iter_mut().filter(|p| p.alive) {
+            if p.last_heard.elapsed() > timeout {
+                warn!(self.log, "lost contact with outbox";
+                    "outbox" => %p.addr,
+                    "silent_ms" => p.last_heard.elapsed().as_millis() as u64,
+                );
+                p.alive = false;
+            }
+        }
+
+        if self.liveness == Liveness::Alive && !self.peers.iter().any(|p| p.alive) {
+            warn!(self.log, "lost contact with all outboxes"; "policy" => ?self.liveness_config.on_lost);
+            self.liveness = Liveness::Lost;
+        }
+
+        let unanswered: Vec<SocketAddr> = self
+            .outboxes
+            .iter()
+            .filter(|&&a| !self.is_peer(a))
+            .copied()
+            .collect();
+        if !unanswered.is_empty() && self.init_due() {
+            for addr in unanswered {
+                self.send_session_msg(addr, false, epoch_length);
+            }
+        }
+
+        // keep going while outboxes are lost, so they hear from us when they come back
+        let heartbeat_due = match self.last_heartbeat {
+            Some(t) => t.elapsed() >= HEARTBEAT_INTERVAL,
+            None => true,
+        };
+        if heartbeat_due && !self.peers.is_empty() {
+            self.last_heartbeat = Some(Instant::now());
+            let msg = HeartbeatMsg {
+                bundle_id: self.bundle_id,
+                session_id: self.id,
+            };
+            for p in &self.peers {
+                self.send(p.addr, msg.as_bytes());
+            }
+        }
+
+        self.liveness
+    }
+
+    /// Tell each outbox the epoch length, if it changed since we last did.
+    pub fn report_epoch_length(&mut self, epoch_length: u32) {
+        let msg = OutBoxReportMsg {
+            bundle_id: self.bundle_id,
+            epoch_length_packets: epoch_length,
+        };
+        for i in 0..self.peers.len() {
+            if self.peers[i].reported_epoch_length == epoch_length {
+                continue;
+            }
+
+            self.send(self.peers[i].addr, msg.as_bytes());
+            self.peers[i].reported_epoch_length = epoch_length;
+        }
+    }
+
+    fn init_due(&self) -> bool {
+        match self.last_init {
+            Some(t) => t.elapsed() >= INIT_INTERVAL,
+            None => true,
+        }
+    }
+
+    fn send_session_msg(&mut self, to: SocketAddr, ack: bool, epoch_length: u32) {
+        let msg = SessionMsg {
+            bundle_id: self.bundle_id,
+            ack,
+            session_id: self.id,
+            epoch_length_packets: epoch_length,
+            capabilities: CAPABILITIES,
+            hash_spec: self.hash_spec.bits(),
+        };
+
+        if !ack {
+            self.last_init = Some(Instant::now());
+        }
+
+        self.send(to, msg.as_bytes());
+    }
+
+    fn send(&self, to: SocketAddr, mut buf: Vec<u8>) {
+        if let Some(ref sealer) = self.auth {
+            sealer.seal(&mut buf);
+        }
+
+        if let Err(e) = self.sk.send_to(&buf, to) {
+            warn!(self.log, "failed to send to outbox"; "to" => %to, "err" => ?e);
+        }
+    }
+}
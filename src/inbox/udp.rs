@@ -1,24 +1,60 @@
+use crate::drops::DropCounter;
+use crate::serialize::auth::{AuthError, Opener};
 use portus::ipc;
 use portus::Error;
-use std::net::UdpSocket;
-use std::sync::mpsc;
+use slog::debug;
+use std::cell::RefCell;
+use std::net::{SocketAddr, UdpSocket};
 
 use portus::Result;
 
 pub struct Socket {
+    logger: slog::Logger,
     sk: UdpSocket,
-    outbox_found: mpsc::Sender<std::net::SocketAddr>,
+    // if set, only datagrams authenticated with the pre-shared key are accepted
+    auth: Option<RefCell<Opener>>,
+    drops: RefCell<DropCounter>,
 }
 
 impl Socket {
-    pub fn new(port: u16, outbox_found: mpsc::Sender<std::net::SocketAddr>) -> Result<Self> {
-        let sk = UdpSocket::bind(("0.0.0.0", port))?;
-        Ok(Socket { sk, outbox_found })
+    pub fn new(
+        logger: slog::Logger,
+        port: u16,
+        auth: Option<Opener>,
+    ) -> Result<Self> {
+        let sk = crate::bind_udp(port)?;
+        Ok(Socket {
+            logger,
+            sk,
+            auth: auth.map(RefCell::new),
+            drops: RefCell::new(DropCounter::new("datagram")),
+        })
     }
 
     pub fn try_clone(&self) -> UdpSocket {
         self.sk.try_clone().unwrap()
     }
+
+    /// Receive the next datagram we accept, and who sent it.
+    pub fn recv_from(&self, msg: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        loop {
+            let (bytes, from) = self.sk.recv_from(msg).map_err(Error::from)?;
+            let from = crate::unmap_addr(from);
+            if let Some(ref opener) = self.auth {
+                if let Err(e) = opener.borrow_mut().open(from, &msg[0..bytes]) {
+                    let reason = match e {
+                        AuthError::Replayed(_) => "replayed",
+                        _ => "unauthenticated",
+                    };
+                    debug!(self.logger, "dropping datagram"; "from" => %from, "err" => %e);
+                    self.drops.borrow_mut().add(&self.logger, reason);
+                    continue;
+                }
+            }
+
+            return Ok((bytes, from));
+        }
+    }
 }
 
 impl ipc::Ipc for Socket {
@@ -31,13 +67,7 @@ impl ipc::Ipc for Socket {
     }
 
     fn recv(&self, msg: &mut [u8]) -> Result<usize> {
-        self.sk
-            .recv_from(msg)
-            .map(|(bytes, from)| {
-                self.outbox_found.send(from).unwrap_or_else(|_| ());
-                bytes
-            })
-            .map_err(Error::from)
+        self.recv_from(msg).map(|(bytes, _)| bytes)
     }
 
     fn close(&mut self) -> Result<()> {
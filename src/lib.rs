@@ -8,10 +8,12 @@ extern crate minion;
 extern crate portus;
 extern crate slog;
 
+pub mod drops;
 pub mod hash;
 pub mod inbox;
 pub mod outbox;
 pub mod serialize;
+pub mod upstream;
 
 // Header lengths
 pub const MAC_HEADER_LENGTH: usize = 14;
@@ -19,7 +21,30 @@ pub const IP_HEADER_LENGTH: usize = 20;
 // Locations in headers
 pub const PROTO_IN_IP_HEADER: usize = 9;
 // Values
+pub const IP_PROTO_ICMP: u8 = 1;
 pub const IP_PROTO_TCP: u8 = 6;
+pub const IP_PROTO_UDP: u8 = 17;
+pub const IP_PROTO_ICMPV6: u8 = 58;
+
+/// Bind a UDP socket on `port` which takes both IPv6 and IPv4 datagrams (unless the host sets
+/// `net.ipv6.bindv6only`), or only IPv4 ones if this host has no IPv6.
+pub fn bind_udp(port: u16) -> std::io::Result<std::net::UdpSocket> {
+    use std::net::{Ipv4Addr, Ipv6Addr, UdpSocket};
+    UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port))
+        .or_else(|_| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)))
+}
+
+/// A dual-stack socket reports IPv4 peers as IPv4-mapped IPv6 addresses. Turn those back into
+/// IPv4 addresses, so they compare equal to configured ones.
+pub fn unmap_addr(addr: std::net::SocketAddr) -> std::net::SocketAddr {
+    match addr {
+        std::net::SocketAddr::V6(a) => match a.ip().to_ipv4_mapped() {
+            Some(ip) => (ip, a.port()).into(),
+            None => addr,
+        },
+        a => a,
+    }
+}
 
 fn round_down_power_of_2(x: u32) -> u32 {
     let y = x.leading_zeros();
@@ -41,4 +66,14 @@ mod tests {
         assert_eq!(crate::round_down_power_of_2(1), 1);
         assert_eq!(crate::round_down_power_of_2(0), 0);
     }
+
+    #[test]
+    fn test_unmap_addr() {
+        let v4: std::net::SocketAddr = "10.0.0.1:28316".parse().unwrap();
+        let mapped: std::net::SocketAddr = "[::ffff:10.0.0.1]:28316".parse().unwrap();
+        let v6: std::net::SocketAddr = "[2001:db8::1]:28316".parse().unwrap();
+        assert_eq!(crate::unmap_addr(mapped), v4);
+        assert_eq!(crate::unmap_addr(v4), v4);
+        assert_eq!(crate::unmap_addr(v6), v6);
+    }
 }
@@ -0,0 +1,553 @@
+//! Capture straight from an `AF_PACKET` socket's memory-mapped TPACKET_V3 receive ring.
+//!
+//! The kernel fills the ring one block at a time, and hands a block over once it is full or
+//! `RingConfig::block_timeout_ms` after its first packet. We then walk every packet in the block
+//! without a syscall, so a busy link costs one `poll` per block rather than a call per packet.
+//!
+//! Packets go through the same filter, compiled by libpcap, and are cut to the same snapshot
+//! length as with `source::live`. VLAN tags the NIC stripped are put back as libpcap does, so the
+//! outbox counts the same bytes either way.
+//!
+//! Several rings in one fanout group split an interface's packets by flow, to capture on several
+//! cores with `fanout::start_fanout_outbox`.
+
+use super::source::{Next, Packet, PacketSource, SourceError, SourceStats};
+use std::io;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::sync::atomic::{fence, Ordering};
+
+// linux/if_packet.h
+const PACKET_RX_RING: c_int = 5;
+const PACKET_STATISTICS: c_int = 6;
+const PACKET_VERSION: c_int = 10;
+const PACKET_FANOUT: c_int = 18;
+const PACKET_FANOUT_HASH: u32 = 0;
+const PACKET_FANOUT_FLAG_UNIQUEID: u32 = 0x2000;
+const TPACKET_V3: c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+const TP_STATUS_VLAN_VALID: u32 = 1 << 4;
+const TP_STATUS_VLAN_TPID_VALID: u32 = 1 << 6;
+// asm-generic/socket.h
+const SO_ATTACH_FILTER: c_int = 26;
+// pcap/dlt.h and pcap/pcap.h
+const DLT_EN10MB: c_int = 1;
+const DLT_RAW: c_int = 12;
+const PCAP_NETMASK_UNKNOWN: u32 = 0xffff_ffff;
+
+const ETH_P_8021Q: u16 = 0x8100;
+const VLAN_TAG_LEN: usize = 4;
+const MAC_ADDRS_LEN: usize = 12;
+
+// where the fields we read sit in struct tpacket_block_desc and struct tpacket3_hdr
+const BLOCK_STATUS: usize = 8;
+const BLOCK_NUM_PKTS: usize = 12;
+const BLOCK_FIRST_PKT: usize = 16;
+const PKT_NEXT_OFFSET: usize = 0;
+const PKT_SEC: usize = 4;
+const PKT_NSEC: usize = 8;
+const PKT_SNAPLEN: usize = 12;
+const PKT_LEN: usize = 16;
+const PKT_STATUS: usize = 20;
+const PKT_MAC: usize = 24;
+const PKT_VLAN_TCI: usize = 32;
+const PKT_VLAN_TPID: usize = 36;
+
+/// The same as `source::live` keeps.
+const SNAPLEN: c_int = 128;
+
+/// How big a receive ring to ask the kernel for.
+#[derive(Clone, Copy, Debug)]
+pub struct RingConfig {
+    /// Bytes in each block; a multiple of the page size.
+    pub block_bytes: u32,
+    pub blocks: u32,
+    /// Hand a block over this long after its first packet, even if it is not full.
+    ///
+    /// On a quiet link, marks wait in a block for up to this long before the outbox sees them, and
+    /// the feedback for them is late by as much, which the inbox counts as RTT. Keep it small.
+    pub block_timeout_ms: u32,
+    /// How long to wait for a block before reporting `Next::Idle`.
+    pub poll_timeout_ms: i32,
+    /// Share the interface's packets with the other rings in a fanout group, each flow going to
+    /// one of them.
+    pub fanout: Option<Fanout>,
+}
+
+/// Which fanout group a ring is in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fanout {
+    /// A new group, with an id the kernel picks so it is not any other process's group.
+    New,
+    /// The group with this id, from `AfPacketRing::fanout_group` of the ring which started it.
+    Join(u16),
+}
+
+impl Default for RingConfig {
+    fn default() -> Self {
+        RingConfig {
+            block_bytes: 1 << 20,
+            blocks: 64,
+            block_timeout_ms: 1,
+            poll_timeout_ms: 100,
+            fanout: None,
+        }
+    }
+}
+
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: c_uint,
+    tp_block_nr: c_uint,
+    tp_frame_size: c_uint,
+    tp_frame_nr: c_uint,
+    tp_retire_blk_tov: c_uint,
+    tp_sizeof_priv: c_uint,
+    tp_feature_req_word: c_uint,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct TpacketStatsV3 {
+    tp_packets: c_uint,
+    tp_drops: c_uint,
+    tp_freeze_q_cnt: c_uint,
+}
+
+/// A classic BPF instruction, both `struct bpf_insn` and `struct sock_filter`.
+#[repr(C)]
+struct BpfInsn {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct BpfProgram {
+    bf_len: c_uint,
+    bf_insns: *mut BpfInsn,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *mut BpfInsn,
+}
+
+// libpcap is linked in by the pcap crate
+extern "C" {
+    fn pcap_open_dead(linktype: c_int, snaplen: c_int) -> *mut c_void;
+    fn pcap_compile(p: *mut c_void, fp: *mut BpfProgram, s: *const c_char, optimize: c_int, netmask: u32) -> c_int;
+    fn pcap_geterr(p: *mut c_void) -> *const c_char;
+    fn pcap_freecode(fp: *mut BpfProgram);
+    fn pcap_close(p: *mut c_void);
+}
+
+fn read_u16(buf: &[u8], at: usize) -> u16 {
+    let mut b = [0u8; 2];
+    b.copy_from_slice(&buf[at..at + 2]);
+    u16::from_ne_bytes(b)
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    let mut b = [0u8; 4];
+    b.copy_from_slice(&buf[at..at + 4]);
+    u32::from_ne_bytes(b)
+}
+
+fn setsockopt<T>(fd: c_int, level: c_int, name: c_int, val: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            val as *const T as *const c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn getsockopt_u32(fd: c_int, level: c_int, name: c_int) -> io::Result<u32> {
+    let mut val = 0u32;
+    let mut len = std::mem::size_of::<u32>() as libc::socklen_t;
+    let ret = unsafe { libc::getsockopt(fd, level, name, &mut val as *mut u32 as *mut c_void, &mut len) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(val)
+    }
+}
+
+/// Compile `filter` with libpcap and attach it to `fd`.
+fn attach_filter(fd: c_int, filter: &str, ethernet: bool) -> Result<(), SourceError> {
+    let filter = std::ffi::CString::new(filter)
+        .map_err(|e| SourceError::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+    let linktype = if ethernet { DLT_EN10MB } else { DLT_RAW };
+    unsafe {
+        let p = pcap_open_dead(linktype, SNAPLEN);
+        if p.is_null() {
+            return Err(pcap::Error::PcapError("pcap_open_dead failed".to_string()).into());
+        }
+
+        let mut prog = BpfProgram {
+            bf_len: 0,
+            bf_insns: std::ptr::null_mut(),
+        };
+        if pcap_compile(p, &mut prog, filter.as_ptr(), 1, PCAP_NETMASK_UNKNOWN) < 0 {
+            let err = std::ffi::CStr::from_ptr(pcap_geterr(p)).to_string_lossy().into_owned();
+            pcap_close(p);
+            return Err(pcap::Error::PcapError(err).into());
+        }
+
+        let fprog = SockFprog {
+            len: prog.bf_len as u16,
+            filter: prog.bf_insns,
+        };
+        let attached = setsockopt(fd, libc::SOL_SOCKET, SO_ATTACH_FILTER, &fprog);
+        pcap_freecode(&mut prog);
+        pcap_close(p);
+        attached.map_err(SourceError::from)
+    }
+}
+
+/// Where the next packet is in the block we are reading.
+struct BlockCursor {
+    block: usize,
+    remaining: u32,
+    offset: usize,
+}
+
+/// A TPACKET_V3 receive ring on one interface.
+pub struct AfPacketRing {
+    fd: c_int,
+    ring: *mut u8,
+    config: RingConfig,
+    ethernet: bool,
+    fanout_group: Option<u16>,
+    // the block we are reading, until we give it back
+    cursor: Option<BlockCursor>,
+    next_block: usize,
+    // a packet with its VLAN tag put back
+    scratch: Vec<u8>,
+    stats: SourceStats,
+}
+
+// The ring is only touched through `&mut self`.
+unsafe impl Send for AfPacketRing {}
+
+impl AfPacketRing {
+    /// Capture the packets on `iface` which match the pcap `filter`. If `ethernet` is not set, the
+    /// interface has no link-layer header.
+    pub fn open(iface: &str, filter: &str, ethernet: bool, config: RingConfig) -> Result<Self, SourceError> {
+        let ifname = std::ffi::CString::new(iface)
+            .map_err(|e| SourceError::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+        let ifindex = unsafe { libc::if_nametoindex(ifname.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        // no protocol until we bind, so nothing arrives before the filter is in place
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut ring = AfPacketRing {
+            fd,
+            ring: std::ptr::null_mut(),
+            config,
+            ethernet,
+            fanout_group: None,
+            cursor: None,
+            next_block: 0,
+            scratch: Vec::with_capacity(SNAPLEN as usize + VLAN_TAG_LEN),
+            stats: Default::default(),
+        };
+
+        setsockopt(fd, libc::SOL_PACKET, PACKET_VERSION, &TPACKET_V3)?;
+        attach_filter(fd, filter, ethernet)?;
+
+        let req = TpacketReq3 {
+            tp_block_size: config.block_bytes,
+            tp_block_nr: config.blocks,
+            // frames only matter to the kernel's sanity checks in V3
+            tp_frame_size: 2048,
+            tp_frame_nr: config.block_bytes / 2048 * config.blocks,
+            tp_retire_blk_tov: config.block_timeout_ms,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        setsockopt(fd, libc::SOL_PACKET, PACKET_RX_RING, &req)?;
+
+        let len = config.block_bytes as usize * config.blocks as usize;
+        let mem = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if mem == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        ring.ring = mem as *mut u8;
+
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+        addr.sll_ifindex = ifindex as c_int;
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        // only a bound socket can join a group
+        let arg = match config.fanout {
+            Some(Fanout::New) => Some((PACKET_FANOUT_HASH | PACKET_FANOUT_FLAG_UNIQUEID) << 16),
+            Some(Fanout::Join(group)) => Some(u32::from(group) | PACKET_FANOUT_HASH << 16),
+            None => None,
+        };
+        if let Some(arg) = arg {
+            setsockopt(fd, libc::SOL_PACKET, PACKET_FANOUT, &arg)?;
+            // the group id is in the low bits, above it are the type and flags
+            ring.fanout_group = Some(getsockopt_u32(fd, libc::SOL_PACKET, PACKET_FANOUT)? as u16);
+        }
+
+        Ok(ring)
+    }
+
+    /// The id of the fanout group the ring is in, for other rings to join.
+    pub fn fanout_group(&self) -> Option<u16> {
+        self.fanout_group
+    }
+
+    fn block(&self, i: usize) -> &[u8] {
+        let size = self.config.block_bytes as usize;
+        unsafe { std::slice::from_raw_parts(self.ring.add(i * size), size) }
+    }
+
+    fn block_status(&self, i: usize) -> u32 {
+        let size = self.config.block_bytes as usize;
+        unsafe { std::ptr::read_volatile(self.ring.add(i * size + BLOCK_STATUS) as *const u32) }
+    }
+
+    fn release_block(&mut self, i: usize) {
+        let size = self.config.block_bytes as usize;
+        // we are done reading before the kernel may write again
+        fence(Ordering::Release);
+        unsafe {
+            std::ptr::write_volatile(self.ring.add(i * size + BLOCK_STATUS) as *mut u32, TP_STATUS_KERNEL);
+        }
+    }
+
+    /// Wait up to `poll_timeout_ms` for the next block. Returns whether it is ours.
+    fn wait_for_block(&mut self) -> Result<bool, SourceError> {
+        let i = self.next_block;
+        if self.block_status(i) & TP_STATUS_USER == 0 {
+            let mut pfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN | libc::POLLERR,
+                revents: 0,
+            };
+            let ret = unsafe { libc::poll(&mut pfd, 1, self.config.poll_timeout_ms) };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    return Ok(false);
+                }
+
+                return Err(e.into());
+            }
+
+            if self.block_status(i) & TP_STATUS_USER == 0 {
+                return Ok(false);
+            }
+        }
+
+        // read the block only after seeing it handed over
+        fence(Ordering::Acquire);
+        let block = self.block(i);
+        self.cursor = Some(BlockCursor {
+            block: i,
+            remaining: read_u32(block, BLOCK_NUM_PKTS),
+            offset: read_u32(block, BLOCK_FIRST_PKT) as usize,
+        });
+        self.next_block = (i + 1) % self.config.blocks as usize;
+        Ok(true)
+    }
+}
+
+/// Read the packet at `offset` in `block`: its timestamp, wire length, where the next packet
+/// starts, and its bytes. On Ethernet, a VLAN tag the NIC stripped is put back, in `scratch`.
+fn read_packet<'a>(block: &'a [u8], offset: usize, ethernet: bool, scratch: &'a mut Vec<u8>) -> (Packet<'a>, usize) {
+    let hdr = &block[offset..];
+    let next = read_u32(hdr, PKT_NEXT_OFFSET) as usize;
+    let ts_ns = u64::from(read_u32(hdr, PKT_SEC)) * 1_000_000_000 + u64::from(read_u32(hdr, PKT_NSEC));
+    let snaplen = read_u32(hdr, PKT_SNAPLEN) as usize;
+    let mut wire_len = read_u32(hdr, PKT_LEN);
+    let status = read_u32(hdr, PKT_STATUS);
+    let mac = read_u16(hdr, PKT_MAC) as usize;
+    let mut data = &hdr[mac..mac + snaplen];
+
+    let vlan_tci = read_u32(hdr, PKT_VLAN_TCI);
+    // older kernels only say there was a tag by it not being zero
+    let tagged = status & TP_STATUS_VLAN_VALID != 0 || vlan_tci != 0;
+    if ethernet && tagged && data.len() >= MAC_ADDRS_LEN {
+        let tpid = if status & TP_STATUS_VLAN_TPID_VALID != 0 {
+            read_u16(hdr, PKT_VLAN_TPID)
+        } else {
+            ETH_P_8021Q
+        };
+
+        scratch.clear();
+        scratch.extend_from_slice(&data[..MAC_ADDRS_LEN]);
+        scratch.extend_from_slice(&tpid.to_be_bytes());
+        scratch.extend_from_slice(&(vlan_tci as u16).to_be_bytes());
+        scratch.extend_from_slice(&data[MAC_ADDRS_LEN..]);
+        scratch.truncate(SNAPLEN as usize);
+        data = &scratch[..];
+        wire_len += VLAN_TAG_LEN as u32;
+    }
+
+    (Packet { ts_ns, wire_len, data }, next)
+}
+
+impl PacketSource for AfPacketRing {
+    fn next_packet(&mut self) -> Result<Next<'_>, SourceError> {
+        // the caller is done with the last packet, so we can give its block back if it was the last
+        if let Some(BlockCursor { block, remaining: 0, .. }) = self.cursor {
+            self.release_block(block);
+            self.cursor = None;
+        }
+
+        if self.cursor.is_none() {
+            if !self.wait_for_block()? {
+                return Ok(Next::Idle);
+            }
+
+            if let Some(BlockCursor { block, remaining: 0, .. }) = self.cursor {
+                self.release_block(block);
+                self.cursor = None;
+                return Ok(Next::Idle);
+            }
+        }
+
+        let (block, offset) = match self.cursor {
+            Some(ref mut c) => {
+                c.remaining = c.remaining.saturating_sub(1);
+                (c.block, c.offset)
+            }
+            None => return Ok(Next::Idle),
+        };
+
+        let size = self.config.block_bytes as usize;
+        let block = unsafe { std::slice::from_raw_parts(self.ring.add(block * size), size) };
+        let (pkt, next) = read_packet(block, offset, self.ethernet, &mut self.scratch);
+        if let Some(ref mut c) = self.cursor {
+            c.offset += next;
+        }
+
+        Ok(Next::Packet(pkt))
+    }
+
+    /// The kernel resets its counters each time we read them, so we add them up.
+    fn stats(&mut self) -> Option<SourceStats> {
+        let mut st = TpacketStatsV3::default();
+        let mut len = std::mem::size_of::<TpacketStatsV3>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                self.fd,
+                libc::SOL_PACKET,
+                PACKET_STATISTICS,
+                &mut st as *mut TpacketStatsV3 as *mut c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return None;
+        }
+
+        // tp_packets counts the dropped packets too
+        self.stats.received += u64::from(st.tp_packets);
+        self.stats.dropped += u64::from(st.tp_drops);
+        Some(self.stats)
+    }
+}
+
+impl Drop for AfPacketRing {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.ring.is_null() {
+                let len = self.config.block_bytes as usize * self.config.blocks as usize;
+                libc::munmap(self.ring as *mut c_void, len);
+            }
+
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_packet, PKT_LEN, PKT_MAC, PKT_NEXT_OFFSET, PKT_NSEC, PKT_SEC, PKT_SNAPLEN, PKT_STATUS, PKT_VLAN_TCI,
+        TP_STATUS_USER, TP_STATUS_VLAN_VALID,
+    };
+
+    /// Lay a packet's tpacket3_hdr and bytes out at `offset` in `block`, as the kernel would.
+    fn put_packet(block: &mut [u8], offset: usize, next: u32, wire_len: u32, status: u32, vlan_tci: u32, data: &[u8]) {
+        let mac = 48;
+        let hdr = &mut block[offset..];
+        hdr[PKT_NEXT_OFFSET..PKT_NEXT_OFFSET + 4].copy_from_slice(&next.to_ne_bytes());
+        hdr[PKT_SEC..PKT_SEC + 4].copy_from_slice(&2u32.to_ne_bytes());
+        hdr[PKT_NSEC..PKT_NSEC + 4].copy_from_slice(&5u32.to_ne_bytes());
+        hdr[PKT_SNAPLEN..PKT_SNAPLEN + 4].copy_from_slice(&(data.len() as u32).to_ne_bytes());
+        hdr[PKT_LEN..PKT_LEN + 4].copy_from_slice(&wire_len.to_ne_bytes());
+        hdr[PKT_STATUS..PKT_STATUS + 4].copy_from_slice(&status.to_ne_bytes());
+        hdr[PKT_MAC..PKT_MAC + 2].copy_from_slice(&(mac as u16).to_ne_bytes());
+        hdr[PKT_VLAN_TCI..PKT_VLAN_TCI + 4].copy_from_slice(&vlan_tci.to_ne_bytes());
+        hdr[mac..mac + data.len()].copy_from_slice(data);
+    }
+
+    #[test]
+    fn check_read_packet() {
+        let mut frame = vec![0u8; 60];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        frame[14] = 0x45;
+
+        let mut block = vec![0u8; 512];
+        put_packet(&mut block, 0, 160, 1514, TP_STATUS_USER, 0, &frame);
+        put_packet(&mut block, 160, 0, 1514, TP_STATUS_USER | TP_STATUS_VLAN_VALID, 7, &frame);
+
+        let mut scratch = vec![];
+        let (pkt, next) = read_packet(&block, 0, true, &mut scratch);
+        assert_eq!((pkt.ts_ns, pkt.wire_len, next), (2_000_000_005, 1514, 160));
+        assert_eq!(pkt.data, &frame[..]);
+
+        // the stripped tag is back, and counted on the wire, as libpcap does
+        let (pkt, _) = read_packet(&block, 160, true, &mut scratch);
+        assert_eq!(pkt.wire_len, 1518);
+        assert_eq!(&pkt.data[12..18], &[0x81, 0x00, 0, 7, 0x08, 0x00]);
+        let hdrs = crate::hash::parse_headers(pkt.data, true).unwrap();
+        assert_eq!(hdrs.ip_header_start, 18);
+
+        // without a link-layer header there is no tag to put back
+        let (pkt, _) = read_packet(&block, 160, false, &mut scratch);
+        assert_eq!(pkt.wire_len, 1514);
+    }
+}
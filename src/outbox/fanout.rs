@@ -0,0 +1,385 @@
+//! Capture on several cores at once.
+//!
+//! Each worker runs an `Outbox` on its own packet source, e.g. one of several `AfPacketRing`s in
+//! the same fanout group, so the kernel spreads the packets between them by flow. A worker only
+//! counts the bytes it sees, so its byte clock is partial. Workers send their marks, and every so
+//! often their byte clock, to a `Merger`, which puts the marks of all workers in time order and
+//! gives each the sum of the workers' byte clocks at its time, before it goes out as feedback.
+
+use minion::Cancellable;
+use slog::{debug, info};
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::source::PacketSource;
+use super::{MarkUpdate, Outbox, OutboxError};
+use crate::hash;
+use crate::serialize::PathStats;
+
+/// How much capture time may pass before a worker tells the merger its byte clock again. Marks
+/// from other workers wait at most this long for it.
+const PROGRESS_INTERVAL_NS: u64 = 1_000_000;
+/// An idle worker may still get packets this old from its source, e.g. from a ring block the
+/// kernel has not handed over yet. This covers `RingConfig`'s default block timeout, with time
+/// to spare for the worker to get to the block.
+const IDLE_LAG_NS: u64 = 5_000_000;
+/// How often the merger passes on mark updates while no worker has anything for it.
+const MERGE_TICK: Duration = Duration::from_millis(10);
+
+/// What a worker tells the merger. Byte clocks and times are the worker's own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WorkerEvent {
+    /// The worker had counted `bytes` by `ts_ns`.
+    Progress { ts_ns: u64, bytes: u64 },
+    /// A mark, with the upstream loss the worker saw since its last mark.
+    Mark {
+        ts_ns: u64,
+        hash: u32,
+        bytes: u64,
+        stats: PathStats,
+    },
+    /// The worker's source had nothing for it.
+    Idle,
+    /// The worker stopped.
+    End,
+}
+
+impl WorkerEvent {
+    fn ts_ns(&self) -> Option<u64> {
+        match *self {
+            WorkerEvent::Progress { ts_ns, .. } | WorkerEvent::Mark { ts_ns, .. } => Some(ts_ns),
+            WorkerEvent::Idle | WorkerEvent::End => None,
+        }
+    }
+}
+
+/// A worker's end of the channel to the merger.
+pub struct WorkerTx {
+    id: usize,
+    tx: crossbeam::Sender<(usize, WorkerEvent)>,
+    last_progress_ns: u64,
+    // the latest (time, byte clock) not passed on
+    unsent: Option<(u64, u64)>,
+}
+
+impl WorkerTx {
+    fn send(&self, ev: WorkerEvent) -> Result<(), OutboxError> {
+        self.tx.send((self.id, ev)).map_err(|_| OutboxError::MarksClosed)
+    }
+
+    /// The worker has counted `bytes` by `ts_ns`. Only passed on every `PROGRESS_INTERVAL_NS`.
+    pub fn progress(&mut self, ts_ns: u64, bytes: u64) -> Result<(), OutboxError> {
+        if ts_ns < self.last_progress_ns + PROGRESS_INTERVAL_NS {
+            self.unsent = Some((ts_ns, bytes));
+            return Ok(());
+        }
+
+        self.last_progress_ns = ts_ns;
+        self.unsent = None;
+        self.send(WorkerEvent::Progress { ts_ns, bytes })
+    }
+
+    pub fn mark(&mut self, ts_ns: u64, hash: u32, bytes: u64, stats: PathStats) -> Result<(), OutboxError> {
+        self.last_progress_ns = ts_ns;
+        self.unsent = None;
+        self.send(WorkerEvent::Mark {
+            ts_ns,
+            hash,
+            bytes,
+            stats,
+        })
+    }
+
+    /// The worker's source had nothing for it. Passes on the last progress first, so marks from
+    /// other workers up to then need not wait out `IDLE_LAG_NS`.
+    pub fn idle(&mut self) -> Result<(), OutboxError> {
+        if let Some((ts_ns, bytes)) = self.unsent.take() {
+            self.last_progress_ns = ts_ns;
+            self.send(WorkerEvent::Progress { ts_ns, bytes })?;
+        }
+
+        self.send(WorkerEvent::Idle)
+    }
+}
+
+/// What the merger knows of one worker.
+#[derive(Default)]
+struct WorkerClock {
+    // events not yet merged, oldest first
+    queue: VecDeque<WorkerEvent>,
+    bytes: u64,
+    // the worker will send nothing older than this
+    watermark_ns: u64,
+    ended: bool,
+}
+
+impl WorkerClock {
+    /// Whether the worker might still send something older than `ts_ns`.
+    fn behind(&self, ts_ns: u64) -> bool {
+        self.queue.is_empty() && !self.ended && self.watermark_ns < ts_ns
+    }
+}
+
+/// Merges the workers' events into marks with the global byte clock.
+///
+/// Each worker's events come in time order, so the oldest event queued is the next in time order
+/// once no worker could still send an older one. Until then, it waits. Workers' clocks only go
+/// forward, so neither does the global one, even when a late packet makes a mark come out of order.
+struct Merger {
+    workers: Vec<WorkerClock>,
+}
+
+impl Merger {
+    fn new(workers: usize) -> Self {
+        Merger {
+            workers: (0..workers).map(|_| WorkerClock::default()).collect(),
+        }
+    }
+
+    /// Take in an event from worker `id`, arriving at `now_ns`.
+    fn push(&mut self, id: usize, ev: WorkerEvent, now_ns: u64) {
+        let w = &mut self.workers[id];
+        match ev {
+            WorkerEvent::Idle => {
+                w.watermark_ns = std::cmp::max(w.watermark_ns, now_ns.saturating_sub(IDLE_LAG_NS));
+            }
+            WorkerEvent::End => w.ended = true,
+            ev => w.queue.push_back(ev),
+        }
+    }
+
+    /// The next mark in time order, as (time, hash, global byte clock, upstream loss), if no worker
+    /// can still send an older one.
+    fn pop(&mut self) -> Option<(u64, u32, u64, PathStats)> {
+        loop {
+            let (id, ts_ns) = self
+                .workers
+                .iter()
+                .enumerate()
+                .filter_map(|(i, w)| w.queue.front().and_then(WorkerEvent::ts_ns).map(|t| (i, t)))
+                .min_by_key(|&(_, t)| t)?;
+
+            if self.workers.iter().any(|w| w.behind(ts_ns)) {
+                return None;
+            }
+
+            let w = &mut self.workers[id];
+            let ev = w.queue.pop_front()?;
+            w.watermark_ns = std::cmp::max(w.watermark_ns, ts_ns);
+            match ev {
+                WorkerEvent::Progress { bytes, .. } => w.bytes = std::cmp::max(w.bytes, bytes),
+                WorkerEvent::Mark { hash, bytes, stats, .. } => {
+                    w.bytes = std::cmp::max(w.bytes, bytes);
+                    let total = self.workers.iter().map(|w| w.bytes).sum();
+                    return Some((ts_ns, hash, total, stats));
+                }
+                WorkerEvent::Idle | WorkerEvent::End => (),
+            }
+        }
+    }
+}
+
+/// Now, on the same clock as packet timestamps.
+fn wall_clock_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos()))
+        .unwrap_or(0)
+}
+
+fn forward_updates(
+    updates: &mpsc::Receiver<MarkUpdate>,
+    workers: &[mpsc::Sender<MarkUpdate>],
+) -> Result<(), OutboxError> {
+    loop {
+        let u = match updates.try_recv() {
+            Ok(u) => u,
+            Err(mpsc::TryRecvError::Empty) => return Ok(()),
+            Err(mpsc::TryRecvError::Disconnected) => return Err(OutboxError::SessionEnded),
+        };
+
+        for w in workers {
+            // a worker which stopped is dealt with when it says so
+            w.send(u).unwrap_or(());
+        }
+    }
+}
+
+/// Run an `Outbox` on each of `srcs` in its own thread, and send their marks on `tx` with the
+/// global byte clock. Mark updates from `updates` go to every worker.
+///
+/// Stops when every worker has stopped, or as soon as one fails or the sender of `updates` goes
+/// away.
+pub fn start_fanout_outbox<S: PacketSource + Send + 'static>(
+    srcs: Vec<S>,
+    tx: crossbeam::Sender<(u64, u32, u64, PathStats)>,
+    updates: mpsc::Receiver<MarkUpdate>,
+    sample_rate: u32,
+    no_ethernet: bool,
+    hasher: hash::MarkHasher,
+    log: slog::Logger,
+) -> Result<(), OutboxError> {
+    let (events_tx, events) = crossbeam::unbounded();
+    let (worker_updates, rs): (Vec<_>, Vec<_>) = srcs.iter().map(|_| mpsc::channel()).unzip();
+    // what we were told before starting applies from the first packet
+    forward_updates(&updates, &worker_updates)?;
+
+    let mut handles = vec![];
+    for ((id, src), r) in srcs.into_iter().enumerate().zip(rs) {
+        let worker = WorkerTx {
+            id,
+            tx: events_tx.clone(),
+            last_progress_ns: 0,
+            unsent: None,
+        };
+        let log = log.new(slog::o!("worker" => id));
+        let mut outbox = Outbox::worker(src, worker, r, sample_rate, no_ethernet, hasher, log);
+        let end = events_tx.clone();
+        handles.push(Some(thread::spawn(move || {
+            let res = outbox.run();
+            end.send((id, WorkerEvent::End)).unwrap_or(());
+            res
+        })));
+    }
+    drop(events_tx);
+
+    info!(log, "started outbox workers"; "workers" => handles.len());
+    let mut merger = Merger::new(handles.len());
+    let mut running = handles.len();
+    while running > 0 {
+        forward_updates(&updates, &worker_updates)?;
+        let (id, ev) = match events.recv_timeout(MERGE_TICK) {
+            Ok(x) => x,
+            Err(crossbeam::RecvTimeoutError::Timeout) => continue,
+            Err(crossbeam::RecvTimeoutError::Disconnected) => break,
+        };
+
+        if ev == WorkerEvent::End {
+            running -= 1;
+            if let Some(h) = handles[id].take() {
+                h.join().expect("outbox worker panicked")?;
+            }
+
+            debug!(log, "outbox worker stopped"; "worker" => id);
+        }
+
+        merger.push(id, ev, wall_clock_ns());
+        while let Some(mark) = merger.pop() {
+            tx.send(mark).map_err(|_| OutboxError::MarksClosed)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{start_fanout_outbox, Merger, WorkerEvent, WorkerTx, IDLE_LAG_NS, PROGRESS_INTERVAL_NS};
+    use crate::hash::{HashSpec, MarkHasher};
+    use crate::outbox::source::{udp_packet, SyntheticSource};
+    use crate::outbox::MarkUpdate;
+    use crate::serialize::PathStats;
+
+    fn mark(ts_ns: u64, hash: u32, bytes: u64) -> WorkerEvent {
+        WorkerEvent::Mark {
+            ts_ns,
+            hash,
+            bytes,
+            stats: PathStats::default(),
+        }
+    }
+
+    #[test]
+    fn check_merger() {
+        let mut m = Merger::new(2);
+        m.push(0, mark(1_000, 1, 1_500), 0);
+        // worker 1 could still have something older
+        assert_eq!(m.pop(), None);
+
+        m.push(1, WorkerEvent::Progress { ts_ns: 2_000, bytes: 600 }, 0);
+        assert_eq!(m.pop().map(|x| (x.0, x.2)), Some((1_000, 1_500)));
+        assert_eq!(m.pop(), None);
+
+        m.push(0, mark(3_000, 2, 3_000), 0);
+        m.push(1, mark(2_500, 3, 900), 0);
+        assert_eq!(m.pop().map(|x| (x.1, x.2)), Some((3, 2_400)));
+        assert_eq!(m.pop(), None);
+
+        // an idle worker holds marks back only until its source cannot have anything that old
+        m.push(1, WorkerEvent::Idle, IDLE_LAG_NS + 2_900);
+        assert_eq!(m.pop(), None);
+        m.push(1, WorkerEvent::Idle, IDLE_LAG_NS + 3_000);
+        assert_eq!(m.pop().map(|x| (x.1, x.2)), Some((2, 3_900)));
+
+        // a late packet still adds to the byte clock
+        m.push(1, mark(2_800, 4, 1_000), 0);
+        assert_eq!(m.pop().map(|x| (x.0, x.2)), Some((2_800, 4_000)));
+
+        m.push(0, WorkerEvent::End, 0);
+        m.push(1, mark(5_000, 5, 1_200), 0);
+        assert_eq!(m.pop().map(|x| x.2), Some(4_200));
+    }
+
+    #[test]
+    fn check_worker_idle() {
+        let (tx, rx) = crossbeam::unbounded();
+        let mut w = WorkerTx {
+            id: 1,
+            tx,
+            last_progress_ns: 0,
+            unsent: None,
+        };
+
+        w.progress(PROGRESS_INTERVAL_NS, 1_000).unwrap();
+        w.progress(PROGRESS_INTERVAL_NS + 10, 2_000).unwrap();
+        w.progress(PROGRESS_INTERVAL_NS + 20, 3_000).unwrap();
+        let progress = WorkerEvent::Progress {
+            ts_ns: PROGRESS_INTERVAL_NS,
+            bytes: 1_000,
+        };
+        assert_eq!(rx.try_recv(), Ok((1, progress)));
+        assert!(rx.try_recv().is_err());
+
+        // going idle passes on the progress held back
+        w.idle().unwrap();
+        let progress = WorkerEvent::Progress {
+            ts_ns: PROGRESS_INTERVAL_NS + 20,
+            bytes: 3_000,
+        };
+        assert_eq!(rx.try_recv(), Ok((1, progress)));
+        assert_eq!(rx.try_recv(), Ok((1, WorkerEvent::Idle)));
+
+        // but only once
+        w.idle().unwrap();
+        assert_eq!(rx.try_recv(), Ok((1, WorkerEvent::Idle)));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn check_fanout_outbox() {
+        let mut a = SyntheticSource::default();
+        let mut b = SyntheticSource::default();
+        for i in 0..5 {
+            a.push(2_000 * i + 1_000, 1_000, udp_packet(i as u16));
+            b.push(2_000 * i + 2_000, 500, udp_packet(100 + i as u16));
+        }
+
+        let (tx, rx) = crossbeam::unbounded();
+        let (updates, r) = std::sync::mpsc::channel();
+        updates.send(MarkUpdate::EpochLength(1)).unwrap();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let hasher = MarkHasher::new(HashSpec::default());
+        start_fanout_outbox(vec![a, b], tx, r, 4, true, hasher, log).unwrap();
+
+        // every packet is a mark, in time order, counting both workers' bytes
+        let marks: Vec<(u64, u32, u64, PathStats)> = rx.try_iter().collect();
+        assert_eq!(marks.len(), 10);
+        let times: Vec<u64> = marks.iter().map(|m| m.0).collect();
+        assert_eq!(times, (1..=10).map(|i| i * 1_000).collect::<Vec<_>>());
+        assert_eq!(marks[0].2, 1_014);
+        assert_eq!(marks[1].2, 1_528);
+        assert_eq!(marks[9].2, 5 * 1_014 + 5 * 514);
+    }
+}
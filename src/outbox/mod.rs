@@ -0,0 +1,345 @@
+use minion::Cancellable;
+use slog::{debug, info, warn};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::hash;
+use crate::serialize::PathStats;
+use crate::upstream::LossTracker;
+use crate::MAC_HEADER_LENGTH;
+
+#[cfg(target_os = "linux")]
+pub mod af_packet;
+pub mod fanout;
+pub mod source;
+use self::fanout::WorkerTx;
+use self::source::{Next, PacketSource, SourceError, SourceStats};
+
+/// How often to log the source's drop counters.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Changes to how the outbox picks marks, sent as the inbox tells us about them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarkUpdate {
+    /// Mark one in this many packets.
+    EpochLength(u32),
+    /// The key for keyed mark hashing in a new inbox session.
+    HashKey(hash::HashKey),
+}
+
+/// Why the outbox stopped.
+#[derive(Debug)]
+pub enum OutboxError {
+    /// The packet source failed.
+    Source(SourceError),
+    /// Nobody is listening for marks any more.
+    MarksClosed,
+    /// Whoever sends us mark updates gave up, e.g. on an incompatible inbox.
+    SessionEnded,
+}
+
+impl std::fmt::Display for OutboxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OutboxError::Source(e) => write!(f, "packet source failed: {}", e),
+            OutboxError::MarksClosed => write!(f, "mark receiver hung up"),
+            OutboxError::SessionEnded => write!(f, "session with inbox ended"),
+        }
+    }
+}
+
+impl std::error::Error for OutboxError {}
+
+impl From<SourceError> for OutboxError {
+    fn from(e: SourceError) -> Self {
+        OutboxError::Source(e)
+    }
+}
+
+/// Where an `Outbox` sends its marks.
+enum Sink {
+    /// Straight to feedback, as the outbox sees every packet.
+    Marks(crossbeam::Sender<(u64, u32, u64, PathStats)>),
+    /// To be merged with the other workers' marks, as the outbox only sees some of the packets.
+    Worker(WorkerTx),
+}
+
+/// Watches the packets from a `PacketSource` for marks, and sends each mark's time, hash, the
+/// byte clock and the upstream loss since the last mark on `tx`. As one of several workers, it
+/// sends them to `fanout::start_fanout_outbox` instead, along with its byte clock.
+///
+/// Runs until the source ends, the sender of `updates` goes away, or until cancelled through
+/// `minion`.
+pub struct Outbox<S: PacketSource> {
+    src: S,
+    sink: Sink,
+    updates: mpsc::Receiver<MarkUpdate>,
+    sample_rate: u32,
+    no_ethernet: bool,
+    hasher: hash::MarkHasher,
+    log: slog::Logger,
+
+    bytes_recvd: u64,
+    last_bytes_recvd: u64,
+    r1: u64,
+    pkts: u64,
+    unparsed_pkts: u64,
+    marks: hash::MarkCounts,
+    loss: LossTracker,
+    stats: SourceStats,
+    last_stats: Instant,
+}
+
+impl<S: PacketSource> Outbox<S> {
+    pub fn new(
+        src: S,
+        tx: crossbeam::Sender<(u64, u32, u64, PathStats)>,
+        updates: mpsc::Receiver<MarkUpdate>,
+        sample_rate: u32,
+        no_ethernet: bool,
+        hasher: hash::MarkHasher,
+        log: slog::Logger,
+    ) -> Self {
+        Self::with_sink(src, Sink::Marks(tx), updates, sample_rate, no_ethernet, hasher, log)
+    }
+
+    /// An outbox which sends its marks to `worker`.
+    pub fn worker(
+        src: S,
+        worker: WorkerTx,
+        updates: mpsc::Receiver<MarkUpdate>,
+        sample_rate: u32,
+        no_ethernet: bool,
+        hasher: hash::MarkHasher,
+        log: slog::Logger,
+    ) -> Self {
+        Self::with_sink(src, Sink::Worker(worker), updates, sample_rate, no_ethernet, hasher, log)
+    }
+
+    fn with_sink(
+        src: S,
+        sink: Sink,
+        updates: mpsc::Receiver<MarkUpdate>,
+        sample_rate: u32,
+        no_ethernet: bool,
+        hasher: hash::MarkHasher,
+        log: slog::Logger,
+    ) -> Self {
+        Outbox {
+            src,
+            sink,
+            updates,
+            sample_rate,
+            no_ethernet,
+            hasher,
+            log,
+            bytes_recvd: 0,
+            last_bytes_recvd: 0,
+            r1: 0,
+            pkts: 0,
+            unparsed_pkts: 0,
+            marks: Default::default(),
+            loss: Default::default(),
+            stats: Default::default(),
+            last_stats: Instant::now(),
+        }
+    }
+
+    /// Log the source's counters every `STATS_INTERVAL`, and loudly if it dropped packets, since
+    /// those never reach the byte clock.
+    fn check_stats(&mut self) {
+        if self.last_stats.elapsed() < STATS_INTERVAL {
+            return;
+        }
+
+        self.last_stats = Instant::now();
+        let stats = match self.src.stats() {
+            Some(s) => s,
+            None => return,
+        };
+
+        if stats.dropped > self.stats.dropped {
+            warn!(self.log, "capture dropped packets";
+                "dropped" => stats.dropped - self.stats.dropped,
+                "total_dropped" => stats.dropped,
+                "received" => stats.received,
+            );
+        } else {
+            debug!(self.log, "capture stats"; "received" => stats.received, "dropped" => stats.dropped);
+        }
+
+        self.stats = stats;
+    }
+
+    fn apply_updates(&mut self) -> Result<(), OutboxError> {
+        loop {
+            let update = match self.updates.try_recv() {
+                Ok(u) => u,
+                Err(mpsc::TryRecvError::Empty) => return Ok(()),
+                Err(mpsc::TryRecvError::Disconnected) => return Err(OutboxError::SessionEnded),
+            };
+
+            match update {
+                MarkUpdate::EpochLength(epoch_length_packets) => {
+                    if epoch_length_packets > 0 {
+                        info!(self.log, "adjust_epoch";
+                            "curr" => self.sample_rate,
+                            "new" => epoch_length_packets,
+                        );
+
+                        self.sample_rate = epoch_length_packets;
+                    }
+                }
+                MarkUpdate::HashKey(key) => {
+                    info!(self.log, "new mark hash key");
+                    self.hasher.key = Some(key);
+                }
+            }
+        }
+    }
+}
+
+impl<S: PacketSource> Cancellable for Outbox<S> {
+    type Error = OutboxError;
+
+    fn for_each(&mut self) -> Result<minion::LoopState, Self::Error> {
+        self.apply_updates()?;
+        self.check_stats();
+
+        let pkt = match self.src.next_packet()? {
+            Next::Packet(pkt) => pkt,
+            Next::Idle => {
+                if let Sink::Worker(ref mut w) = self.sink {
+                    w.idle()?;
+                }
+
+                return Ok(minion::LoopState::Continue);
+            }
+            Next::End => {
+                info!(self.log, "packet source ended";
+                    "unparsed_pkts" => self.unparsed_pkts,
+                    "marks" => %self.marks,
+                );
+                return Ok(minion::LoopState::Break);
+            }
+        };
+
+        let now = pkt.ts_ns;
+        let data = pkt.data;
+
+        self.bytes_recvd += u64::from(pkt.wire_len);
+        if self.no_ethernet {
+            self.bytes_recvd += MAC_HEADER_LENGTH as u64;
+        }
+
+        if let Sink::Worker(ref mut w) = self.sink {
+            w.progress(now, self.bytes_recvd)?;
+        }
+
+        // count every packet towards the byte clock, but only ones we can parse can be marks
+        let hdrs = match hash::parse_headers(data, !self.no_ethernet) {
+            Ok(h) => h,
+            Err(e) => {
+                self.unparsed_pkts += 1;
+                debug!(self.log, "skipping packet"; "err" => %e, "unparsed_pkts" => self.unparsed_pkts);
+                return Ok(minion::LoopState::Continue);
+            }
+        };
+
+        self.loss.on_packet(&hdrs, data);
+        self.pkts += 1;
+
+        // until we agree on a key with the inbox, we cannot know which packets it marks
+        let hash = match self.hasher.hash(&hdrs, data) {
+            Some(h) => h,
+            None => return Ok(minion::LoopState::Continue),
+        };
+
+        // If hash ends in X zeros, "mark" it
+        if hash % self.sample_rate == 0 {
+            self.marks.add(hdrs.transport);
+            let r2 = now;
+            let stats = self.loss.take();
+            match self.sink {
+                Sink::Marks(ref tx) => tx
+                    .send((r2, hash, self.bytes_recvd, stats))
+                    .map_err(|_| OutboxError::MarksClosed)?,
+                Sink::Worker(ref mut w) => w.mark(r2, hash, self.bytes_recvd, stats)?,
+            }
+            debug!(self.log, "outbox hash";
+                "ip" => ?hash::unpack_ips(data, &hdrs),
+                "ports" => ?hash::unpack_ports(data, hdrs.transport_header_start),
+                "transport" => ?hdrs.transport,
+                "id" => ?hash::packet_id(data, &hdrs),
+                "hash" => hash,
+            );
+
+            let r1 = self.r1;
+            if r1 != 0 && r1 < r2 {
+                let recv_epoch_seconds = (r2 - r1) as f64 / 1e9;
+                let recv_epoch_bytes = (self.bytes_recvd - self.last_bytes_recvd) as f64;
+                info!(self.log, "outbox epoch";
+                    "recv_rate" => recv_epoch_bytes / recv_epoch_seconds,
+                    "recv_epoch_bytes" => recv_epoch_bytes,
+                    "recv_epoch_ns" => (r2 - r1),
+                    "recv_epoch_packet_count" => self.pkts,
+                    "upstream_lost" => stats.lost_pkts,
+                    "upstream_reordered" => stats.reordered_pkts,
+                    "unparsed_pkts" => self.unparsed_pkts,
+                    "marks" => %self.marks,
+                );
+            }
+
+            self.r1 = r2;
+            self.last_bytes_recvd = self.bytes_recvd;
+            self.pkts = 0;
+        }
+
+        Ok(minion::LoopState::Continue)
+    }
+}
+
+/// Run an `Outbox` on `src` until it ends.
+pub fn start_outbox<S: PacketSource>(
+    src: S,
+    tx: crossbeam::Sender<(u64, u32, u64, PathStats)>,
+    r: mpsc::Receiver<MarkUpdate>,
+    sample_rate: u32,
+    no_ethernet: bool,
+    hasher: hash::MarkHasher,
+    log: slog::Logger,
+) -> Result<(), OutboxError> {
+    Outbox::new(src, tx, r, sample_rate, no_ethernet, hasher, log).run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::source::{udp_packet, SyntheticSource};
+    use super::{start_outbox, MarkUpdate};
+    use crate::hash::{HashSpec, MarkHasher};
+
+    #[test]
+    fn check_outbox_marks() {
+        let mut src = SyntheticSource::default();
+        for i in 0..10 {
+            src.push(1_000 * (i + 1), 1_000, udp_packet(i as u16));
+        }
+        // not IP, but still on the wire
+        src.push(20_000, 60, vec![0u8; 40]);
+        src.push(21_000, 1_000, udp_packet(42));
+
+        let (tx, rx) = crossbeam::unbounded();
+        let (updates, r) = std::sync::mpsc::channel();
+        updates.send(MarkUpdate::EpochLength(1)).unwrap();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let hasher = MarkHasher::new(HashSpec::default());
+        start_outbox(src, tx, r, 4, true, hasher, log).unwrap();
+
+        // with an epoch of one packet, every packet is a mark
+        let marks: Vec<(u64, u32, u64, crate::serialize::PathStats)> = rx.try_iter().collect();
+        assert_eq!(marks.len(), 11);
+        assert_eq!(marks[0].0, 1_000);
+        assert_eq!(marks[0].2, 1_014);
+        assert_eq!(marks[10].2, 11 * 1_014 + 74);
+    }
+}
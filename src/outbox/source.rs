@@ -0,0 +1,184 @@
+//! Where the outbox gets its packets from.
+
+/// How long a live capture waits for packets before reporting that it is idle, so whoever runs
+/// the outbox gets a chance to stop it.
+const LIVE_TIMEOUT_MS: i32 = 100;
+/// How much of each packet a live capture keeps: enough to reach the TCP header behind VLAN tags
+/// and IPv4 options or IPv6.
+const LIVE_SNAPLEN: i32 = 128;
+
+/// A packet as a `PacketSource` yields it.
+#[derive(Clone, Copy, Debug)]
+pub struct Packet<'a> {
+    /// When it was captured, in ns since the Unix epoch.
+    pub ts_ns: u64,
+    /// Its length on the wire, which may be more than was captured.
+    pub wire_len: u32,
+    /// The captured bytes, starting with the link-layer header if there is one.
+    pub data: &'a [u8],
+}
+
+/// What a `PacketSource` has for us.
+#[derive(Clone, Copy, Debug)]
+pub enum Next<'a> {
+    Packet(Packet<'a>),
+    /// Nothing arrived for a while.
+    Idle,
+    /// There will be no more packets, e.g. at the end of a trace.
+    End,
+}
+
+/// Why a `PacketSource` failed.
+#[derive(Debug)]
+pub enum SourceError {
+    Pcap(pcap::Error),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for SourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SourceError::Pcap(e) => write!(f, "pcap: {}", e),
+            SourceError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl From<pcap::Error> for SourceError {
+    fn from(e: pcap::Error) -> Self {
+        SourceError::Pcap(e)
+    }
+}
+
+impl From<std::io::Error> for SourceError {
+    fn from(e: std::io::Error) -> Self {
+        SourceError::Io(e)
+    }
+}
+
+/// How many packets a source has seen, and how many it had to drop, since it started.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SourceStats {
+    pub received: u64,
+    pub dropped: u64,
+}
+
+/// A feed of captured packets.
+pub trait PacketSource {
+    /// The next packet. A source should not block for long without returning `Next::Idle`, so
+    /// that the outbox can be stopped.
+    fn next_packet(&mut self) -> Result<Next<'_>, SourceError>;
+
+    /// None if the source does not count, e.g. because nothing can be dropped.
+    fn stats(&mut self) -> Option<SourceStats> {
+        None
+    }
+}
+
+impl<S: PacketSource + ?Sized> PacketSource for Box<S> {
+    fn next_packet(&mut self) -> Result<Next<'_>, SourceError> {
+        (**self).next_packet()
+    }
+
+    fn stats(&mut self) -> Option<SourceStats> {
+        (**self).stats()
+    }
+}
+
+/// Live and offline pcap captures.
+impl<T: pcap::Activated + ?Sized> PacketSource for pcap::Capture<T> {
+    fn next_packet(&mut self) -> Result<Next<'_>, SourceError> {
+        match self.next() {
+            Ok(pkt) => {
+                let ts = pkt.header.ts;
+                Ok(Next::Packet(Packet {
+                    ts_ns: ts.tv_sec as u64 * 1_000_000_000 + ts.tv_usec as u64 * 1_000,
+                    wire_len: pkt.header.len,
+                    data: pkt.data,
+                }))
+            }
+            Err(pcap::Error::TimeoutExpired) => Ok(Next::Idle),
+            Err(pcap::Error::NoMorePackets) => Ok(Next::End),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Offline captures have none.
+    fn stats(&mut self) -> Option<SourceStats> {
+        let s = pcap::Capture::stats(self).ok()?;
+        Some(SourceStats {
+            received: u64::from(s.received),
+            dropped: u64::from(s.dropped) + u64::from(s.if_dropped),
+        })
+    }
+}
+
+/// Capture the packets on `iface` which match the pcap `filter`.
+pub fn live(iface: &str, filter: &str) -> Result<pcap::Capture<pcap::Active>, SourceError> {
+    let dev = pcap::Device::list()?
+        .into_iter()
+        .find(|dev| dev.name == iface)
+        .ok_or_else(|| {
+            SourceError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no such interface: {}", iface),
+            ))
+        })?;
+    let mut cap = pcap::Capture::from_device(dev)?
+        .promisc(false) // Promiscuous mode because the packets are not destined for our IP
+        .snaplen(LIVE_SNAPLEN)
+        .immediate_mode(true)
+        .timeout(LIVE_TIMEOUT_MS)
+        .open()?;
+    cap.filter(filter)?;
+    Ok(cap)
+}
+
+/// Read the packets in the pcap file at `path`.
+pub fn offline<P: AsRef<std::path::Path>>(path: P) -> Result<pcap::Capture<pcap::Offline>, SourceError> {
+    Ok(pcap::Capture::from_file(path)?)
+}
+
+/// Packets held in memory, e.g. to test the outbox with.
+#[derive(Clone, Debug, Default)]
+pub struct SyntheticSource {
+    packets: Vec<(u64, u32, Vec<u8>)>,
+    next: usize,
+}
+
+impl SyntheticSource {
+    /// Add a packet captured at `ts_ns`, `wire_len` bytes long on the wire, of which we have
+    /// `data`.
+    pub fn push(&mut self, ts_ns: u64, wire_len: u32, data: Vec<u8>) {
+        self.packets.push((ts_ns, wire_len, data));
+    }
+}
+
+impl PacketSource for SyntheticSource {
+    fn next_packet(&mut self) -> Result<Next<'_>, SourceError> {
+        let (ts_ns, wire_len, data) = match self.packets.get(self.next) {
+            Some(p) => p,
+            None => return Ok(Next::End),
+        };
+
+        self.next += 1;
+        Ok(Next::Packet(Packet {
+            ts_ns: *ts_ns,
+            wire_len: *wire_len,
+            data,
+        }))
+    }
+}
+
+/// The first bytes of an IPv4 UDP packet with IP id `id`, for a `SyntheticSource` in tests.
+#[cfg(test)]
+pub fn udp_packet(id: u16) -> Vec<u8> {
+    let mut pkt = vec![0u8; 40];
+    pkt[0] = 0x45;
+    pkt[4..6].copy_from_slice(&id.to_be_bytes());
+    pkt[9] = crate::IP_PROTO_UDP;
+    pkt[22..24].copy_from_slice(&[0x13, 0x88]);
+    pkt
+}
@@ -0,0 +1,341 @@
+//! Optional pre-shared-key authentication of inbox <--> outbox messages.
+//!
+//! An authenticated message is the plain message, with `FLAG_AUTHENTICATED` set in its header
+//! and its header length covering this trailer:
+//!
+//!    0                   1                   2                   3
+//!    0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//!   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//!   |                         Counter (64 bits)                     |
+//!   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//!   |     Tag: HMAC-SHA256 of all preceding bytes, first 16 bytes   |
+//!   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//!
+//! The counter increases with every message a sender seals. It starts from the wall-clock time in
+//! nanoseconds, so a restarted sender is not mistaken for a replay. In case the clock went back,
+//! e.g. as a host rebooted, a sender's first `SessionMsg` with a new session id also starts its
+//! replay window over.
+
+use super::{SessionMsg, FLAG_AUTHENTICATED, HEADER_LEN};
+use bytes::{ByteOrder, LittleEndian};
+use fnv::FnvHashMap;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub const TAG_LEN: usize = 16;
+pub const TRAILER_LEN: usize = 8 + TAG_LEN;
+
+/// How far behind the newest counter a message may arrive and still be accepted.
+const REPLAY_WINDOW: u64 = 64;
+/// How many of a sender's earlier sessions to remember, so their messages stay replays once its
+/// replay window started over.
+const MAX_OLD_SESSIONS: usize = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthError {
+    Unauthenticated,
+    BadTag,
+    Replayed(u64),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthError::Unauthenticated => write!(f, "message is not authenticated"),
+            AuthError::BadTag => write!(f, "message authentication failed"),
+            AuthError::Replayed(c) => write!(f, "replayed message, counter {}", c),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Read a pre-shared key, ignoring a trailing newline.
+pub fn read_key_file<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Vec<u8>> {
+    let mut key = std::fs::read(path)?;
+    let len = key
+        .iter()
+        .rposition(|c| !c.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    key.truncate(len);
+
+    if key.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "empty pre-shared key",
+        ));
+    }
+
+    Ok(key)
+}
+
+fn tag(key: &[u8], data: &[u8]) -> [u8; TAG_LEN] {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any length");
+    mac.input(data);
+    let code = mac.result().code();
+    let mut t = [0u8; TAG_LEN];
+    t.copy_from_slice(&code[0..TAG_LEN]);
+    t
+}
+
+/// The key for keyed mark hashing in the inbox session `inbox_session`. Both ends derive it from
+/// the pre-shared key, so it never goes on the wire, and each inbox restart picks a new one.
+pub fn mark_hash_key(psk: &[u8], inbox_session: u64) -> crate::hash::HashKey {
+    let mut data = b"bundler mark hash".to_vec();
+    let mut id = [0u8; 8];
+    LittleEndian::write_u64(&mut id, inbox_session);
+    data.extend_from_slice(&id);
+    tag(psk, &data)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn wall_clock_ns() -> u64 {
+    let now = time::get_time();
+    now.sec as u64 * 1_000_000_000 + now.nsec as u64
+}
+
+/// Authenticates outgoing messages.
+/// Clones share one counter, so they can seal messages sent from the same socket.
+#[derive(Clone)]
+pub struct Sealer {
+    key: Arc<Vec<u8>>,
+    counter: Arc<AtomicU64>,
+}
+
+impl Sealer {
+    pub fn new(key: &[u8]) -> Self {
+        Sealer {
+            key: Arc::new(key.to_vec()),
+            counter: Arc::new(AtomicU64::new(wall_clock_ns())),
+        }
+    }
+
+    pub fn seal(&self, msg: &mut Vec<u8>) {
+        let counter = self.counter.fetch_add(1, Ordering::SeqCst);
+        let len = msg.len();
+        msg.resize(len + TRAILER_LEN, 0);
+        LittleEndian::write_u16(&mut msg[4..6], (len + TRAILER_LEN) as u16);
+        let flags = LittleEndian::read_u16(&msg[6..8]) | FLAG_AUTHENTICATED;
+        LittleEndian::write_u16(&mut msg[6..8], flags);
+        LittleEndian::write_u64(&mut msg[len..len + 8], counter);
+        let t = tag(&self.key, &msg[0..len + 8]);
+        msg[len + 8..].copy_from_slice(&t);
+    }
+}
+
+/// Sliding window of recently seen counters, as in RFC 4303.
+#[derive(Default)]
+struct ReplayWindow {
+    highest: u64,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn check_and_update(&mut self, counter: u64) -> bool {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = counter;
+            return true;
+        }
+
+        let offset = self.highest - counter;
+        if offset >= REPLAY_WINDOW || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+/// What we know of one sender.
+#[derive(Default)]
+struct Peer {
+    window: ReplayWindow,
+    session: Option<u64>,
+    // the lowest counter accepted in this session
+    lowest: Option<u64>,
+    // (session id, lowest counter, highest counter) of its earlier sessions, oldest first
+    old_sessions: Vec<(u64, u64, u64)>,
+}
+
+impl Peer {
+    /// The sender says it is in session `id`. If that is a new session, it restarted, and its
+    /// counter may have started over lower, so the replay window does too.
+    fn got_session(&mut self, id: u64) {
+        if self.session == Some(id) || self.old_sessions.iter().any(|s| s.0 == id) {
+            return;
+        }
+
+        if let (Some(old), Some(lowest)) = (self.session, self.lowest) {
+            if self.old_sessions.len() == MAX_OLD_SESSIONS {
+                self.old_sessions.remove(0);
+            }
+
+            self.old_sessions.push((old, lowest, self.window.highest));
+            self.window = Default::default();
+            self.lowest = None;
+        }
+
+        self.session = Some(id);
+    }
+
+    fn check_and_update(&mut self, counter: u64) -> bool {
+        if self.old_sessions.iter().any(|&(_, lo, hi)| lo <= counter && counter <= hi)
+            || !self.window.check_and_update(counter)
+        {
+            return false;
+        }
+
+        self.lowest = Some(self.lowest.map_or(counter, |l| std::cmp::min(l, counter)));
+        true
+    }
+}
+
+/// Checks incoming messages, keeping a replay window per sender.
+pub struct Opener {
+    key: Vec<u8>,
+    peers: FnvHashMap<SocketAddr, Peer>,
+}
+
+impl Opener {
+    pub fn new(key: &[u8]) -> Self {
+        Opener {
+            key: key.to_vec(),
+            peers: Default::default(),
+        }
+    }
+
+    /// Check the message in `buf`, received from `from`.
+    /// Returns the length of the authenticated message, including its trailer.
+    pub fn open(&mut self, from: SocketAddr, buf: &[u8]) -> Result<usize, AuthError> {
+        if buf.len() < HEADER_LEN + TRAILER_LEN
+            || LittleEndian::read_u16(&buf[6..8]) & FLAG_AUTHENTICATED == 0
+        {
+            return Err(AuthError::Unauthenticated);
+        }
+
+        let len = LittleEndian::read_u16(&buf[4..6]) as usize;
+        if len < HEADER_LEN + TRAILER_LEN || len > buf.len() {
+            return Err(AuthError::BadTag);
+        }
+
+        let t = tag(&self.key, &buf[0..len - TAG_LEN]);
+        if !constant_time_eq(&t, &buf[len - TAG_LEN..len]) {
+            return Err(AuthError::BadTag);
+        }
+
+        let counter = LittleEndian::read_u64(&buf[len - TRAILER_LEN..len - TAG_LEN]);
+        let peer = self.peers.entry(from).or_default();
+        if let Ok(msg) = SessionMsg::from_slice(&buf[0..len]) {
+            peer.got_session(msg.session_id);
+        }
+
+        if !peer.check_and_update(counter) {
+            return Err(AuthError::Replayed(counter));
+        }
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthError, Opener, Sealer};
+    use crate::serialize::{OutBoxFeedbackMsg, SessionMsg};
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    #[test]
+    fn check_auth() {
+        let m = OutBoxFeedbackMsg {
+            bundle_id: 3,
+            seq: 7,
+            marked_packet_hash: 0x3fff_ffff,
+            epoch_bytes: 0xe,
+            epoch_time: 0xf0f0_f0f0,
+            stats: None,
+        };
+        let from = "127.0.0.1:28317".parse().unwrap();
+        let sealer = Sealer::new(b"secret");
+        let mut opener = Opener::new(b"secret");
+
+        let mut first = m.as_bytes();
+        sealer.seal(&mut first);
+        let mut second = m.as_bytes();
+        sealer.seal(&mut second);
+
+        // reordering is fine, replays are not
+        assert_eq!(opener.open(from, &second), Ok(second.len()));
+        assert_eq!(opener.open(from, &first), Ok(first.len()));
+        let counter = match opener.open(from, &first) {
+            Err(AuthError::Replayed(c)) => c,
+            r => panic!("expected replay, got {:?}", r),
+        };
+        assert!(counter > 0);
+        assert_eq!(OutBoxFeedbackMsg::from_slice(&first), Ok(m.clone()));
+
+        assert_eq!(opener.open(from, &m.as_bytes()), Err(AuthError::Unauthenticated));
+
+        let mut forged = m.as_bytes();
+        Sealer::new(b"guess").seal(&mut forged);
+        assert_eq!(opener.open(from, &forged), Err(AuthError::BadTag));
+    }
+
+    #[test]
+    fn check_auth_restart() {
+        let from = "127.0.0.1:28317".parse().unwrap();
+        let sealer = |counter| Sealer {
+            key: Arc::new(b"secret".to_vec()),
+            counter: Arc::new(AtomicU64::new(counter)),
+        };
+        let sealed = |s: &Sealer, mut msg: Vec<u8>| {
+            s.seal(&mut msg);
+            msg
+        };
+        let session = |session_id| SessionMsg {
+            bundle_id: 3,
+            ack: false,
+            session_id,
+            epoch_length_packets: 16,
+            capabilities: 0,
+            hash_spec: 0,
+        };
+        let feedback = OutBoxFeedbackMsg {
+            bundle_id: 3,
+            seq: 0,
+            marked_packet_hash: 7,
+            epoch_bytes: 1_500,
+            epoch_time: 1_000,
+            stats: None,
+        };
+        let mut opener = Opener::new(b"secret");
+
+        let before = sealer(1_000_000);
+        let old_init = sealed(&before, session(1).as_bytes());
+        assert!(opener.open(from, &old_init).is_ok());
+        assert!(opener.open(from, &sealed(&before, feedback.as_bytes())).is_ok());
+
+        // the sender restarted with its clock behind
+        let after = sealer(1_000);
+        let msg = sealed(&after, feedback.as_bytes());
+        assert_eq!(opener.open(from, &msg), Err(AuthError::Replayed(1_000)));
+        assert!(opener.open(from, &sealed(&after, session(2).as_bytes())).is_ok());
+        assert!(opener.open(from, &sealed(&after, feedback.as_bytes())).is_ok());
+
+        // nor are the old session's messages replays any less
+        assert_eq!(opener.open(from, &old_init), Err(AuthError::Replayed(1_000_000)));
+    }
+}
@@ -1,5 +1,182 @@
 use bytes::{ByteOrder, LittleEndian};
 
+pub mod auth;
+
+/// Every bundler message starts with this value, so stray datagrams are not misparsed.
+pub const MAGIC: u16 = 0xb417;
+/// Bumped whenever a message is added or the layout of any message changes.
+/// Messages only sent to peers advertising a capability for them do not need a bump.
+/// Inbox, outbox and qdisc must all speak the same version.
+pub const PROTOCOL_VERSION: u8 = 6;
+pub const HEADER_LEN: usize = 8;
+/// The message carries an `auth` trailer.
+pub const FLAG_AUTHENTICATED: u16 = 0x1;
+/// The inbox accepts `OutBoxFeedbackBatchMsg`.
+pub const CAP_BATCHED_FEEDBACK: u32 = 0x1;
+/// The inbox accepts feedback carrying `PathStats`.
+pub const CAP_PATH_STATS: u32 = 0x2;
+/// Optional features we support, advertised in `SessionMsg::capabilities`.
+pub const CAPABILITIES: u32 = CAP_BATCHED_FEEDBACK | CAP_PATH_STATS;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MsgType {
+    OutBoxReport = 1,
+    OutBoxFeedback = 2,
+    QDiscFeedback = 3,
+    QDiscUpdate = 4,
+    SessionInit = 5,
+    SessionAck = 6,
+    Heartbeat = 7,
+    OutBoxFeedbackBatch = 8,
+    OutBoxFeedbackExt = 9,
+}
+
+impl MsgType {
+    fn from_u8(x: u8) -> Option<Self> {
+        match x {
+            1 => Some(MsgType::OutBoxReport),
+            2 => Some(MsgType::OutBoxFeedback),
+            3 => Some(MsgType::QDiscFeedback),
+            4 => Some(MsgType::QDiscUpdate),
+            5 => Some(MsgType::SessionInit),
+            6 => Some(MsgType::SessionAck),
+            7 => Some(MsgType::Heartbeat),
+            8 => Some(MsgType::OutBoxFeedbackBatch),
+            9 => Some(MsgType::OutBoxFeedbackExt),
+            _ => None,
+        }
+    }
+}
+
+/// Common header of all messages.
+///
+///    0                   1                   2                   3
+///    0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+///   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///   |             Magic             |    Version    |   Msg Type    |
+///   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///   |         Total Length          |             Flags             |
+///   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///
+/// The body of every message starts with the bundle id.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    pub msg_type: MsgType,
+    pub len: u16,
+    pub flags: u16,
+}
+
+/// Why a buffer could not be decoded into a message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    TooShort { need: usize, got: usize },
+    BadMagic(u16),
+    VersionMismatch { ours: u8, theirs: u8 },
+    UnknownType(u8),
+    WrongType { expected: MsgType, got: MsgType },
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::TooShort { need, got } => {
+                write!(f, "message too short: need {} bytes, got {}", need, got)
+            }
+            DecodeError::BadMagic(m) => write!(f, "bad magic {:#06x}, not a bundler message", m),
+            DecodeError::VersionMismatch { ours, theirs } => write!(
+                f,
+                "protocol version mismatch: peer speaks v{}, we speak v{}; run the same bundler version on inbox and outbox",
+                theirs, ours
+            ),
+            DecodeError::UnknownType(t) => write!(f, "unknown message type {}", t),
+            DecodeError::WrongType { expected, got } => {
+                write!(f, "expected {:?} message, got {:?}", expected, got)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Header {
+    fn new(msg_type: MsgType, len: usize) -> Self {
+        Header {
+            version: PROTOCOL_VERSION,
+            msg_type,
+            len: len as u16,
+            flags: 0,
+        }
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        LittleEndian::write_u16(&mut buf[0..2], MAGIC);
+        buf[2] = self.version;
+        buf[3] = self.msg_type as u8;
+        LittleEndian::write_u16(&mut buf[4..6], self.len);
+        LittleEndian::write_u16(&mut buf[6..8], self.flags);
+    }
+
+    /// Check that `buf` holds a message of our protocol version and find out its type.
+    pub fn from_slice(buf: &[u8]) -> Result<Self, DecodeError> {
+        if buf.len() < HEADER_LEN {
+            return Err(DecodeError::TooShort {
+                need: HEADER_LEN,
+                got: buf.len(),
+            });
+        }
+
+        let magic = LittleEndian::read_u16(&buf[0..2]);
+        if magic != MAGIC {
+            return Err(DecodeError::BadMagic(magic));
+        }
+
+        if buf[2] != PROTOCOL_VERSION {
+            return Err(DecodeError::VersionMismatch {
+                ours: PROTOCOL_VERSION,
+                theirs: buf[2],
+            });
+        }
+
+        let msg_type = MsgType::from_u8(buf[3]).ok_or(DecodeError::UnknownType(buf[3]))?;
+        Ok(Header {
+            version: buf[2],
+            msg_type,
+            len: LittleEndian::read_u16(&buf[4..6]),
+            flags: LittleEndian::read_u16(&buf[6..8]),
+        })
+    }
+
+    /// Check that `buf` holds a whole message of type `msg_type`, which is `len` bytes long.
+    fn expect(buf: &[u8], msg_type: MsgType, len: usize) -> Result<Self, DecodeError> {
+        let h = Header::from_slice(buf)?;
+        if h.msg_type != msg_type {
+            return Err(DecodeError::WrongType {
+                expected: msg_type,
+                got: h.msg_type,
+            });
+        }
+
+        if buf.len() < len || (h.len as usize) < len {
+            return Err(DecodeError::TooShort {
+                need: len,
+                got: std::cmp::min(buf.len(), h.len as usize),
+            });
+        }
+
+        Ok(h)
+    }
+}
+
+/// The bundle a message belongs to, without decoding the rest of it.
+pub fn peek_bundle_id(buf: &[u8]) -> Option<u32> {
+    if buf.len() < HEADER_LEN + 4 {
+        return None;
+    }
+
+    Some(LittleEndian::read_u32(&buf[HEADER_LEN..HEADER_LEN + 4]))
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct OutBoxReportMsg {
     pub bundle_id: u32,
@@ -8,17 +185,19 @@ pub struct OutBoxReportMsg {
 
 impl OutBoxReportMsg {
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut buf = vec![0u8; 2 * 4]; // 8 bytes
-        LittleEndian::write_u32(&mut buf[0..4], self.bundle_id);
-        LittleEndian::write_u32(&mut buf[4..8], self.epoch_length_packets);
+        let mut buf = vec![0u8; HEADER_LEN + 2 * 4]; // 16 bytes
+        Header::new(MsgType::OutBoxReport, buf.len()).write(&mut buf[0..HEADER_LEN]);
+        LittleEndian::write_u32(&mut buf[8..12], self.bundle_id);
+        LittleEndian::write_u32(&mut buf[12..16], self.epoch_length_packets);
         buf
     }
 
-    pub fn from_slice(buf: &[u8]) -> Self {
-        OutBoxReportMsg {
-            bundle_id: LittleEndian::read_u32(&buf[0..4]),
-            epoch_length_packets: LittleEndian::read_u32(&buf[4..8]),
-        }
+    pub fn from_slice(buf: &[u8]) -> Result<Self, DecodeError> {
+        Header::expect(buf, MsgType::OutBoxReport, 16)?;
+        Ok(OutBoxReportMsg {
+            bundle_id: LittleEndian::read_u32(&buf[8..12]),
+            epoch_length_packets: LittleEndian::read_u32(&buf[12..16]),
+        })
     }
 }
 
@@ -26,31 +205,81 @@ impl OutBoxReportMsg {
 /// Receive time of this message, combined with the
 /// send time of the corresponding marked packet, gets us
 /// RTT.
+/// `seq` numbers the feedback messages an outbox sends, so the inbox can
+/// detect lost and reordered feedback.
+/// With `stats`, this is sent as an `OutBoxFeedbackExt` message, only to inboxes advertising
+/// `CAP_PATH_STATS`.
 #[derive(Clone, Debug, PartialEq)]
 pub struct OutBoxFeedbackMsg {
     pub bundle_id: u32,
+    pub seq: u32,
     pub marked_packet_hash: u32,
     pub epoch_bytes: u64,
     pub epoch_time: u64,
+    pub stats: Option<PathStats>,
+}
+
+/// Packets lost or reordered before reaching the outbox, since the previous mark.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PathStats {
+    pub lost_pkts: u32,
+    pub reordered_pkts: u32,
+}
+
+impl PathStats {
+    fn write(&self, buf: &mut [u8]) {
+        LittleEndian::write_u32(&mut buf[0..4], self.lost_pkts);
+        LittleEndian::write_u32(&mut buf[4..8], self.reordered_pkts);
+    }
+
+    fn read(buf: &[u8]) -> Self {
+        PathStats {
+            lost_pkts: LittleEndian::read_u32(&buf[0..4]),
+            reordered_pkts: LittleEndian::read_u32(&buf[4..8]),
+        }
+    }
 }
 
 impl OutBoxFeedbackMsg {
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut buf = vec![0u8; 2 * 4 + 2 * 8]; // 24 bytes
-        LittleEndian::write_u32(&mut buf[0..4], self.bundle_id);
-        LittleEndian::write_u32(&mut buf[4..8], self.marked_packet_hash);
-        LittleEndian::write_u64(&mut buf[8..16], self.epoch_bytes);
-        LittleEndian::write_u64(&mut buf[16..24], self.epoch_time);
+        let (msg_type, len) = match self.stats {
+            None => (MsgType::OutBoxFeedback, HEADER_LEN + 3 * 4 + 2 * 8), // 36 bytes
+            Some(_) => (MsgType::OutBoxFeedbackExt, HEADER_LEN + 5 * 4 + 2 * 8), // 44 bytes
+        };
+        let mut buf = vec![0u8; len];
+        Header::new(msg_type, buf.len()).write(&mut buf[0..HEADER_LEN]);
+        LittleEndian::write_u32(&mut buf[8..12], self.bundle_id);
+        LittleEndian::write_u32(&mut buf[12..16], self.seq);
+        LittleEndian::write_u32(&mut buf[16..20], self.marked_packet_hash);
+        LittleEndian::write_u64(&mut buf[20..28], self.epoch_bytes);
+        LittleEndian::write_u64(&mut buf[28..36], self.epoch_time);
+        if let Some(ref stats) = self.stats {
+            stats.write(&mut buf[36..44]);
+        }
+
         buf
     }
 
-    pub fn from_slice(buf: &[u8]) -> Self {
-        OutBoxFeedbackMsg {
-            bundle_id: LittleEndian::read_u32(&buf[0..4]),
-            marked_packet_hash: LittleEndian::read_u32(&buf[4..8]),
-            epoch_bytes: LittleEndian::read_u64(&buf[8..16]),
-            epoch_time: LittleEndian::read_u64(&buf[16..24]),
+    pub fn from_slice(buf: &[u8]) -> Result<Self, DecodeError> {
+        let extended = Header::from_slice(buf)?.msg_type == MsgType::OutBoxFeedbackExt;
+        if extended {
+            Header::expect(buf, MsgType::OutBoxFeedbackExt, 44)?;
+        } else {
+            Header::expect(buf, MsgType::OutBoxFeedback, 36)?;
         }
+
+        Ok(OutBoxFeedbackMsg {
+            bundle_id: LittleEndian::read_u32(&buf[8..12]),
+            seq: LittleEndian::read_u32(&buf[12..16]),
+            marked_packet_hash: LittleEndian::read_u32(&buf[16..20]),
+            epoch_bytes: LittleEndian::read_u64(&buf[20..28]),
+            epoch_time: LittleEndian::read_u64(&buf[28..36]),
+            stats: if extended {
+                Some(PathStats::read(&buf[36..44]))
+            } else {
+                None
+            },
+        })
     }
 }
 
@@ -66,67 +295,398 @@ pub struct QDiscFeedbackMsg {
 
 impl QDiscFeedbackMsg {
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut buf = vec![0u8; 3 * 4 + 2 * 8]; // 28 bytes
-        LittleEndian::write_u32(&mut buf[0..4], self.bundle_id);
-        LittleEndian::write_u32(&mut buf[4..8], self.marked_packet_hash);
-        LittleEndian::write_u32(&mut buf[8..12], self.curr_qlen);
-        LittleEndian::write_u64(&mut buf[12..20], self.epoch_bytes);
-        LittleEndian::write_u64(&mut buf[20..28], self.epoch_time);
+        let mut buf = vec![0u8; HEADER_LEN + 3 * 4 + 2 * 8]; // 36 bytes
+        Header::new(MsgType::QDiscFeedback, buf.len()).write(&mut buf[0..HEADER_LEN]);
+        LittleEndian::write_u32(&mut buf[8..12], self.bundle_id);
+        LittleEndian::write_u32(&mut buf[12..16], self.marked_packet_hash);
+        LittleEndian::write_u32(&mut buf[16..20], self.curr_qlen);
+        LittleEndian::write_u64(&mut buf[20..28], self.epoch_bytes);
+        LittleEndian::write_u64(&mut buf[28..36], self.epoch_time);
+        buf
+    }
+
+    pub fn from_slice(buf: &[u8]) -> Result<Self, DecodeError> {
+        Header::expect(buf, MsgType::QDiscFeedback, 36)?;
+        Ok(QDiscFeedbackMsg {
+            bundle_id: LittleEndian::read_u32(&buf[8..12]),
+            marked_packet_hash: LittleEndian::read_u32(&buf[12..16]),
+            curr_qlen: LittleEndian::read_u32(&buf[16..20]),
+            epoch_bytes: LittleEndian::read_u64(&buf[20..28]),
+            epoch_time: LittleEndian::read_u64(&buf[28..36]),
+        })
+    }
+}
+
+/// Most records in one `OutBoxFeedbackBatchMsg`, so that it fits in one unfragmented datagram.
+pub const MAX_FEEDBACK_BATCH: usize = 32;
+
+/// The records of an `OutBoxFeedbackBatchMsg` carry `PathStats`.
+const BATCH_FLAG_PATH_STATS: u16 = 0x1;
+
+/// One marked packet seen by the outbox, as in `OutBoxFeedbackMsg`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeedbackRecord {
+    pub marked_packet_hash: u32,
+    pub epoch_bytes: u64,
+    pub epoch_time: u64,
+    pub stats: Option<PathStats>,
+}
+
+/// Several outbox feedback records with consecutive sequence numbers, starting at `first_seq`.
+/// `sent_time` is when the outbox sent the batch, on the same clock as the records' `epoch_time`,
+/// so the inbox can tell how long each record took from capture to send and correct its RTT sample.
+/// If any record has `PathStats`, all records carry them on the wire.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutBoxFeedbackBatchMsg {
+    pub bundle_id: u32,
+    pub first_seq: u32,
+    pub sent_time: u64,
+    pub records: Vec<FeedbackRecord>,
+}
+
+impl OutBoxFeedbackBatchMsg {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let with_stats = self.records.iter().any(|r| r.stats.is_some());
+        let record_len = if with_stats { 28 } else { 20 };
+        let len = HEADER_LEN + 3 * 4 + 8 + self.records.len() * record_len;
+        let mut buf = vec![0u8; len];
+        Header::new(MsgType::OutBoxFeedbackBatch, len).write(&mut buf[0..HEADER_LEN]);
+        LittleEndian::write_u32(&mut buf[8..12], self.bundle_id);
+        LittleEndian::write_u32(&mut buf[12..16], self.first_seq);
+        LittleEndian::write_u16(&mut buf[16..18], self.records.len() as u16);
+        if with_stats {
+            LittleEndian::write_u16(&mut buf[18..20], BATCH_FLAG_PATH_STATS);
+        }
+        LittleEndian::write_u64(&mut buf[20..28], self.sent_time);
+        for (i, r) in self.records.iter().enumerate() {
+            let off = 28 + i * record_len;
+            LittleEndian::write_u32(&mut buf[off..off + 4], r.marked_packet_hash);
+            LittleEndian::write_u64(&mut buf[off + 4..off + 12], r.epoch_bytes);
+            LittleEndian::write_u64(&mut buf[off + 12..off + 20], r.epoch_time);
+            if with_stats {
+                r.stats.unwrap_or_default().write(&mut buf[off + 20..off + 28]);
+            }
+        }
+
         buf
     }
 
-    pub fn from_slice(buf: &[u8]) -> Self {
-        QDiscFeedbackMsg {
-            bundle_id: LittleEndian::read_u32(&buf[0..4]),
-            marked_packet_hash: LittleEndian::read_u32(&buf[4..8]),
-            curr_qlen: LittleEndian::read_u32(&buf[8..12]),
-            epoch_bytes: LittleEndian::read_u64(&buf[12..20]),
-            epoch_time: LittleEndian::read_u64(&buf[20..28]),
+    pub fn from_slice(buf: &[u8]) -> Result<Self, DecodeError> {
+        Header::expect(buf, MsgType::OutBoxFeedbackBatch, 28)?;
+        let count = LittleEndian::read_u16(&buf[16..18]) as usize;
+        let with_stats = LittleEndian::read_u16(&buf[18..20]) & BATCH_FLAG_PATH_STATS != 0;
+        let record_len = if with_stats { 28 } else { 20 };
+        Header::expect(buf, MsgType::OutBoxFeedbackBatch, 28 + count * record_len)?;
+        Ok(OutBoxFeedbackBatchMsg {
+            bundle_id: LittleEndian::read_u32(&buf[8..12]),
+            first_seq: LittleEndian::read_u32(&buf[12..16]),
+            sent_time: LittleEndian::read_u64(&buf[20..28]),
+            records: (0..count)
+                .map(|i| {
+                    let off = 28 + i * record_len;
+                    FeedbackRecord {
+                        marked_packet_hash: LittleEndian::read_u32(&buf[off..off + 4]),
+                        epoch_bytes: LittleEndian::read_u64(&buf[off + 4..off + 12]),
+                        epoch_time: LittleEndian::read_u64(&buf[off + 12..off + 20]),
+                        stats: if with_stats {
+                            Some(PathStats::read(&buf[off + 20..off + 28]))
+                        } else {
+                            None
+                        },
+                    }
+                })
+                .collect(),
+        })
+    }
+
+    /// Unpack into the individual feedback messages, each with how long the outbox took to send it.
+    pub fn into_msgs(self) -> Vec<(OutBoxFeedbackMsg, u64)> {
+        let bundle_id = self.bundle_id;
+        let first_seq = self.first_seq;
+        let sent_time = self.sent_time;
+        self.records
+            .into_iter()
+            .enumerate()
+            .map(|(i, r)| {
+                let held = sent_time.saturating_sub(r.epoch_time);
+                let msg = OutBoxFeedbackMsg {
+                    bundle_id,
+                    seq: first_seq.wrapping_add(i as u32),
+                    marked_packet_hash: r.marked_packet_hash,
+                    epoch_bytes: r.epoch_bytes,
+                    epoch_time: r.epoch_time,
+                    stats: r.stats,
+                };
+                (msg, held)
+            })
+            .collect()
+    }
+}
+
+/// The datagram carrying `records`, numbered from `first_seq`, sent at `sent_time`.
+///
+/// A plain `OutBoxFeedbackMsg` has no send time, so the inbox takes it as sent the moment its mark
+/// was captured. If the inbox takes `batch`es, even a lone record goes out as an
+/// `OutBoxFeedbackBatchMsg`, so the inbox can take the capture ring's block timeout, merging
+/// workers' marks and batching out of its RTT samples. Otherwise there must be a single record.
+pub fn feedback_datagram(
+    bundle_id: u32,
+    first_seq: u32,
+    mut records: Vec<FeedbackRecord>,
+    batch: bool,
+    sent_time: u64,
+) -> Vec<u8> {
+    if !batch {
+        assert_eq!(records.len(), 1, "inbox takes one feedback record per datagram");
+        let r = records.pop().unwrap();
+        return OutBoxFeedbackMsg {
+            bundle_id,
+            seq: first_seq,
+            marked_packet_hash: r.marked_packet_hash,
+            epoch_bytes: r.epoch_bytes,
+            epoch_time: r.epoch_time,
+            stats: r.stats,
         }
+        .as_bytes();
     }
+
+    OutBoxFeedbackBatchMsg {
+        bundle_id,
+        first_seq,
+        sent_time,
+        records,
+    }
+    .as_bytes()
 }
 
 /// Netlink message requesting the qdisc to change the rate at which it samples packets for a given
 /// bundle.
 /// The rate is specified as the epoch length in number of packets.
+/// The qdisc installed on `ifindex` adopts `bundle_id` and stamps it on its feedback, and hashes
+/// packets over the fields in `hash_spec` (`hash::HashSpec` bits). If `hash_spec` is keyed, it
+/// hashes under `hash_key`, which is all zeros otherwise.
 #[derive(Clone, Debug, PartialEq)]
 pub struct QDiscUpdateMsg {
     pub bundle_id: u32,
+    pub ifindex: u32,
     pub sample_rate: u32,
+    pub hash_spec: u32,
+    pub hash_key: crate::hash::HashKey,
 }
 
 impl QDiscUpdateMsg {
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut buf = vec![0u8; 2 * 4]; // 8 bytes
-        LittleEndian::write_u32(&mut buf[0..4], self.bundle_id);
-        LittleEndian::write_u32(&mut buf[4..8], self.sample_rate);
+        let mut buf = vec![0u8; HEADER_LEN + 4 * 4 + 16]; // 40 bytes
+        Header::new(MsgType::QDiscUpdate, buf.len()).write(&mut buf[0..HEADER_LEN]);
+        LittleEndian::write_u32(&mut buf[8..12], self.bundle_id);
+        LittleEndian::write_u32(&mut buf[12..16], self.ifindex);
+        LittleEndian::write_u32(&mut buf[16..20], self.sample_rate);
+        LittleEndian::write_u32(&mut buf[20..24], self.hash_spec);
+        buf[24..40].copy_from_slice(&self.hash_key);
         buf
     }
 
-    pub fn from_slice(buf: &[u8]) -> Self {
-        QDiscUpdateMsg {
-            bundle_id: LittleEndian::read_u32(&buf[0..4]),
-            sample_rate: LittleEndian::read_u32(&buf[4..8]),
-        }
+    pub fn from_slice(buf: &[u8]) -> Result<Self, DecodeError> {
+        Header::expect(buf, MsgType::QDiscUpdate, 40)?;
+        let mut hash_key = [0u8; 16];
+        hash_key.copy_from_slice(&buf[24..40]);
+        Ok(QDiscUpdateMsg {
+            bundle_id: LittleEndian::read_u32(&buf[8..12]),
+            ifindex: LittleEndian::read_u32(&buf[12..16]),
+            sample_rate: LittleEndian::read_u32(&buf[16..20]),
+            hash_spec: LittleEndian::read_u32(&buf[20..24]),
+            hash_key,
+        })
+    }
+}
+
+/// Inbox <--> outbox handshake.
+/// Either side sends an init when it does not have a session with its peer, and answers every init
+/// with an ack. Each side picks a new `session_id` when it starts, so a peer with a different
+/// session id than before has restarted.
+/// `hash_spec` is how the sender hashes packets (`hash::HashSpec` bits), so a mismatch with the
+/// peer can be reported.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionMsg {
+    pub bundle_id: u32,
+    pub ack: bool,
+    pub session_id: u64,
+    pub epoch_length_packets: u32,
+    pub capabilities: u32,
+    pub hash_spec: u32,
+}
+
+impl SessionMsg {
+    /// A session id that is unlikely to have been used by a previous run.
+    pub fn new_session_id() -> u64 {
+        let now = time::get_time();
+        (now.sec as u64 * 1_000_000_000 + now.nsec as u64) ^ (u64::from(std::process::id()) << 32)
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; HEADER_LEN + 4 * 4 + 8]; // 32 bytes
+        let msg_type = if self.ack {
+            MsgType::SessionAck
+        } else {
+            MsgType::SessionInit
+        };
+        Header::new(msg_type, buf.len()).write(&mut buf[0..HEADER_LEN]);
+        LittleEndian::write_u32(&mut buf[8..12], self.bundle_id);
+        LittleEndian::write_u32(&mut buf[12..16], self.epoch_length_packets);
+        LittleEndian::write_u32(&mut buf[16..20], self.capabilities);
+        LittleEndian::write_u64(&mut buf[20..28], self.session_id);
+        LittleEndian::write_u32(&mut buf[28..32], self.hash_spec);
+        buf
+    }
+
+    pub fn from_slice(buf: &[u8]) -> Result<Self, DecodeError> {
+        let ack = Header::from_slice(buf)?.msg_type == MsgType::SessionAck;
+        let msg_type = if ack {
+            MsgType::SessionAck
+        } else {
+            MsgType::SessionInit
+        };
+        Header::expect(buf, msg_type, 32)?;
+        Ok(SessionMsg {
+            bundle_id: LittleEndian::read_u32(&buf[8..12]),
+            ack,
+            epoch_length_packets: LittleEndian::read_u32(&buf[12..16]),
+            capabilities: LittleEndian::read_u32(&buf[16..20]),
+            session_id: LittleEndian::read_u64(&buf[20..28]),
+            hash_spec: LittleEndian::read_u32(&buf[28..32]),
+        })
+    }
+}
+
+/// Keepalive, sent periodically in both directions once a session is established.
+/// `session_id` is the sender's, so a peer that restarted without a handshake is noticed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeartbeatMsg {
+    pub bundle_id: u32,
+    pub session_id: u64,
+}
+
+impl HeartbeatMsg {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; HEADER_LEN + 4 + 8]; // 20 bytes
+        Header::new(MsgType::Heartbeat, buf.len()).write(&mut buf[0..HEADER_LEN]);
+        LittleEndian::write_u32(&mut buf[8..12], self.bundle_id);
+        LittleEndian::write_u64(&mut buf[12..20], self.session_id);
+        buf
+    }
+
+    pub fn from_slice(buf: &[u8]) -> Result<Self, DecodeError> {
+        Header::expect(buf, MsgType::Heartbeat, 20)?;
+        Ok(HeartbeatMsg {
+            bundle_id: LittleEndian::read_u32(&buf[8..12]),
+            session_id: LittleEndian::read_u64(&buf[12..20]),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{OutBoxFeedbackMsg, QDiscFeedbackMsg, QDiscUpdateMsg};
+    use super::{
+        FeedbackRecord, Header, DecodeError, HeartbeatMsg, MsgType, OutBoxFeedbackBatchMsg,
+        OutBoxFeedbackMsg, OutBoxReportMsg, PathStats, QDiscFeedbackMsg, QDiscUpdateMsg,
+        SessionMsg, PROTOCOL_VERSION,
+    };
+
+    #[test]
+    fn check_feedback_batch() {
+        let m = OutBoxFeedbackBatchMsg {
+            bundle_id: 3,
+            first_seq: u32::MAX,
+            sent_time: 1_000,
+            records: vec![
+                FeedbackRecord {
+                    marked_packet_hash: 0x3fff_ffff,
+                    epoch_bytes: 0xe,
+                    epoch_time: 400,
+                    stats: None,
+                },
+                FeedbackRecord {
+                    marked_packet_hash: 0x1234,
+                    epoch_bytes: 0xf,
+                    epoch_time: 1_000,
+                    stats: None,
+                },
+            ],
+        };
+
+        let buf = m.as_bytes();
+        assert_eq!(buf.len(), 68);
+        assert_eq!(OutBoxFeedbackBatchMsg::from_slice(&buf), Ok(m.clone()));
+        assert_eq!(
+            OutBoxFeedbackBatchMsg::from_slice(&buf[0..60]),
+            Err(DecodeError::TooShort { need: 68, got: 60 })
+        );
+
+        let msgs = m.into_msgs();
+        assert_eq!(msgs[0].1, 600);
+        assert_eq!(msgs[1].0.seq, 0);
+        assert_eq!(msgs[1].0.epoch_bytes, 0xf);
+        assert_eq!(msgs[1].1, 0);
+    }
+
+    #[test]
+    fn check_session_msg() {
+        let m = SessionMsg {
+            bundle_id: 3,
+            ack: true,
+            session_id: 0x0123_4567_89ab_cdef,
+            epoch_length_packets: 32,
+            capabilities: 0,
+            hash_spec: 0x48,
+        };
+
+        let buf = m.as_bytes();
+        assert_eq!(Header::from_slice(&buf).unwrap().msg_type, MsgType::SessionAck);
+        assert_eq!(SessionMsg::from_slice(&buf), Ok(m.clone()));
+
+        let init = SessionMsg { ack: false, ..m };
+        assert_eq!(SessionMsg::from_slice(&init.as_bytes()), Ok(init));
+
+        let hb = HeartbeatMsg {
+            bundle_id: 3,
+            session_id: 0x0123_4567_89ab_cdef,
+        };
+        assert_eq!(HeartbeatMsg::from_slice(&hb.as_bytes()), Ok(hb));
+        assert_eq!(
+            SessionMsg::from_slice(&OutBoxReportMsg { bundle_id: 3, epoch_length_packets: 32 }.as_bytes()),
+            Err(DecodeError::WrongType {
+                expected: MsgType::SessionInit,
+                got: MsgType::OutBoxReport,
+            })
+        );
+    }
 
     #[test]
     fn check_outbox_msg() {
         let m = OutBoxFeedbackMsg {
             bundle_id: 3,
+            seq: 7,
             marked_packet_hash: 0x3fff_ffff,
             epoch_bytes: 0xe,
             epoch_time: 0xf0f0_f0f0,
+            stats: None,
         };
 
         let buf = m.as_bytes();
-        let ms = OutBoxFeedbackMsg::from_slice(&buf);
+        let ms = OutBoxFeedbackMsg::from_slice(&buf).unwrap();
         assert_eq!(m, ms);
+
+        let ext = OutBoxFeedbackMsg {
+            stats: Some(PathStats {
+                lost_pkts: 2,
+                reordered_pkts: 1,
+            }),
+            ..m
+        };
+        let buf = ext.as_bytes();
+        assert_eq!(buf.len(), 44);
+        assert_eq!(Header::from_slice(&buf).unwrap().msg_type, MsgType::OutBoxFeedbackExt);
+        assert_eq!(OutBoxFeedbackMsg::from_slice(&buf), Ok(ext));
     }
 
     #[test]
@@ -140,7 +700,7 @@ mod tests {
         };
 
         let buf = m.as_bytes();
-        let ms = QDiscFeedbackMsg::from_slice(&buf);
+        let ms = QDiscFeedbackMsg::from_slice(&buf).unwrap();
         assert_eq!(m, ms);
     }
 
@@ -148,10 +708,70 @@ mod tests {
     fn check_update_msg() {
         let m = QDiscUpdateMsg {
             bundle_id: 4,
+            ifindex: 2,
             sample_rate: 128,
+            hash_spec: 0xc8,
+            hash_key: [7; 16],
         };
         let buf = m.as_bytes();
-        let ms = QDiscUpdateMsg::from_slice(&buf);
+        assert_eq!(buf.len(), 40);
+        let ms = QDiscUpdateMsg::from_slice(&buf).unwrap();
         assert_eq!(m, ms);
     }
+
+    #[test]
+    fn check_header() {
+        let m = OutBoxReportMsg {
+            bundle_id: 4,
+            epoch_length_packets: 64,
+        };
+        let mut buf = m.as_bytes();
+        let h = Header::from_slice(&buf).unwrap();
+        assert_eq!(h.msg_type, MsgType::OutBoxReport);
+        assert_eq!(h.len as usize, buf.len());
+        assert_eq!(super::peek_bundle_id(&buf), Some(4));
+
+        buf[2] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            Header::from_slice(&buf),
+            Err(DecodeError::VersionMismatch {
+                ours: PROTOCOL_VERSION,
+                theirs: PROTOCOL_VERSION + 1,
+            })
+        );
+
+        buf[0] = 0;
+        assert!(Header::from_slice(&buf).is_err());
+        assert_eq!(
+            Header::from_slice(&buf[0..4]),
+            Err(DecodeError::TooShort { need: 8, got: 4 })
+        );
+    }
+
+    #[test]
+    fn check_malformed() {
+        let m = OutBoxFeedbackMsg {
+            bundle_id: 3,
+            seq: 7,
+            marked_packet_hash: 0x3fff_ffff,
+            epoch_bytes: 0xe,
+            epoch_time: 0xf0f0_f0f0,
+            stats: None,
+        };
+        let buf = m.as_bytes();
+
+        assert_eq!(
+            OutBoxFeedbackMsg::from_slice(&buf[0..20]),
+            Err(DecodeError::TooShort { need: 36, got: 20 })
+        );
+        assert_eq!(
+            OutBoxReportMsg::from_slice(&buf),
+            Err(DecodeError::WrongType {
+                expected: MsgType::OutBoxReport,
+                got: MsgType::OutBoxFeedback,
+            })
+        );
+        assert!(OutBoxFeedbackMsg::from_slice(&[]).is_err());
+        assert!(OutBoxFeedbackMsg::from_slice(&[0xff; 64]).is_err());
+    }
 }
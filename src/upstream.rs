@@ -0,0 +1,140 @@
+//! Count packets lost or reordered upstream of the outbox.
+//!
+//! Most senders number a flow's packets with consecutive IP IDs, so a gap in a flow's IP IDs means
+//! packets were lost before reaching the outbox, and an IP ID older than the flow's latest one
+//! means a packet arrived out of order. For flows whose IP IDs do not progress (zero or random),
+//! and for IPv6 flows, which have no IP ID, we fall back to their TCP sequence numbers.
+
+use crate::hash::{Headers, IpVersion};
+use crate::serialize::PathStats;
+use bytes::{BigEndian, ByteOrder};
+use fnv::FnvHashMap;
+use std::net::IpAddr;
+
+/// IP IDs further apart than this are not consecutive numbering, but a reset or random IDs.
+const MAX_IP_ID_GAP: i16 = 1024;
+/// Forget all flows once we track this many, so finished flows do not pile up.
+const MAX_FLOWS: usize = 4096;
+
+type FlowKey = (IpAddr, IpAddr, u16, u16);
+
+struct FlowProgress {
+    ip_id: u16,
+    next_seq: u32,
+}
+
+/// `a` comes before `b` in TCP sequence number space.
+fn seq_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[derive(Default)]
+pub struct LossTracker {
+    flows: FnvHashMap<FlowKey, FlowProgress>,
+    stats: PathStats,
+}
+
+impl LossTracker {
+    /// Look at a captured packet. Packets too short to hold the TCP header are skipped.
+    pub fn on_packet(&mut self, hdrs: &Headers, pkt: &[u8]) {
+        if pkt.len() < hdrs.transport_header_start + 14 || hdrs.protocol != crate::IP_PROTO_TCP {
+            return;
+        }
+
+        let ip = &pkt[hdrs.ip_header_start..];
+        let tcp = &pkt[hdrs.transport_header_start..];
+        let (src, dst) = crate::hash::unpack_ips(pkt, hdrs);
+        let flow = (src, dst, BigEndian::read_u16(&tcp[0..2]), BigEndian::read_u16(&tcp[2..4]));
+        let (ip_id, ip_payload_len) = match hdrs.version {
+            IpVersion::V4 => (
+                Some(BigEndian::read_u16(&ip[4..6])),
+                (BigEndian::read_u16(&ip[2..4]) as usize)
+                    .saturating_sub(hdrs.transport_header_start - hdrs.ip_header_start),
+            ),
+            IpVersion::V6 => (None, BigEndian::read_u16(&ip[4..6]) as usize),
+        };
+        let seq = BigEndian::read_u32(&tcp[4..8]);
+        let payload_len = ip_payload_len.saturating_sub((tcp[12] >> 4) as usize * 4) as u32;
+
+        self.update(flow, ip_id, seq, payload_len);
+    }
+
+    fn update(&mut self, flow: FlowKey, ip_id: Option<u16>, seq: u32, payload_len: u32) {
+        if self.flows.len() >= MAX_FLOWS && !self.flows.contains_key(&flow) {
+            self.flows.clear();
+        }
+
+        let f = self.flows.entry(flow).or_insert(FlowProgress {
+            ip_id: ip_id.unwrap_or(0).wrapping_sub(1),
+            next_seq: seq,
+        });
+
+        // without an IP ID (IPv6), only the sequence numbers tell us anything
+        let id_delta = ip_id.map_or(0, |id| id.wrapping_sub(f.ip_id) as i16);
+        if id_delta != 0 && id_delta > -MAX_IP_ID_GAP && id_delta < MAX_IP_ID_GAP {
+            if id_delta < 0 {
+                // it was counted as lost when we skipped over it
+                self.stats.reordered_pkts += 1;
+                self.stats.lost_pkts = self.stats.lost_pkts.saturating_sub(1);
+                return;
+            }
+
+            self.stats.lost_pkts += (id_delta - 1) as u32;
+            f.ip_id = f.ip_id.wrapping_add(id_delta as u16);
+        } else if payload_len > 0 {
+            if seq_before(seq, f.next_seq) {
+                // a late packet or a retransmission; we cannot tell which
+                self.stats.reordered_pkts += 1;
+                return;
+            }
+
+            let gap_bytes = seq.wrapping_sub(f.next_seq);
+            self.stats.lost_pkts += (gap_bytes as f64 / payload_len as f64).ceil() as u32;
+        }
+
+        if !seq_before(seq.wrapping_add(payload_len), f.next_seq) {
+            f.next_seq = seq.wrapping_add(payload_len);
+        }
+    }
+
+    /// The losses and reordering seen since the previous call.
+    pub fn take(&mut self) -> PathStats {
+        std::mem::take(&mut self.stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LossTracker;
+    use crate::serialize::PathStats;
+    use std::net::IpAddr;
+
+    #[test]
+    fn check_loss_tracker() {
+        let a: IpAddr = [10, 0, 0, 1].into();
+        let b: IpAddr = [10, 0, 0, 2].into();
+        let flow = (a, b, 5000, 80);
+        let mut t = LossTracker::default();
+
+        // consecutive IP IDs, with 12 and 13 missing and 11 arriving late
+        for &id in &[10u16, 14, 11, 15] {
+            t.update(flow, Some(id), u32::from(id) * 1000, 1000);
+        }
+        assert_eq!(t.take(), PathStats { lost_pkts: 2, reordered_pkts: 1 });
+
+        // constant IP ID: a 2-packet hole in the sequence space, then a retransmission
+        let other = (a, b, 5001, 80);
+        for &seq in &[0u32, 1000, 4000, 2000] {
+            t.update(other, Some(0), seq, 1000);
+        }
+        assert_eq!(t.take(), PathStats { lost_pkts: 2, reordered_pkts: 1 });
+
+        // no IP ID at all (IPv6)
+        let v6: IpAddr = std::net::Ipv6Addr::LOCALHOST.into();
+        for &seq in &[0u32, 1000, 4000, 2000] {
+            t.update((v6, v6, 5002, 80), None, seq, 1000);
+        }
+        assert_eq!(t.take(), PathStats { lost_pkts: 2, reordered_pkts: 1 });
+        assert_eq!(t.take(), PathStats::default());
+    }
+}